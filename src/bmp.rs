@@ -0,0 +1,729 @@
+/* Windows and OS/2 bitmaps. BITMAPCOREHEADER, BITMAPINFOHEADER and the V2
+ * to V5 headers are read at 1, 4, 8, 16, 24 and 32 bits per pixel, with RLE4,
+ * RLE8 and bitfield compression. Files are written as 24-bit BGR or, for
+ * images with alpha, 32-bit BGRA with a V4 header. */
+use std::io::Read;
+
+use crate::codec::{ImageDecoder, ImageEncoder, ImageInfo};
+use crate::image::{Image, PixelData, PixelFormat};
+
+const FILE_HEADER_SIZE: usize = 14;
+const CORE_HEADER_SIZE: u32 = 12;
+const INFO_HEADER_SIZE: u32 = 40;
+const V4_HEADER_SIZE: u32 = 108;
+const V5_HEADER_SIZE: u32 = 124;
+
+/* Compression types */
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+/* The file header, the largest DIB header, masks and a 256 entry palette */
+const MAX_HEADER: usize = FILE_HEADER_SIZE + V5_HEADER_SIZE as usize + 16 + 256 * 4;
+
+/* 72 DPI in pixels per metre */
+const PIXELS_PER_METRE: i32 = 2835;
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, String> {
+    match data.get(pos..pos + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => Err("Truncated BMP header".to_string()),
+    }
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, String> {
+    match data.get(pos..pos + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err("Truncated BMP header".to_string()),
+    }
+}
+
+/* A contiguous run of bits in a 16 or 32-bit pixel */
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bitfield {
+    shift: u32,
+    len: u32,
+}
+
+impl Bitfield {
+    fn from_mask(mask: u32, bpp: u16) -> Result<Bitfield, String> {
+        if mask == 0 {
+            return Ok(Bitfield { shift: 0, len: 0 });
+        }
+        let shift = mask.trailing_zeros();
+        let len = mask.count_ones();
+        if (mask >> shift).trailing_ones() != len || shift + len > bpp as u32 {
+            return Err(format!("Invalid bitfield mask 0x{:08x}", mask));
+        }
+        Ok(Bitfield { shift, len })
+    }
+
+    /* The field scaled to 8 bits */
+    fn read(self, v: u32) -> u8 {
+        if self.len == 0 {
+            return 0;
+        }
+        let max = (1u64 << self.len) - 1;
+        let v = (v >> self.shift) as u64 & max;
+        ((v * 255 + max / 2) / max) as u8
+    }
+}
+
+struct Header {
+    header_size: u32,
+    width: u32,
+    height: u32,
+    top_down: bool,
+    bpp: u16,
+    compression: u32,
+    /* Red, green, blue and alpha, for 16 and 32-bit pixels */
+    masks: [Bitfield; 4],
+    palette: Vec<(u8, u8, u8)>,
+    data_offset: usize,
+}
+
+impl Header {
+    fn format(&self) -> PixelFormat {
+        match self.masks[3].len {
+            0 => PixelFormat::Rgb8,
+            _ => PixelFormat::Rgba8,
+        }
+    }
+
+    fn header_name(&self) -> &'static str {
+        match self.header_size {
+            CORE_HEADER_SIZE => "BITMAPCOREHEADER",
+            INFO_HEADER_SIZE => "BITMAPINFOHEADER",
+            52 => "BITMAPV2INFOHEADER",
+            56 => "BITMAPV3INFOHEADER",
+            V4_HEADER_SIZE => "BITMAPV4HEADER",
+            _ => "BITMAPV5HEADER",
+        }
+    }
+
+    fn compression_name(&self) -> &'static str {
+        match self.compression {
+            BI_RLE8 => "RLE8",
+            BI_RLE4 => "RLE4",
+            BI_BITFIELDS | BI_ALPHABITFIELDS => "bitfields",
+            _ => "none",
+        }
+    }
+}
+
+/* Reads the file and DIB headers, the bitfield masks and the palette */
+fn read_header(data: &[u8]) -> Result<Header, String> {
+    if !data.starts_with(b"BM") {
+        return Err("Not a BMP file".to_string());
+    }
+    let data_offset = read_u32(data, 10)? as usize;
+    let header = read_dib_header(data, FILE_HEADER_SIZE)?;
+    Ok(Header {
+        data_offset,
+        ..header
+    })
+}
+
+/* Reads a DIB header at `dib` with its masks and palette, the pixels are
+ * taken to follow them directly */
+fn read_dib_header(data: &[u8], dib: usize) -> Result<Header, String> {
+    let header_size = read_u32(data, dib)?;
+
+    let (width, height, planes, bpp, compression, colours_used) = match header_size {
+        CORE_HEADER_SIZE => (
+            read_u16(data, dib + 4)? as i32,
+            read_u16(data, dib + 6)? as i32,
+            read_u16(data, dib + 8)?,
+            read_u16(data, dib + 10)?,
+            BI_RGB,
+            0,
+        ),
+        INFO_HEADER_SIZE | 52 | 56 | V4_HEADER_SIZE | V5_HEADER_SIZE => (
+            read_u32(data, dib + 4)? as i32,
+            read_u32(data, dib + 8)? as i32,
+            read_u16(data, dib + 12)?,
+            read_u16(data, dib + 14)?,
+            read_u32(data, dib + 16)?,
+            read_u32(data, dib + 32)?,
+        ),
+        _ => return Err(format!("Unsupported BMP header size {}", header_size)),
+    };
+
+    if width <= 0 || height == 0 || height == i32::MIN {
+        return Err(format!("Invalid image size {}x{}", width, height));
+    }
+    if planes != 1 {
+        return Err(format!("Invalid number of planes {}", planes));
+    }
+    let valid = match compression {
+        BI_RGB => [1, 4, 8, 16, 24, 32].contains(&bpp),
+        BI_RLE8 => bpp == 8,
+        BI_RLE4 => bpp == 4,
+        BI_BITFIELDS | BI_ALPHABITFIELDS => bpp == 16 || bpp == 32,
+        _ => false,
+    };
+    if !valid {
+        return Err(format!(
+            "Unsupported compression {} at {} bits per pixel",
+            compression, bpp
+        ));
+    }
+    let top_down = height < 0;
+    if top_down && (compression == BI_RLE8 || compression == BI_RLE4) {
+        return Err("RLE images cannot be top-down".to_string());
+    }
+
+    /* The masks follow a BITMAPINFOHEADER, later headers contain them */
+    let mut masks = match bpp {
+        16 => [0x7c00, 0x03e0, 0x001f, 0],
+        32 => [0xff0000, 0xff00, 0xff, 0],
+        _ => [0; 4],
+    };
+    if compression == BI_BITFIELDS || compression == BI_ALPHABITFIELDS {
+        let num_masks = match (compression, header_size) {
+            (BI_ALPHABITFIELDS, _) | (_, 56..) => 4,
+            _ => 3,
+        };
+        masks = [0; 4];
+        for (i, mask) in masks.iter_mut().take(num_masks).enumerate() {
+            *mask = read_u32(data, dib + INFO_HEADER_SIZE as usize + 4 * i)?;
+        }
+    }
+    let mut end = dib + header_size as usize;
+    if header_size == INFO_HEADER_SIZE {
+        end += match compression {
+            BI_BITFIELDS => 12,
+            BI_ALPHABITFIELDS => 16,
+            _ => 0,
+        };
+    }
+    let mut fields = [Bitfield { shift: 0, len: 0 }; 4];
+    for (field, mask) in fields.iter_mut().zip(masks.iter()) {
+        *field = Bitfield::from_mask(*mask, bpp)?;
+    }
+
+    /* Palettes only come with 1 to 8-bit pixels, so never after masks */
+    let mut palette = Vec::new();
+    if bpp <= 8 {
+        let pos = dib + header_size as usize;
+        let max_colours = 1 << bpp;
+        let num_colours = match colours_used {
+            0 => max_colours,
+            n if n > max_colours => return Err(format!("Invalid palette size {}", n)),
+            n => n,
+        };
+        let entry_size = match header_size {
+            CORE_HEADER_SIZE => 3,
+            _ => 4,
+        };
+        let table = data
+            .get(pos..pos + entry_size * num_colours as usize)
+            .ok_or("Truncated BMP palette")?;
+        palette.extend(table.chunks(entry_size).map(|c| (c[2], c[1], c[0])));
+        end = pos + table.len();
+    }
+
+    Ok(Header {
+        header_size,
+        width: width as u32,
+        height: height.unsigned_abs(),
+        top_down,
+        bpp,
+        compression,
+        masks: fields,
+        palette,
+        data_offset: end,
+    })
+}
+
+/* A zeroed buffer, or an error when the size from the header cannot be
+ * allocated */
+fn zeroed(len: Option<usize>) -> Result<Vec<u8>, String> {
+    let len = len.ok_or("BMP image is too large")?;
+    let mut buffer = Vec::new();
+    buffer
+        .try_reserve_exact(len)
+        .map_err(|_| "BMP image is too large".to_string())?;
+    buffer.resize(len, 0);
+    Ok(buffer)
+}
+
+/* Expands RLE4 or RLE8 data to RGB, bottom row first. Pixels skipped by
+ * deltas or the end of a row or of the image are left black. */
+fn decode_rle(header: &Header, palette: &[(u8, u8, u8)], data: &[u8]) -> Result<Vec<u8>, String> {
+    let (width, height) = (header.width as usize, header.height as usize);
+    let mut rgb = zeroed(width.checked_mul(height * 3))?;
+    let (mut x, mut y, mut pos) = (0, 0, 0);
+    let mut set = |x: usize, y: usize, index: u8| {
+        if x < width && y < height {
+            let (r, g, b) = palette[index as usize];
+            rgb[(y * width + x) * 3..][..3].copy_from_slice(&[r, g, b]);
+        }
+    };
+
+    while pos + 1 < data.len() && y < height {
+        let (count, value) = (data[pos] as usize, data[pos + 1]);
+        pos += 2;
+        match (count, value) {
+            (0, 0) => {
+                x = 0;
+                y += 1;
+            }
+            (0, 1) => break,
+            (0, 2) => {
+                if pos + 1 >= data.len() {
+                    break;
+                }
+                x += data[pos] as usize;
+                y += data[pos + 1] as usize;
+                pos += 2;
+            }
+            /* A run of indices stored as is, padded to 16 bits */
+            (0, n) => {
+                let n = n as usize;
+                let len = match header.compression {
+                    BI_RLE8 => n,
+                    _ => n.div_ceil(2),
+                };
+                let run = &data[pos..(pos + len).min(data.len())];
+                for i in 0..n {
+                    let index = match header.compression {
+                        BI_RLE8 => run.get(i).copied(),
+                        _ => run.get(i / 2).map(|b| b >> (4 - 4 * (i % 2)) & 15),
+                    };
+                    set(x, y, index.unwrap_or(0));
+                    x += 1;
+                }
+                pos += len + len % 2;
+            }
+            /* A repeated index, or a pair of alternating ones for RLE4 */
+            (n, value) => {
+                for i in 0..n {
+                    let index = match header.compression {
+                        BI_RLE8 => value,
+                        _ => value >> (4 - 4 * (i % 2)) & 15,
+                    };
+                    set(x, y, index);
+                    x += 1;
+                }
+            }
+        }
+    }
+    Ok(rgb)
+}
+
+/* Decodes a BMP file to RGB8, or to RGBA8 when the masks include alpha */
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    let header = read_header(data)?;
+    let pixels = data.get(header.data_offset..).ok_or("Truncated BMP file")?;
+    decode_pixels(&header, pixels)
+}
+
+fn decode_pixels(header: &Header, pixels: &[u8]) -> Result<Image, String> {
+    let (width, height) = (header.width as usize, header.height as usize);
+    let rle = header.compression == BI_RLE8 || header.compression == BI_RLE4;
+    /* Checked before allocating, as the size in the header may be bogus */
+    let stride = (header.bpp as usize * width).div_ceil(32) * 4;
+    if !rle && pixels.len() < stride.saturating_mul(height) {
+        return Err(format!(
+            "Truncated pixel data, expected {} bytes but got {}",
+            stride.saturating_mul(height),
+            pixels.len()
+        ));
+    }
+    let format = header.format();
+    let channels = format.channels();
+    let image = |out| Image {
+        width: header.width,
+        height: header.height,
+        format,
+        data: PixelData::U8(out),
+    };
+
+    /* Rows are stored bottom-up unless the height is negative */
+    let dest_row = |row: usize| match header.top_down {
+        true => row,
+        false => height - 1 - row,
+    };
+    let palette = full_palette(header);
+
+    /* A few bytes of RLE can cover any size through deltas and the end of
+     * the bitmap, so only the allocation limits it */
+    if rle {
+        let mut rgb = decode_rle(header, &palette, pixels)?;
+        if !header.top_down {
+            for row in 0..height / 2 {
+                let (top, bottom) = rgb.split_at_mut(dest_row(row) * width * 3);
+                top[row * width * 3..][..width * 3].swap_with_slice(&mut bottom[..width * 3]);
+            }
+        }
+        return Ok(image(rgb));
+    }
+
+    let mut out = zeroed(width.checked_mul(height * channels))?;
+    for (row, src) in pixels.chunks(stride).take(height).enumerate() {
+        let dest = &mut out[dest_row(row) * width * channels..][..width * channels];
+        decode_row(header, &palette, src, dest)?;
+    }
+    Ok(image(out))
+}
+
+/* The colour table padded to 256 entries, so any index can be looked up */
+fn full_palette(header: &Header) -> Vec<(u8, u8, u8)> {
+    let mut palette = header.palette.clone();
+    palette.resize(256, (0, 0, 0));
+    palette
+}
+
+/* Converts one stored row of uncompressed pixels into `dest`, which has the
+ * channels of the header's format */
+fn decode_row(
+    header: &Header,
+    palette: &[(u8, u8, u8)],
+    src: &[u8],
+    dest: &mut [u8],
+) -> Result<(), String> {
+    let bpp = header.bpp as usize;
+    let channels = header.format().channels();
+    let [r, g, b, a] = header.masks;
+    for (x, px) in dest.chunks_mut(channels).enumerate() {
+        let rgba = match bpp {
+            1 | 4 | 8 => {
+                let bit = x * bpp;
+                let index = src[bit / 8] >> (8 - bpp - bit % 8) & ((1 << bpp) - 1) as u8;
+                let (r, g, b) = palette[index as usize];
+                [r, g, b, 255]
+            }
+            24 => [src[x * 3 + 2], src[x * 3 + 1], src[x * 3], 255],
+            _ => {
+                let v = match bpp {
+                    16 => read_u16(src, x * 2)? as u32,
+                    _ => read_u32(src, x * 4)?,
+                };
+                [r.read(v), g.read(v), b.read(v), a.read(v)]
+            }
+        };
+        px.copy_from_slice(&rgba[..channels]);
+    }
+    Ok(())
+}
+
+/* Decodes the DIB of an ICO or CUR entry. Its height covers the colour
+ * pixels and the AND mask after them, in which set bits are transparent.
+ * 32-bit pixels have alpha, the mask only applies when it is all zero. */
+pub(crate) fn decode_icon(data: &[u8]) -> Result<Image, String> {
+    let mut header = read_dib_header(data, 0)?;
+    header.height /= 2;
+    if header.height == 0 {
+        return Err("Invalid icon height".to_string());
+    }
+    if header.bpp == 32 && header.compression == BI_RGB {
+        header.masks[3] = Bitfield { shift: 24, len: 8 };
+    }
+    let pixels = data.get(header.data_offset..).ok_or("Truncated icon")?;
+    let mut img = decode_pixels(&header, pixels)?.convert(PixelFormat::Rgba8);
+    let PixelData::U8(out) = &mut img.data else {
+        unreachable!()
+    };
+    if header.bpp == 32 && out.chunks(4).any(|px| px[3] != 0) {
+        return Ok(img);
+    }
+
+    let (width, height) = (header.width as usize, header.height as usize);
+    let stride = (header.bpp as usize * width).div_ceil(32) * 4;
+    let mask_stride = width.div_ceil(32) * 4;
+    /* Some writers leave out the mask of compressed or 32-bit entries */
+    let mask = match pixels.get(stride * height..stride * height + mask_stride * height) {
+        Some(mask) if header.compression != BI_RLE8 && header.compression != BI_RLE4 => mask,
+        _ => return Ok(img),
+    };
+    for (row, bits) in mask.chunks(mask_stride).enumerate() {
+        let y = match header.top_down {
+            true => row,
+            false => height - 1 - row,
+        };
+        for (x, px) in out[y * width * 4..][..width * 4].chunks_mut(4).enumerate() {
+            px[3] = match bits[x / 8] >> (7 - x % 8) & 1 {
+                1 => 0,
+                _ => 255,
+            };
+        }
+    }
+    Ok(img)
+}
+
+/* Writes a bottom-up BMP: 24-bit BGR with a BITMAPINFOHEADER, or 32-bit
+ * BGRA with a BITMAPV4HEADER and bitfield masks when the image has alpha */
+pub fn encode(img: &Image) -> Result<Vec<u8>, String> {
+    if img.width > i32::MAX as u32 || img.height > i32::MAX as u32 {
+        return Err(format!(
+            "Image size {}x{} is too large for BMP",
+            img.width, img.height
+        ));
+    }
+    let alpha = img.format.has_alpha();
+    let (format, bpp, header_size) = match alpha {
+        true => (PixelFormat::Rgba8, 32, V4_HEADER_SIZE),
+        false => (PixelFormat::Rgb8, 24, INFO_HEADER_SIZE),
+    };
+    let img = img.convert(format);
+    let PixelData::U8(data) = &img.data else {
+        unreachable!()
+    };
+
+    let channels = format.channels();
+    let stride = (bpp * img.width as usize).div_ceil(32) * 4;
+    let data_offset = FILE_HEADER_SIZE + header_size as usize;
+    let file_size = data_offset as u64 + stride as u64 * img.height as u64;
+    if file_size > u32::MAX as u64 {
+        return Err("Image is too large for BMP".to_string());
+    }
+
+    let mut out = Vec::with_capacity(file_size as usize);
+    out.extend_from_slice(b"BM");
+    out.extend_from_slice(&(file_size as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(data_offset as u32).to_le_bytes());
+
+    out.extend_from_slice(&header_size.to_le_bytes());
+    out.extend_from_slice(&(img.width as i32).to_le_bytes());
+    out.extend_from_slice(&(img.height as i32).to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&(bpp as u16).to_le_bytes());
+    let compression = match alpha {
+        true => BI_BITFIELDS,
+        false => BI_RGB,
+    };
+    out.extend_from_slice(&compression.to_le_bytes());
+    out.extend_from_slice(&((file_size - data_offset as u64) as u32).to_le_bytes());
+    out.extend_from_slice(&PIXELS_PER_METRE.to_le_bytes());
+    out.extend_from_slice(&PIXELS_PER_METRE.to_le_bytes());
+    /* Colours used and important */
+    out.extend_from_slice(&[0; 8]);
+    if alpha {
+        for mask in [0x00ff0000u32, 0x0000ff00, 0x000000ff, 0xff000000].iter() {
+            out.extend_from_slice(&mask.to_le_bytes());
+        }
+        /* sRGB, which leaves the endpoints and gammas unused */
+        out.extend_from_slice(b"BGRs");
+        out.resize(data_offset, 0);
+    }
+
+    let row_len = img.width as usize * channels;
+    for row in data.chunks(row_len.max(1)).take(img.height as usize).rev() {
+        let start = out.len();
+        for px in row.chunks(channels) {
+            out.extend_from_slice(&[px[2], px[1], px[0]]);
+            if alpha {
+                out.push(px[3]);
+            }
+        }
+        out.resize(start + stride, 0);
+    }
+    Ok(out)
+}
+
+/* Reads only the headers and the palette */
+pub fn probe<R: Read>(reader: R) -> Result<ImageInfo, String> {
+    let mut data = Vec::new();
+    reader
+        .take(MAX_HEADER as u64)
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Reading BMP: {}", e))?;
+    let header = read_header(&data)?;
+
+    let mut info = ImageInfo::new(header.width, header.height, header.format());
+    if header.bpp <= 8 {
+        info.bit_depth = header.bpp as u8;
+        info.palette = Some(
+            header
+                .palette
+                .iter()
+                .map(|(r, g, b)| (*r, *g, *b, 255))
+                .collect(),
+        );
+    } else if header.bpp != 24 {
+        info.bit_depth = header.masks[..3].iter().map(|m| m.len).max().unwrap() as u8;
+    }
+    Ok(info)
+}
+
+pub struct BmpDecoder {
+    data: Vec<u8>,
+}
+
+impl BmpDecoder {
+    pub fn new(data: Vec<u8>) -> BmpDecoder {
+        BmpDecoder { data }
+    }
+}
+
+impl ImageDecoder for BmpDecoder {
+    fn info(&mut self) -> Result<ImageInfo, String> {
+        probe(self.data.as_slice())
+    }
+
+    fn decode(&mut self) -> Result<Image, String> {
+        decode(&self.data)
+    }
+
+    /* Uncompressed rows are converted one at a time, RLE is expanded whole */
+    fn decode_rows(&mut self, f: &mut dyn FnMut(u32, &Image)) -> Result<(), String> {
+        let header = read_header(&self.data)?;
+        let pixels = self
+            .data
+            .get(header.data_offset..)
+            .ok_or("Truncated BMP file")?;
+        let (width, height) = (header.width as usize, header.height as usize);
+        let stride = (header.bpp as usize * width).div_ceil(32) * 4;
+        if header.compression == BI_RLE8
+            || header.compression == BI_RLE4
+            || pixels.len() < stride.saturating_mul(height)
+        {
+            let img = decode_pixels(&header, pixels)?;
+            for y in 0..img.height {
+                f(y, &crate::codec::row(&img, y));
+            }
+            return Ok(());
+        }
+
+        let palette = full_palette(&header);
+        let mut row = Image::new(header.width, 1, header.format());
+        for y in 0..height {
+            let stored = match header.top_down {
+                true => y,
+                false => height - 1 - y,
+            };
+            let PixelData::U8(dest) = &mut row.data else {
+                unreachable!()
+            };
+            decode_row(
+                &header,
+                &palette,
+                &pixels[stored * stride..][..stride],
+                dest,
+            )?;
+            f(y as u32, &row);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> Vec<(String, String)> {
+        let header = match read_header(&self.data) {
+            Ok(header) => header,
+            Err(_) => return Vec::new(),
+        };
+        vec![
+            ("header".to_string(), header.header_name().to_string()),
+            ("bits per pixel".to_string(), header.bpp.to_string()),
+            (
+                "compression".to_string(),
+                header.compression_name().to_string(),
+            ),
+            ("top-down".to_string(), header.top_down.to_string()),
+        ]
+    }
+}
+
+/* Writes 24-bit BMP, or 32-bit when the image has alpha */
+#[derive(Default)]
+pub struct BmpEncoder;
+
+impl ImageEncoder for BmpEncoder {
+    fn encode(&self, img: &Image) -> Result<Vec<u8>, String> {
+        encode(img)
+    }
+}
+
+#[test]
+fn test_decode_bmp() {
+    let files = [
+        ("basn3p01", "basn3p01"),
+        ("basn3p04_core", "basn3p04"),
+        ("basn3p04_rle4", "basn3p04"),
+        ("basn3p08", "basn3p08"),
+        ("basn3p08_rle8", "basn3p08"),
+        ("basn2c08", "basn2c08"),
+        ("basn2c08_topdown", "basn2c08"),
+        ("basn2c08_565", "basn2c08"),
+        ("basn6a08_v5", "basn6a08"),
+        ("basn6a08_4444", "basn6a08"),
+    ];
+    for (name, reference) in files.iter() {
+        let data = std::fs::read(format!("tests/bmp/{}.bmp", name)).unwrap();
+        let img = decode(&data).unwrap();
+        let expected = crate::open(format!("tests/png_testsuite/{}.png", reference))
+            .unwrap()
+            .convert(img.format);
+        let info = probe(data.as_slice()).unwrap();
+        assert_eq!((info.width, info.height), (32, 32));
+        assert_eq!(info.pixel_format, img.format);
+
+        /* 16-bit pixels have 4 to 6 bits per channel */
+        let tolerance = match name.ends_with("565") || name.ends_with("4444") {
+            true => 17,
+            false => 0,
+        };
+        let (PixelData::U8(a), PixelData::U8(b)) = (&img.data, &expected.data) else {
+            panic!("{} is not 8-bit", name)
+        };
+        assert!(
+            a.iter().zip(b).all(|(a, b)| a.abs_diff(*b) <= tolerance),
+            "{}",
+            name
+        );
+    }
+
+    /* A delta skips pixels, which stay black, and the end of the image comes
+     * before the last row is written */
+    let mut data = b"BM\0\0\0\0\0\0\0\0\x3e\0\0\0".to_vec();
+    data.extend_from_slice(&[40, 0, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 1, 0, 8, 0]);
+    data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    data.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0]);
+    data.extend_from_slice(&[0, 0, 255, 0, 255, 255, 255, 0]);
+    data.extend_from_slice(&[0, 2, 1, 0, 2, 1, 0, 1]);
+    let img = decode(&data).unwrap();
+    let white = [255, 255, 255];
+    let mut expected = vec![0; 4 * 2 * 3];
+    for x in 1..3 {
+        expected[(4 + x) * 3..][..3].copy_from_slice(&white);
+    }
+    assert_eq!(img.data, PixelData::U8(expected));
+
+    /* Deltas and the end of the bitmap let 18 bytes cover 1000x1000 */
+    data[18..26].copy_from_slice(&[0xe8, 3, 0, 0, 0xe8, 3, 0, 0]);
+    data.truncate(0x3e);
+    data.extend_from_slice(&[0, 2, 0, 255, 0, 2, 0, 255, 2, 1, 0, 2, 0, 255, 0, 1]);
+    let img = decode(&data).unwrap();
+    assert_eq!((img.width, img.height), (1000, 1000));
+    /* Two white pixels on stored row 510, the rest stays black */
+    assert_eq!(img.get_rgba16(489 * 1000 + 1), [0xffff; 4]);
+    assert_eq!(img.get_rgba16(489 * 1000 + 2), [0, 0, 0, 0xffff]);
+
+    /* 2147483647x2147483647 cannot be allocated, which is an error */
+    data[18..26].copy_from_slice(&[0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff, 0x7f]);
+    let error = decode(&data).unwrap_err();
+    assert!(error.contains("too large"), "{}", error);
+}
+
+#[test]
+fn test_encode_bmp() {
+    for name in ["basn2c08", "basn6a08", "basn0g16"].iter() {
+        let img = crate::open(format!("tests/png_testsuite/{}.png", name)).unwrap();
+        let encoded = encode(&img).unwrap();
+        let decoded = decode(&encoded).unwrap();
+        let format = match img.format.has_alpha() {
+            true => PixelFormat::Rgba8,
+            false => PixelFormat::Rgb8,
+        };
+        assert_eq!(decoded, img.convert(format));
+        assert_eq!(encoded.len(), read_u32(&encoded, 2).unwrap() as usize);
+    }
+
+    /* Rows of 3 pixels are padded from 9 to 12 bytes */
+    let img = Image::new(3, 2, PixelFormat::Rgb8);
+    let encoded = encode(&img).unwrap();
+    assert_eq!(encoded.len(), 54 + 2 * 12);
+}
@@ -0,0 +1,326 @@
+use std::collections::VecDeque;
+
+use crate::png::{self, ColourType, Header};
+use crate::zlib;
+
+/* A spec violation found by check_png */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    /* Offset of the chunk the problem belongs to, None for the file as a whole */
+    pub offset: Option<usize>,
+    pub message: String,
+}
+
+/* Chunks that may appear at most once */
+const SINGLE: [&[u8; 4]; 13] = [
+    b"IHDR", b"PLTE", b"IEND", b"gAMA", b"sBIT", b"bKGD", b"cHRM", b"hIST", b"tIME", b"tRNS",
+    b"pHYs", b"sRGB", b"iCCP",
+];
+/* Chunks that have to come before PLTE */
+const BEFORE_PLTE: [&[u8; 4]; 5] = [b"gAMA", b"sBIT", b"cHRM", b"sRGB", b"iCCP"];
+/* Chunks that have to come after PLTE when there is one */
+const AFTER_PLTE: [&[u8; 4]; 3] = [b"tRNS", b"bKGD", b"hIST"];
+/* Chunks that have to come before the image data */
+const BEFORE_IDAT: [&[u8; 4]; 11] = [
+    b"PLTE", b"gAMA", b"sBIT", b"cHRM", b"sRGB", b"iCCP", b"tRNS", b"bKGD", b"hIST", b"pHYs",
+    b"sPLT",
+];
+/* Chunks that start with a null terminated keyword */
+const KEYWORD: [&[u8; 4]; 5] = [b"tEXt", b"zTXt", b"iTXt", b"iCCP", b"sPLT"];
+
+/* Keywords are 1-79 printable Latin-1 characters without leading, trailing or
+ * consecutive spaces */
+fn check_keyword(data: &[u8]) -> Result<(), String> {
+    let keyword = match data.iter().position(|b| *b == 0) {
+        Some(end) => &data[..end],
+        None => return Err("Keyword is not null terminated".to_string()),
+    };
+
+    let printable = |b: &u8| (32..=126).contains(b) || *b >= 161;
+    if keyword.is_empty() || keyword.len() > 79 {
+        Err(format!("Keyword has invalid length {}", keyword.len()))
+    } else if !keyword.iter().all(printable) {
+        Err("Keyword contains non-printable characters".to_string())
+    } else if keyword[0] == b' ' || keyword[keyword.len() - 1] == b' ' {
+        Err("Keyword has leading or trailing spaces".to_string())
+    } else if keyword.windows(2).any(|w| w == b"  ") {
+        Err("Keyword has consecutive spaces".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn check_header(header: &Header) -> Vec<String> {
+    let mut problems = Vec::new();
+    if header.width == 0 || header.width > i32::MAX as u32 {
+        problems.push(format!("Invalid width {}", header.width));
+    }
+    if header.height == 0 || header.height > i32::MAX as u32 {
+        problems.push(format!("Invalid height {}", header.height));
+    }
+    if header.colour_type == ColourType::Invalid {
+        problems.push("Invalid colour type".to_string());
+    } else if !header.colour_type.allows_depth(header.depth) {
+        problems.push(format!(
+            "Invalid bit depth {} for {:?}",
+            header.depth, header.colour_type
+        ));
+    }
+    if header.compression != 0 {
+        problems.push(format!("Invalid compression method {}", header.compression));
+    }
+    if header.filter != 0 {
+        problems.push(format!("Invalid filter method {}", header.filter));
+    }
+    if header.interlace > 1 {
+        problems.push(format!("Invalid interlace method {}", header.interlace));
+    }
+
+    problems
+}
+
+/* Checks the length and contents of chunks whose layout depends on IHDR */
+fn check_chunk_data(
+    chunk_type: &[u8; 4],
+    data: &[u8],
+    header: &Header,
+    palette_len: usize,
+) -> Option<String> {
+    let colour_type = header.colour_type;
+    let expect = |len: usize| {
+        if data.len() == len {
+            None
+        } else {
+            Some(format!(
+                "{} has length {} instead of {}",
+                String::from_utf8_lossy(chunk_type),
+                data.len(),
+                len
+            ))
+        }
+    };
+
+    match chunk_type {
+        b"PLTE" => {
+            if colour_type == ColourType::Grayscale || colour_type == ColourType::GrayscaleAlpha {
+                Some("PLTE is not allowed for grayscale images".to_string())
+            } else if data.is_empty() || !data.len().is_multiple_of(3) || data.len() > 256 * 3 {
+                Some(format!("Invalid PLTE length {}", data.len()))
+            } else if colour_type == ColourType::Indexed && data.len() / 3 > 1 << header.depth {
+                Some(format!(
+                    "PLTE has {} entries, more than a {}-bit image can use",
+                    data.len() / 3,
+                    header.depth
+                ))
+            } else {
+                None
+            }
+        }
+        b"tRNS" => match colour_type {
+            ColourType::Grayscale => expect(2),
+            ColourType::TrueColour => expect(6),
+            ColourType::Indexed if data.len() > palette_len => Some(format!(
+                "tRNS has {} entries but the palette only {}",
+                data.len(),
+                palette_len
+            )),
+            ColourType::Indexed => None,
+            _ => Some(format!("tRNS is not allowed for {:?}", colour_type)),
+        },
+        b"bKGD" => match colour_type {
+            ColourType::Indexed => expect(1),
+            ColourType::Grayscale | ColourType::GrayscaleAlpha => expect(2),
+            _ => expect(6),
+        },
+        b"sBIT" => match colour_type {
+            ColourType::Indexed => expect(3),
+            _ => expect(colour_type.channels()),
+        },
+        b"hIST" => expect(palette_len * 2),
+        b"gAMA" => expect(4),
+        b"cHRM" => expect(32),
+        b"pHYs" => expect(9),
+        b"tIME" => expect(7),
+        b"sRGB" => expect(1),
+        _ if KEYWORD.contains(&chunk_type) => check_keyword(data).err(),
+        _ => None,
+    }
+}
+
+/* Walks every chunk of a PNG file and reports all spec violations found,
+ * an empty result means the file is valid */
+pub fn check_png(data: &[u8]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut report = |offset, message| problems.push(Problem { offset, message });
+
+    let mut chunks = match png::chunks(data) {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            report(None, e);
+            return problems;
+        }
+    };
+
+    let mut header: Option<Header> = None;
+    let mut seen: Vec<[u8; 4]> = Vec::new();
+    let mut palette_len = 0;
+    let mut image_data = VecDeque::new();
+    let mut idat_ended = false;
+
+    for chunk in chunks.by_ref() {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                report(None, e);
+                break;
+            }
+        };
+        let offset = Some(chunk.offset);
+        let chunk_type = &chunk.chunk_type;
+        let name = chunk.name();
+
+        if !chunk.crc_matches() {
+            report(
+                offset,
+                format!(
+                    "CRC error in {}: stored {:08x}, computed {:08x}",
+                    name, chunk.stored_crc, chunk.computed_crc
+                ),
+            );
+        }
+        if !chunk_type.iter().all(|b| b.is_ascii_alphabetic()) {
+            report(offset, format!("Invalid chunk type {:?}", chunk_type));
+            continue;
+        }
+
+        /* Ordering */
+        if seen.is_empty() && chunk_type != b"IHDR" {
+            report(offset, format!("{} before IHDR", name));
+        }
+        if SINGLE.contains(&chunk_type) && seen.contains(chunk_type) {
+            report(offset, format!("Multiple {} chunks", name));
+        }
+        if BEFORE_PLTE.contains(&chunk_type) && seen.contains(b"PLTE") {
+            report(offset, format!("{} after PLTE", name));
+        }
+        if AFTER_PLTE.contains(&chunk_type)
+            && header.is_some_and(|h| h.colour_type == ColourType::Indexed)
+            && !seen.contains(b"PLTE")
+        {
+            report(offset, format!("{} before PLTE", name));
+        }
+        if BEFORE_IDAT.contains(&chunk_type) && seen.contains(b"IDAT") {
+            report(offset, format!("{} after IDAT", name));
+        }
+        if chunk_type == b"IDAT" && idat_ended {
+            report(offset, "IDAT chunks are not consecutive".to_string());
+        }
+        if chunk_type != b"IDAT" && seen.last() == Some(b"IDAT") {
+            idat_ended = true;
+        }
+        if chunk_type == b"IEND" && !seen.contains(b"IDAT") {
+            report(offset, "IEND before IDAT".to_string());
+        }
+        if chunk_type[0].is_ascii_uppercase()
+            && ![b"IHDR", b"PLTE", b"IDAT", b"IEND"].contains(&chunk_type)
+        {
+            report(offset, format!("Unknown critical chunk {}", name));
+        }
+        seen.push(*chunk_type);
+
+        /* Contents */
+        match chunk_type {
+            b"IHDR" => match Header::from_bytes(chunk.data) {
+                Ok(h) => {
+                    for message in check_header(&h) {
+                        report(offset, message);
+                    }
+                    header = Some(h);
+                }
+                Err(e) => report(offset, e),
+            },
+            b"IDAT" => image_data.extend(chunk.data.iter()),
+            b"IEND" if chunk.length != 0 => {
+                report(offset, format!("IEND has length {}", chunk.length));
+            }
+            _ => {}
+        }
+        if chunk_type == b"PLTE" {
+            palette_len = chunk.data.len() / 3;
+        }
+        if let Some(h) = &header {
+            if let Some(message) = check_chunk_data(chunk_type, chunk.data, h, palette_len) {
+                report(offset, message);
+            }
+        }
+    }
+
+    if !seen.contains(b"IEND") {
+        report(None, "Missing IEND".to_string());
+    } else if !chunks.remainder().is_empty() {
+        report(
+            None,
+            format!(
+                "{} bytes of trailing data after IEND",
+                chunks.remainder().len()
+            ),
+        );
+    }
+    if header.is_some_and(|h| h.colour_type == ColourType::Indexed) && !seen.contains(b"PLTE") {
+        report(None, "Missing PLTE for an indexed image".to_string());
+    }
+
+    /* The expected size is meaningless when IHDR itself is broken */
+    if let Some(h) = header.filter(|h| check_header(h).is_empty()) {
+        match h.image_data_len() {
+            None => report(
+                None,
+                format!("Image size {}x{} is too large", h.width, h.height),
+            ),
+            Some(expected) if !image_data.is_empty() => match zlib::parse(&mut image_data) {
+                Ok(inflated) if inflated.len() != expected => report(
+                    None,
+                    format!(
+                        "Image data has {} bytes, expected {}",
+                        inflated.len(),
+                        expected
+                    ),
+                ),
+                Ok(_) => {}
+                Err(e) => report(None, format!("zlib: {}", e)),
+            },
+            Some(_) if !seen.contains(b"IDAT") => report(None, "Missing IDAT".to_string()),
+            Some(_) => {}
+        }
+    }
+
+    problems
+}
+
+#[test]
+fn test_check_png() {
+    let mut data = std::fs::read("tests/png_testsuite/basn0g01.png").unwrap();
+    assert_eq!(check_png(&data), vec![]);
+
+    data.extend_from_slice(b"trailing");
+    let problems = check_png(&data);
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0].message, "8 bytes of trailing data after IEND");
+
+    assert!(!check_png(&std::fs::read("tests/png_testsuite/xcsn0g01.png").unwrap()).is_empty());
+
+    /* 2147483647x2147483647 RGBA16, plain and interlaced, overflows a usize */
+    let mut data = std::fs::read("tests/png_testsuite/basn0g01.png").unwrap();
+    data[16..26].copy_from_slice(&[0x7f, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff, 16, 6]);
+    for interlace in [0, 1] {
+        data[28] = interlace;
+        let problems = check_png(&data);
+        assert!(
+            problems.iter().any(|p| p.message.contains("too large")),
+            "{:?}",
+            problems
+        );
+    }
+    assert_eq!(check_keyword(b"Title\0"), Ok(()));
+    assert!(check_keyword(b"Two  spaces\0").is_err());
+}
@@ -0,0 +1,352 @@
+/* Format independent decoding and encoding. Every format implements
+ * ImageDecoder and, when it can be written, ImageEncoder. A Registry maps
+ * files to them and accepts formats from other crates. */
+use std::io::Read;
+
+use crate::format::{guess_format, ImageFormat};
+use crate::image::{Image, PixelData, PixelFormat};
+use crate::{bmp, gif, ico, jpeg, png, pnm, qoi, tga, tiff, webp};
+
+/* What is known about an image before its pixels are decoded */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    /* The format decode() produces */
+    pub pixel_format: PixelFormat,
+    /* Bits per sample, or per index for indexed images, as stored */
+    pub bit_depth: u8,
+    /* RGBA entries of indexed images */
+    pub palette: Option<Vec<(u8, u8, u8, u8)>>,
+    /* Gamma times 100000, as in PNG's gAMA */
+    pub gamma: Option<u32>,
+    /* Keyword and text pairs */
+    pub text: Vec<(String, String)>,
+}
+
+impl ImageInfo {
+    /* Only the size and layout known, with the bit depth of the layout */
+    pub fn new(width: u32, height: u32, pixel_format: PixelFormat) -> ImageInfo {
+        ImageInfo {
+            width,
+            height,
+            pixel_format,
+            bit_depth: (pixel_format.bytes_per_sample() * 8) as u8,
+            palette: None,
+            gamma: None,
+            text: Vec::new(),
+        }
+    }
+}
+
+pub trait ImageDecoder {
+    /* Reads only as much as needed for the size and pixel format */
+    fn info(&mut self) -> Result<ImageInfo, String>;
+
+    fn decode(&mut self) -> Result<Image, String>;
+
+    /* Calls `f` with the index and pixels of every row, top to bottom. The
+     * default decodes the whole image first and does not stream, formats
+     * that can produce rows one at a time override it. */
+    fn decode_rows(&mut self, f: &mut dyn FnMut(u32, &Image)) -> Result<(), String> {
+        let img = self.decode()?;
+        for y in 0..img.height {
+            f(y, &row(&img, y));
+        }
+        Ok(())
+    }
+
+    /* Format specific details as name and value, complete after decode() */
+    fn metadata(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+pub trait ImageEncoder {
+    fn encode(&self, img: &Image) -> Result<Vec<u8>, String>;
+
+    /* 1 to 100, ignored by lossless formats */
+    fn set_quality(&mut self, _quality: u8) {}
+}
+
+/* A single row of an image */
+pub(crate) fn row(img: &Image, y: u32) -> Image {
+    let len = img.width as usize * img.format.channels();
+    let range = y as usize * len..(y as usize + 1) * len;
+    let data = match &img.data {
+        PixelData::U8(data) => PixelData::U8(data[range].to_vec()),
+        PixelData::U16(data) => PixelData::U16(data[range].to_vec()),
+        PixelData::F32(data) => PixelData::F32(data[range].to_vec()),
+    };
+    Image {
+        width: img.width,
+        height: 1,
+        format: img.format,
+        data,
+    }
+}
+
+/* Creates a decoder for data in a format */
+pub type DecoderFn = fn(Vec<u8>) -> Box<dyn ImageDecoder>;
+pub type EncoderFn = fn() -> Box<dyn ImageEncoder>;
+/* Reads the header from a stream, the flag asks for ancillary data */
+pub type ProbeFn = fn(&mut dyn Read, bool) -> Result<ImageInfo, String>;
+
+pub struct Codec {
+    pub name: &'static str,
+    /* Lower case file extensions without the dot */
+    pub extensions: &'static [&'static str],
+    /* Whether data starts like a file of this format */
+    pub detect: fn(&[u8]) -> bool,
+    pub decoder: Option<DecoderFn>,
+    pub encoder: Option<EncoderFn>,
+    /* Without one, probing reads the whole file and asks the decoder */
+    pub probe: Option<ProbeFn>,
+}
+
+pub struct Registry {
+    codecs: Vec<Codec>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl Registry {
+    /* A registry with the formats sparrow supports */
+    pub fn new() -> Registry {
+        Registry {
+            codecs: vec![
+                Codec {
+                    name: "PNG",
+                    extensions: &["png"],
+                    detect: |data| guess_format(data) == Some(ImageFormat::Png),
+                    decoder: Some(|data| Box::new(png::PngDecoder::new(data))),
+                    encoder: Some(|| Box::new(png::PngEncoder)),
+                    probe: Some(|reader, ancillary| png::probe(reader, ancillary)),
+                },
+                Codec {
+                    name: "JPEG",
+                    extensions: &["jpg", "jpeg"],
+                    detect: |data| guess_format(data) == Some(ImageFormat::Jpeg),
+                    decoder: Some(|data| Box::new(jpeg::JpegDecoder::new(data))),
+                    encoder: Some(|| Box::new(jpeg::JpegEncoder::default())),
+                    probe: Some(|reader, _| jpeg::probe(reader)),
+                },
+                Codec {
+                    name: "GIF",
+                    extensions: &["gif"],
+                    detect: |data| guess_format(data) == Some(ImageFormat::Gif),
+                    decoder: Some(|data| Box::new(gif::GifDecoder::new(data))),
+                    encoder: Some(|| Box::new(gif::GifEncoder::default())),
+                    probe: Some(|reader, _| gif::probe(reader)),
+                },
+                Codec {
+                    name: "Netpbm",
+                    extensions: &["pbm", "pgm", "ppm", "pam", "pnm"],
+                    detect: |data| guess_format(data) == Some(ImageFormat::Pnm),
+                    decoder: Some(|data| Box::new(pnm::PnmDecoder::new(data))),
+                    encoder: Some(|| Box::new(pnm::PnmEncoder::default())),
+                    probe: Some(|reader, _| pnm::probe(reader)),
+                },
+                Codec {
+                    name: "BMP",
+                    extensions: &["bmp", "dib"],
+                    detect: |data| guess_format(data) == Some(ImageFormat::Bmp),
+                    decoder: Some(|data| Box::new(bmp::BmpDecoder::new(data))),
+                    encoder: Some(|| Box::new(bmp::BmpEncoder)),
+                    probe: Some(|reader, _| bmp::probe(reader)),
+                },
+                Codec {
+                    name: "QOI",
+                    extensions: &["qoi"],
+                    detect: |data| guess_format(data) == Some(ImageFormat::Qoi),
+                    decoder: Some(|data| Box::new(qoi::QoiDecoder::new(data))),
+                    encoder: Some(|| Box::new(qoi::QoiEncoder::default())),
+                    probe: Some(|reader, _| qoi::probe(reader)),
+                },
+                Codec {
+                    name: "TGA",
+                    extensions: &["tga", "icb", "vda", "vst"],
+                    detect: |data| guess_format(data) == Some(ImageFormat::Tga),
+                    decoder: Some(|data| Box::new(tga::TgaDecoder::new(data))),
+                    encoder: Some(|| Box::new(tga::TgaEncoder::default())),
+                    probe: Some(|reader, _| tga::probe(reader)),
+                },
+                Codec {
+                    name: "ICO",
+                    extensions: &["ico", "cur"],
+                    detect: |data| guess_format(data) == Some(ImageFormat::Ico),
+                    decoder: Some(|data| Box::new(ico::IcoDecoder::new(data))),
+                    encoder: Some(|| Box::new(ico::IcoEncoder)),
+                    probe: Some(|reader, _| ico::probe(reader)),
+                },
+                Codec {
+                    name: "WebP",
+                    extensions: &["webp"],
+                    detect: |data| guess_format(data) == Some(ImageFormat::WebP),
+                    decoder: Some(|data| Box::new(webp::WebpDecoder::new(data))),
+                    encoder: None,
+                    probe: Some(|reader, _| webp::probe(reader)),
+                },
+                Codec {
+                    name: "TIFF",
+                    extensions: &["tif", "tiff"],
+                    detect: |data| guess_format(data) == Some(ImageFormat::Tiff),
+                    decoder: Some(|data| Box::new(tiff::TiffDecoder::new(data))),
+                    encoder: None,
+                    probe: Some(|reader, _| tiff::probe(reader)),
+                },
+            ],
+        }
+    }
+
+    /* Adds a format, it is tried before the ones registered earlier */
+    pub fn register(&mut self, codec: Codec) {
+        self.codecs.insert(0, codec);
+    }
+
+    pub fn codecs(&self) -> &[Codec] {
+        &self.codecs
+    }
+
+    /* A decoder for the format the data is in */
+    pub fn decoder(&self, data: Vec<u8>) -> Result<Box<dyn ImageDecoder>, String> {
+        let codec = self
+            .codecs
+            .iter()
+            .filter(|c| c.decoder.is_some())
+            .find(|c| (c.detect)(&data));
+        match codec.and_then(|c| c.decoder) {
+            Some(decoder) => Ok(decoder(data)),
+            None => Err(self.unknown_format()),
+        }
+    }
+
+    /* Reads only the header of an image in the format its first bytes show */
+    pub fn probe<R: Read>(&self, mut reader: R, ancillary: bool) -> Result<ImageInfo, String> {
+        let mut data = Vec::new();
+        (&mut reader)
+            .take(18)
+            .read_to_end(&mut data)
+            .map_err(|e| e.to_string())?;
+        let codec = self
+            .codecs
+            .iter()
+            .filter(|c| c.probe.is_some() || c.decoder.is_some())
+            .find(|c| (c.detect)(&data));
+        match codec {
+            Some(Codec {
+                probe: Some(probe), ..
+            }) => probe(&mut data.as_slice().chain(reader), ancillary),
+            Some(Codec {
+                decoder: Some(decoder),
+                ..
+            }) => {
+                reader.read_to_end(&mut data).map_err(|e| e.to_string())?;
+                decoder(data).info()
+            }
+            _ => Err(self.unknown_format()),
+        }
+    }
+
+    /* An encoder for the extension of `path`, if a format claims it */
+    pub fn encoder_for_path(&self, path: &str) -> Option<Box<dyn ImageEncoder>> {
+        let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+        self.codecs
+            .iter()
+            .filter(|c| c.extensions.contains(&extension.as_str()))
+            .find_map(|c| c.encoder)
+            .map(|encoder| encoder())
+    }
+
+    fn unknown_format(&self) -> String {
+        let names: Vec<&str> = self
+            .codecs
+            .iter()
+            .filter(|c| c.decoder.is_some())
+            .map(|c| c.name)
+            .collect();
+        format!(
+            "Unknown image format, supported formats are {}",
+            names.join(", ")
+        )
+    }
+}
+
+#[test]
+fn test_registry() {
+    let registry = Registry::new();
+    let files = [
+        "tests/png_testsuite/basi2c08.png",
+        "tests/png_testsuite/basn3p04.png",
+        "tests/png_testsuite/basn6a16.png",
+        "tests/jpeg/python.jpg",
+        "tests/gif/python.gif",
+        "tests/bmp/basn6a08_v5.bmp",
+        "tests/bmp/basn3p04_core.bmp",
+        "tests/bmp/basn2c08_topdown.bmp",
+        "tests/bmp/basn3p08_rle8.bmp",
+        "tests/tga/basn0g08.tga",
+        "tests/tga/basn2c08_16.tga",
+        "tests/tga/basn3p08_cmap.tga",
+        "tests/tga/basn6a08_rle.tga",
+    ];
+    for path in files.iter() {
+        let data = std::fs::read(path).unwrap();
+        let mut decoder = registry.decoder(data.clone()).unwrap();
+        let info = decoder.info().unwrap();
+        let img = decoder.decode().unwrap();
+        assert_eq!(img, crate::decode(&data).unwrap());
+        assert_eq!((info.width, info.height), (img.width, img.height));
+        assert_eq!(info.pixel_format, img.format);
+
+        let mut rows = 0;
+        decoder
+            .decode_rows(&mut |y, row| {
+                assert_eq!(row, &self::row(&img, y));
+                rows += 1;
+            })
+            .unwrap();
+        assert_eq!(rows, img.height);
+    }
+
+    let encoder = registry.encoder_for_path("out.PPM").unwrap();
+    let img = crate::decode(&std::fs::read("tests/gif/python.gif").unwrap()).unwrap();
+    let encoded = encoder.encode(&img).unwrap();
+    assert_eq!(registry.decoder(encoded).unwrap().decode().unwrap(), img);
+    let encoder = registry.encoder_for_path("out.png").unwrap();
+    let encoded = encoder.encode(&img).unwrap();
+    assert_eq!(registry.decoder(encoded).unwrap().decode().unwrap(), img);
+    assert!(registry.encoder_for_path("out.webp").is_none());
+}
+
+#[test]
+fn test_register() {
+    struct Blank;
+    impl ImageDecoder for Blank {
+        fn info(&mut self) -> Result<ImageInfo, String> {
+            Ok(ImageInfo::new(1, 1, PixelFormat::L8))
+        }
+        fn decode(&mut self) -> Result<Image, String> {
+            Ok(Image::new(1, 1, PixelFormat::L8))
+        }
+    }
+
+    let mut registry = Registry::new();
+    assert!(registry.decoder(b"BLANK".to_vec()).is_err());
+    registry.register(Codec {
+        name: "Blank",
+        extensions: &["blank"],
+        detect: |data| data.starts_with(b"BLANK"),
+        decoder: Some(|_| Box::new(Blank)),
+        encoder: None,
+        probe: None,
+    });
+    let mut decoder = registry.decoder(b"BLANK".to_vec()).unwrap();
+    assert_eq!(decoder.decode().unwrap(), Image::new(1, 1, PixelFormat::L8));
+    let info = registry.probe(&b"BLANK"[..], false).unwrap();
+    assert_eq!(info, ImageInfo::new(1, 1, PixelFormat::L8));
+}
@@ -0,0 +1,144 @@
+/* Detecting the format of a file from its first bytes and dispatching to the
+ * decoder for it */
+use std::io::Read;
+use std::path::Path;
+
+use crate::codec::{ImageInfo, Registry};
+use crate::image::Image;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Bmp,
+    Qoi,
+    /* PBM, PGM, PPM and PAM */
+    Pnm,
+    Tga,
+    /* ICO and CUR */
+    Ico,
+    /* Lossless WebP, lossy files are only probed */
+    WebP,
+    /* Baseline TIFF, the first page */
+    Tiff,
+}
+
+/* TGA has no signature, so this checks that the header fields are sane or
+ * that the file ends with the TGA 2.0 footer */
+fn looks_like_tga(data: &[u8]) -> bool {
+    if data.ends_with(b"TRUEVISION-XFILE.\0") {
+        return true;
+    }
+    if data.len() < 18 {
+        return false;
+    }
+
+    let (colour_map_type, image_type) = (data[1], data[2]);
+    let entry_bits = data[7];
+    let (width, height) = (
+        u16::from_le_bytes([data[12], data[13]]),
+        u16::from_le_bytes([data[14], data[15]]),
+    );
+    let depth = data[16];
+    let colour_mapped = image_type & !8 == 1;
+    let map_ok = match colour_map_type {
+        0 => !colour_mapped,
+        1 => [15, 16, 24, 32].contains(&entry_bits),
+        _ => false,
+    };
+    [1, 2, 3, 9, 10, 11].contains(&image_type)
+        && map_ok
+        && width > 0
+        && height > 0
+        && [8, 15, 16, 24, 32].contains(&depth)
+        && (!colour_mapped || depth == 8 || depth == 16)
+}
+
+/* Guesses the format from the magic bytes at the start of the data */
+pub fn guess_format(data: &[u8]) -> Option<ImageFormat> {
+    let pnm = data.len() >= 2 && data[0] == b'P' && (b'1'..=b'7').contains(&data[1]);
+    /* Reserved zero, type 1 (icon) or 2 (cursor) and a nonzero image count */
+    let ico = data.len() >= 6
+        && data[..2] == [0, 0]
+        && (data[2..4] == [1, 0] || data[2..4] == [2, 0])
+        && data[4..6] != [0, 0];
+    if data.starts_with(&[137, 80, 78, 71, 13, 10, 26, 10]) {
+        Some(ImageFormat::Png)
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some(ImageFormat::Jpeg)
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(ImageFormat::Gif)
+    } else if data.starts_with(b"BM") && data.len() >= 18 {
+        Some(ImageFormat::Bmp)
+    } else if data.starts_with(b"qoif") {
+        Some(ImageFormat::Qoi)
+    } else if pnm {
+        Some(ImageFormat::Pnm)
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some(ImageFormat::WebP)
+    } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        Some(ImageFormat::Tiff)
+    } else if ico {
+        Some(ImageFormat::Ico)
+    } else if looks_like_tga(data) {
+        Some(ImageFormat::Tga)
+    } else {
+        None
+    }
+}
+
+/* Decodes an image in any supported format, only the first frame of an
+ * animation is returned */
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    Registry::new().decoder(data.to_vec())?.decode()
+}
+
+/* Reads only the header of an image in any supported format. Ancillary
+ * data (palette, gamma and text) is gathered when asked for, for PNG that
+ * means reading up to the first IDAT chunk. */
+pub fn probe<R: Read>(reader: R, ancillary: bool) -> Result<ImageInfo, String> {
+    Registry::new().probe(reader, ancillary)
+}
+
+/* Reads and decodes a file */
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Image, String> {
+    let path = path.as_ref();
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    decode(&data)
+}
+
+#[test]
+fn test_guess_format() {
+    let files = [
+        ("tests/png_testsuite/basn0g01.png", ImageFormat::Png),
+        ("tests/jpeg/python.jpg", ImageFormat::Jpeg),
+        ("tests/gif/python.gif", ImageFormat::Gif),
+        ("tests/bmp/basn3p08_rle8.bmp", ImageFormat::Bmp),
+        ("tests/ico/multi.ico", ImageFormat::Ico),
+        ("tests/webp/basn6a08.webp", ImageFormat::WebP),
+        ("tests/tiff/python.tiff", ImageFormat::Tiff),
+    ];
+    for (path, format) in files.iter() {
+        let data = std::fs::read(path).unwrap();
+        assert_eq!(guess_format(&data), Some(*format));
+        let img = open(path).unwrap();
+        assert_eq!(img, decode(&data).unwrap());
+        let info = probe(data.as_slice(), false).unwrap();
+        assert_eq!((info.width, info.height), (img.width, img.height));
+    }
+
+    assert_eq!(
+        guess_format(b"P6\n1 1\n255\n\0\0\0"),
+        Some(ImageFormat::Pnm)
+    );
+    let mut tga = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 24, 0];
+    tga.extend_from_slice(&[0, 0, 255]);
+    assert_eq!(guess_format(&tga), Some(ImageFormat::Tga));
+
+    let info = probe(&b"P2 3\n 2 65535\n"[..], false).unwrap();
+    assert_eq!((info.width, info.height, info.bit_depth), (3, 2, 16));
+
+    let error = decode(b"not an image").unwrap_err();
+    assert!(error.contains("PNG, JPEG, GIF, Netpbm"), "{}", error);
+}
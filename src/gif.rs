@@ -0,0 +1,948 @@
+/* GIF87a and GIF89a decoding. The decoder walks the blocks of the file and
+ * yields one frame at a time, Canvas composites them into the animation as
+ * it would be displayed. Encoding quantises RGBA frames to palettes with
+ * median cut. */
+use std::collections::HashMap;
+use std::io::Read;
+
+use crate::codec::{ImageDecoder, ImageEncoder, ImageInfo};
+use crate::image::{Image, PixelData, PixelFormat};
+
+/* Codes are at most 12 bits wide */
+const MAX_CODES: usize = 4096;
+
+/* What happens to the area of a frame before the next one is drawn */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposal {
+    /* Leave the frame in place */
+    Keep,
+    /* Clear the area to transparent */
+    Background,
+    /* Restore the area to what it was before the frame */
+    Previous,
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub left: u16,
+    pub top: u16,
+    pub width: u16,
+    pub height: u16,
+    /* Palette indices as stored, interlaced frames pass by pass. Frames
+     * whose data runs out early have fewer than width * height. */
+    pub indices: Vec<u8>,
+    /* The local colour table, or the global one when there is none */
+    pub palette: Vec<(u8, u8, u8)>,
+    pub transparent: Option<u8>,
+    /* In hundredths of a second */
+    pub delay: u16,
+    pub disposal: Disposal,
+    pub interlaced: bool,
+}
+
+/* Graphic Control Extension, applies to the image that follows it */
+#[derive(Debug, Clone, Copy)]
+struct Control {
+    delay: u16,
+    disposal: Disposal,
+    transparent: Option<u8>,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Control {
+            delay: 0,
+            disposal: Disposal::Keep,
+            transparent: None,
+        }
+    }
+}
+
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    width: u16,
+    height: u16,
+    global_palette: Vec<(u8, u8, u8)>,
+    background: u8,
+    loop_count: Option<u16>,
+    control: Control,
+    done: bool,
+}
+
+fn read_palette(data: &[u8], pos: &mut usize, size: usize) -> Result<Vec<(u8, u8, u8)>, String> {
+    let bytes = data
+        .get(*pos..*pos + size * 3)
+        .ok_or("Unexpected end of data in colour table")?;
+    *pos += size * 3;
+    Ok(bytes.chunks(3).map(|c| (c[0], c[1], c[2])).collect())
+}
+
+impl<'a> Decoder<'a> {
+    /* Reads the header and the blocks up to the first image */
+    pub fn new(data: &'a [u8]) -> Result<Decoder<'a>, String> {
+        if data.len() < 13 || (&data[..6] != b"GIF87a" && &data[..6] != b"GIF89a") {
+            return Err("Not a GIF file".to_string());
+        }
+
+        let flags = data[10];
+        let mut pos = 13;
+        let global_palette = if flags & 0x80 != 0 {
+            read_palette(data, &mut pos, 2 << (flags & 7))?
+        } else {
+            Vec::new()
+        };
+
+        let mut decoder = Decoder {
+            data,
+            pos,
+            width: u16::from_le_bytes([data[6], data[7]]),
+            height: u16::from_le_bytes([data[8], data[9]]),
+            global_palette,
+            background: data[11],
+            loop_count: None,
+            control: Control::default(),
+            done: false,
+        };
+        decoder.read_extensions()?;
+        Ok(decoder)
+    }
+
+    /* Size of the logical screen */
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    pub fn global_palette(&self) -> &[(u8, u8, u8)] {
+        &self.global_palette
+    }
+
+    pub fn background(&self) -> u8 {
+        self.background
+    }
+
+    /* From the NETSCAPE2.0 extension, Some(0) loops forever and None means
+     * the animation plays once. Encoders write it before the first frame. */
+    pub fn loop_count(&self) -> Option<u16> {
+        self.loop_count
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let b = *self.data.get(self.pos).ok_or("Unexpected end of data")?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    /* Reads a sequence of data sub-blocks up to the terminating empty one.
+     * A truncated file ends the sequence, so what is there can be shown. */
+    fn sub_blocks(&mut self) -> Vec<&'a [u8]> {
+        let mut blocks = Vec::new();
+        while let Some(len) = self.data.get(self.pos).map(|len| *len as usize) {
+            self.pos += 1;
+            if len == 0 {
+                break;
+            }
+            let end = (self.pos + len).min(self.data.len());
+            blocks.push(&self.data[self.pos..end]);
+            self.pos = end;
+        }
+        blocks
+    }
+
+    /* Processes extensions until the next image descriptor or the trailer */
+    fn read_extensions(&mut self) -> Result<(), String> {
+        loop {
+            match self.data.get(self.pos) {
+                Some(0x2c) => return Ok(()),
+                /* A missing trailer is common enough to accept */
+                Some(0x3b) | None => {
+                    self.done = true;
+                    return Ok(());
+                }
+                Some(0x21) => {}
+                Some(b) => return Err(format!("Unknown block 0x{:02x}", b)),
+            }
+
+            self.pos += 1;
+            let label = self.byte()?;
+            let blocks = self.sub_blocks();
+            match (label, blocks.as_slice()) {
+                (0xf9, [control, ..]) if control.len() >= 4 => {
+                    self.control = Control {
+                        delay: u16::from_le_bytes([control[1], control[2]]),
+                        disposal: match (control[0] >> 2) & 7 {
+                            2 => Disposal::Background,
+                            3 => Disposal::Previous,
+                            _ => Disposal::Keep,
+                        },
+                        transparent: (control[0] & 1 != 0).then_some(control[3]),
+                    };
+                }
+                (0xff, [id, sub, ..])
+                    if (*id == b"NETSCAPE2.0" || *id == b"ANIMEXTS1.0")
+                        && sub.len() >= 3
+                        && sub[0] == 1 =>
+                {
+                    self.loop_count = Some(u16::from_le_bytes([sub[1], sub[2]]));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, String> {
+        let descriptor = self
+            .data
+            .get(self.pos + 1..self.pos + 10)
+            .ok_or("Unexpected end of data in image descriptor")?;
+        let field = |i: usize| u16::from_le_bytes([descriptor[i], descriptor[i + 1]]);
+        let (left, top, width, height) = (field(0), field(2), field(4), field(6));
+        let flags = descriptor[8];
+        self.pos += 10;
+
+        let palette = if flags & 0x80 != 0 {
+            read_palette(self.data, &mut self.pos, 2 << (flags & 7))?
+        } else if !self.global_palette.is_empty() {
+            self.global_palette.clone()
+        } else {
+            return Err("Image without a colour table".to_string());
+        };
+
+        let min_code_size = self.byte()?;
+        let data: Vec<u8> = self.sub_blocks().concat();
+        let num_pixels = width as usize * height as usize;
+        let indices = lzw_decode(min_code_size, &data, num_pixels)?;
+        let interlaced = flags & 0x40 != 0;
+
+        let control = std::mem::take(&mut self.control);
+        Ok(Frame {
+            left,
+            top,
+            width,
+            height,
+            indices,
+            palette,
+            transparent: control.transparent,
+            delay: control.delay,
+            disposal: control.disposal,
+            interlaced,
+        })
+    }
+}
+
+impl Iterator for Decoder<'_> {
+    type Item = Result<Frame, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let frame = self
+            .read_frame()
+            .and_then(|frame| self.read_extensions().map(|_| frame));
+        /* Stop after an error, the position in the file is unknown */
+        if frame.is_err() {
+            self.done = true;
+        }
+        Some(frame)
+    }
+}
+
+/* Variable width LZW as used by GIF, with codes packed LSB first. Output
+ * past `limit` is dropped and a missing end code is accepted. */
+fn lzw_decode(min_code_size: u8, data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    if !(1..=8).contains(&min_code_size) {
+        return Err(format!("Invalid LZW code size {}", min_code_size));
+    }
+
+    let clear = 1usize << min_code_size;
+    let end = clear + 1;
+    /* Every string is a previous string plus one byte */
+    let mut prefix = [0u16; MAX_CODES];
+    let mut suffix = [0u8; MAX_CODES];
+    let mut first = [0u8; MAX_CODES];
+    let mut length = [0u16; MAX_CODES];
+    for i in 0..clear {
+        suffix[i] = i as u8;
+        first[i] = i as u8;
+        length[i] = 1;
+    }
+
+    let mut out = Vec::new();
+    let mut width = min_code_size as u32 + 1;
+    let mut next = end + 1;
+    let mut previous: Option<usize> = None;
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    let mut bytes = data.iter();
+
+    while out.len() < limit {
+        while bits < width {
+            match bytes.next() {
+                Some(b) => {
+                    buffer |= (*b as u32) << bits;
+                    bits += 8;
+                }
+                None => return Ok(out),
+            }
+        }
+        let code = (buffer & ((1 << width) - 1)) as usize;
+        buffer >>= width;
+        bits -= width;
+
+        if code == clear {
+            width = min_code_size as u32 + 1;
+            next = end + 1;
+            previous = None;
+            continue;
+        }
+        if code == end {
+            break;
+        }
+
+        match previous {
+            None if code < clear => {}
+            None => return Err(format!("Invalid first LZW code {}", code)),
+            Some(p) => {
+                let k = if code < next {
+                    first[code]
+                } else if code == next {
+                    first[p]
+                } else {
+                    return Err(format!("Invalid LZW code {}", code));
+                };
+                if next < MAX_CODES {
+                    prefix[next] = p as u16;
+                    suffix[next] = k;
+                    first[next] = first[p];
+                    length[next] = length[p] + 1;
+                    next += 1;
+                    if next == 1 << width && width < 12 {
+                        width += 1;
+                    }
+                }
+            }
+        }
+
+        let start = out.len();
+        out.resize(start + length[code] as usize, 0);
+        let mut c = code;
+        for i in (start..out.len()).rev() {
+            out[i] = suffix[c];
+            c = prefix[c] as usize;
+        }
+        previous = Some(code);
+    }
+
+    out.truncate(limit);
+    Ok(out)
+}
+
+impl Frame {
+    /* The index at (x, y) of the frame, None where the data ran out */
+    pub fn index(&self, x: usize, y: usize) -> Option<u8> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let row = match self.interlaced {
+            false => y,
+            true => interlaced_row(y, height),
+        };
+        self.indices.get(row * width + x).copied()
+    }
+}
+
+/* Where display row `y` is stored in an interlaced frame. The passes hold
+ * every 8th row from 0, every 8th from 4, every 4th from 2 and every 2nd
+ * from 1. */
+fn interlaced_row(y: usize, height: usize) -> usize {
+    let passes = [(0, 8), (4, 8), (2, 4), (1, 2)];
+    let mut before = 0;
+    for (start, step) in passes {
+        if y % step == start {
+            return before + (y - start) / step;
+        }
+        before += (height + step - 1 - start) / step;
+    }
+    unreachable!("Every row is in a pass")
+}
+
+/* The logical screen of an animation, frames are drawn onto it in order */
+pub struct Canvas {
+    image: Image,
+    /* Disposal and area (left, top, width, height) of the last frame,
+     * applied before the next one is drawn */
+    dispose: Option<(Disposal, [u16; 4])>,
+    /* The canvas before the last frame, for Disposal::Previous */
+    saved: Vec<u8>,
+}
+
+impl Canvas {
+    /* Starts out fully transparent, fails when the screen size from the
+     * header cannot be allocated */
+    pub fn new(width: u16, height: u16) -> Result<Canvas, String> {
+        let len = width as usize * height as usize * 4;
+        let mut data = Vec::new();
+        data.try_reserve_exact(len)
+            .map_err(|_| format!("GIF screen {}x{} is too large", width, height))?;
+        data.resize(len, 0);
+        Ok(Canvas {
+            image: Image {
+                width: width as u32,
+                height: height as u32,
+                format: PixelFormat::Rgba8,
+                data: PixelData::U8(data),
+            },
+            dispose: None,
+            saved: Vec::new(),
+        })
+    }
+
+    /* Calls `f` with every canvas pixel the area covers, as
+     * (canvas offset, x, y within the area) */
+    fn for_each_pixel(&self, area: [u16; 4], mut f: impl FnMut(usize, usize, usize)) {
+        let (width, height) = (self.image.width as usize, self.image.height as usize);
+        let [left, top, area_width, area_height] = area.map(|v| v as usize);
+        for y in 0..area_height.min(height.saturating_sub(top)) {
+            for x in 0..area_width.min(width.saturating_sub(left)) {
+                f((top + y) * width + left + x, x, y);
+            }
+        }
+    }
+
+    /* Disposes of the previous frame, draws this one and returns the result */
+    pub fn draw(&mut self, frame: &Frame) -> &Image {
+        let PixelData::U8(mut data) =
+            std::mem::replace(&mut self.image.data, PixelData::U8(vec![]))
+        else {
+            unreachable!("The canvas is always RGBA8")
+        };
+
+        match self.dispose.take() {
+            Some((Disposal::Background, area)) => {
+                self.for_each_pixel(area, |i, _, _| data[i * 4..i * 4 + 4].fill(0));
+            }
+            Some((Disposal::Previous, _)) => data.copy_from_slice(&self.saved),
+            _ => {}
+        }
+        if frame.disposal == Disposal::Previous {
+            self.saved.clone_from(&data);
+        }
+
+        let area = [frame.left, frame.top, frame.width, frame.height];
+        self.for_each_pixel(area, |i, x, y| {
+            /* Pixels the data did not reach are left as they were */
+            let Some(index) = frame.index(x, y) else {
+                return;
+            };
+            if frame.transparent == Some(index) {
+                return;
+            }
+            /* Indices past the end of the palette are shown as black */
+            let (r, g, b) = frame.palette.get(index as usize).unwrap_or(&(0, 0, 0));
+            data[i * 4..i * 4 + 4].copy_from_slice(&[*r, *g, *b, 255]);
+        });
+        self.dispose = Some((frame.disposal, area));
+
+        self.image.data = PixelData::U8(data);
+        &self.image
+    }
+
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+}
+
+/* Decodes the first frame as RGBA8 at the size of the logical screen */
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    let mut decoder = Decoder::new(data)?;
+    let mut canvas = Canvas::new(decoder.width(), decoder.height())?;
+    let frame = decoder.next().ok_or("No image data")??;
+    canvas.draw(&frame);
+    Ok(canvas.image)
+}
+
+/* Where the encoder puts the colours of a frame */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteMode {
+    /* A local colour table for every frame */
+    PerFrame,
+    /* One global colour table for all frames */
+    Global,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodeOptions {
+    pub palette: PaletteMode,
+    /* Written as a NETSCAPE2.0 extension, Some(0) loops forever */
+    pub loop_count: Option<u16>,
+    /* Pixels with a lower alpha become transparent */
+    pub alpha_threshold: u8,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            palette: PaletteMode::PerFrame,
+            loop_count: Some(0),
+            alpha_threshold: 128,
+        }
+    }
+}
+
+fn rgba8(img: &Image, i: usize) -> [u8; 4] {
+    img.get_rgba16(i).map(|v| (v >> 8) as u8)
+}
+
+/* Number of pixels of every opaque colour, and whether any are transparent */
+fn histogram(images: &[&Image], alpha_threshold: u8) -> (Vec<([u8; 3], u32)>, bool) {
+    let mut counts: HashMap<[u8; 3], u32> = HashMap::new();
+    let mut transparent = false;
+    for img in images.iter() {
+        for i in 0..img.num_pixels() {
+            let [r, g, b, a] = rgba8(img, i);
+            if a < alpha_threshold {
+                transparent = true;
+            } else {
+                *counts.entry([r, g, b]).or_insert(0) += 1;
+            }
+        }
+    }
+    let mut colours: Vec<([u8; 3], u32)> = counts.into_iter().collect();
+    /* Keeps the output independent of the hash order */
+    colours.sort_unstable();
+    (colours, transparent)
+}
+
+/* Reduces the colours to at most `max` by repeatedly splitting the box with
+ * the widest channel range at its median, the palette holds the averages */
+fn median_cut(colours: Vec<([u8; 3], u32)>, max: usize) -> Vec<[u8; 3]> {
+    let range = |colours: &[([u8; 3], u32)], c: usize| {
+        let (min, max) = colours.iter().fold((255, 0), |(min, max), (colour, _)| {
+            (colour[c].min(min), colour[c].max(max))
+        });
+        max.saturating_sub(min)
+    };
+
+    let mut boxes = vec![colours];
+    while boxes.len() < max {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .flat_map(|(i, b)| (0..3).map(move |c| (range(b, c), i, c)))
+            .max();
+        let Some((_, i, c)) = widest else {
+            break;
+        };
+
+        let mut colours = std::mem::take(&mut boxes[i]);
+        colours.sort_unstable_by_key(|(colour, _)| colour[c]);
+        let total: u64 = colours.iter().map(|(_, n)| *n as u64).sum();
+        let mut seen = 0;
+        let median = colours
+            .iter()
+            .position(|(_, n)| {
+                seen += *n as u64;
+                seen * 2 >= total
+            })
+            .unwrap_or(0);
+        let upper = colours.split_off((median + 1).min(colours.len() - 1));
+        boxes[i] = colours;
+        boxes.push(upper);
+    }
+
+    boxes
+        .iter()
+        .filter(|b| !b.is_empty())
+        .map(|b| {
+            let total: u64 = b.iter().map(|(_, n)| *n as u64).sum();
+            [0, 1, 2].map(|c| {
+                let sum: u64 = b
+                    .iter()
+                    .map(|(colour, n)| colour[c] as u64 * *n as u64)
+                    .sum();
+                ((sum + total / 2) / total) as u8
+            })
+        })
+        .collect()
+}
+
+/* Maps every pixel to the nearest palette entry */
+fn map_indices(img: &Image, palette: &[[u8; 3]], transparent: u8, alpha_threshold: u8) -> Vec<u8> {
+    let mut nearest: HashMap<[u8; 3], u8> = HashMap::new();
+    (0..img.num_pixels())
+        .map(|i| {
+            let [r, g, b, a] = rgba8(img, i);
+            if a < alpha_threshold {
+                return transparent;
+            }
+            *nearest.entry([r, g, b]).or_insert_with(|| {
+                let distance = |p: &[u8; 3]| {
+                    (0..3)
+                        .map(|c| (p[c] as i32 - [r, g, b][c] as i32).pow(2))
+                        .sum::<i32>()
+                };
+                (0..palette.len())
+                    .min_by_key(|i| distance(&palette[*i]))
+                    .unwrap_or(0) as u8
+            })
+        })
+        .collect()
+}
+
+/* Colour table bits for a palette, the table holds 2^bits entries */
+fn table_bits(len: usize) -> u8 {
+    (usize::BITS - len.saturating_sub(1).leading_zeros()).max(1) as u8
+}
+
+fn write_table(out: &mut Vec<u8>, palette: &[[u8; 3]], bits: u8) {
+    for i in 0..1 << bits {
+        out.extend_from_slice(palette.get(i).unwrap_or(&[0, 0, 0]));
+    }
+}
+
+/* Variable width LZW, the reverse of lzw_decode. The table is cleared once
+ * all 4096 codes are in use. */
+fn lzw_encode(min_code_size: u8, indices: &[u8]) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    let mut out = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    let mut emit = |code: u16, width: u32| {
+        buffer |= (code as u32) << bits;
+        bits += width;
+        while bits >= 8 {
+            out.push(buffer as u8);
+            buffer >>= 8;
+            bits -= 8;
+        }
+    };
+    /* The decoder adds codes one step behind, so the width follows the
+     * last code it knows about */
+    let width = |next: u16| (u16::BITS - (next - 1).leading_zeros()).min(12);
+
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut current: Option<u16> = None;
+    emit(clear, min_code_size as u32 + 1);
+    for index in indices.iter() {
+        let Some(code) = current else {
+            current = Some(*index as u16);
+            continue;
+        };
+        if let Some(longer) = table.get(&(code, *index)) {
+            current = Some(*longer);
+            continue;
+        }
+
+        emit(code, width(next));
+        table.insert((code, *index), next);
+        next += 1;
+        if next as usize == MAX_CODES {
+            emit(clear, 12);
+            table.clear();
+            next = end + 1;
+        }
+        current = Some(*index as u16);
+    }
+    if let Some(code) = current {
+        emit(code, width(next));
+        /* Unless this was the first code after a clear, the decoder adds one */
+        if next > end + 1 {
+            next += 1;
+        }
+    }
+    emit(end, width(next));
+    if bits > 0 {
+        out.push(buffer as u8);
+    }
+    out
+}
+
+fn write_sub_blocks(out: &mut Vec<u8>, data: &[u8]) {
+    for block in data.chunks(255) {
+        out.push(block.len() as u8);
+        out.extend_from_slice(block);
+    }
+    out.push(0);
+}
+
+/* Encodes frames of equal size as a GIF89a, each with its delay in
+ * hundredths of a second */
+pub fn encode(frames: &[(Image, u16)], options: &EncodeOptions) -> Result<Vec<u8>, String> {
+    let (width, height) = match frames.first() {
+        Some((img, _)) => (img.width, img.height),
+        None => return Err("No frames to encode".to_string()),
+    };
+    if width == 0 || height == 0 || width > 65535 || height > 65535 {
+        return Err(format!("Cannot encode a {}x{} image as GIF", width, height));
+    }
+    if frames
+        .iter()
+        .any(|(img, _)| (img.width, img.height) != (width, height))
+    {
+        return Err("All frames must have the same size".to_string());
+    }
+
+    let threshold = options.alpha_threshold;
+    let images: Vec<&Image> = frames.iter().map(|(img, _)| img).collect();
+    /* The transparent index comes after the colours */
+    let palette_for = |images: &[&Image]| {
+        let (colours, transparent) = histogram(images, threshold);
+        let palette = median_cut(colours, 256 - transparent as usize);
+        (transparent, palette)
+    };
+    let global = match options.palette {
+        PaletteMode::Global => Some(palette_for(&images)),
+        PaletteMode::PerFrame => None,
+    };
+    /* Transparent areas have to be cleared before the next frame is drawn */
+    let any_transparent = images.iter().any(|img| histogram(&[img], threshold).1);
+    let disposal = if any_transparent { 2 } else { 1 };
+
+    let mut out = b"GIF89a".to_vec();
+    out.extend_from_slice(&(width as u16).to_le_bytes());
+    out.extend_from_slice(&(height as u16).to_le_bytes());
+    match &global {
+        Some((transparent, palette)) => {
+            let bits = table_bits(palette.len() + *transparent as usize);
+            out.extend_from_slice(&[0xf0 | (bits - 1), 0, 0]);
+            write_table(&mut out, palette, bits);
+        }
+        None => out.extend_from_slice(&[0x70, 0, 0]),
+    }
+
+    if let Some(loop_count) = options.loop_count {
+        out.extend_from_slice(b"\x21\xff\x0bNETSCAPE2.0\x03\x01");
+        out.extend_from_slice(&loop_count.to_le_bytes());
+        out.push(0);
+    }
+
+    for (img, delay) in frames.iter() {
+        let (transparent, palette) = match &global {
+            Some((transparent, palette)) => (*transparent, palette.clone()),
+            None => palette_for(&[img]),
+        };
+        let bits = table_bits(palette.len() + transparent as usize);
+        let transparent_index = palette.len() as u8;
+
+        out.extend_from_slice(&[0x21, 0xf9, 4, disposal << 2 | transparent as u8]);
+        out.extend_from_slice(&delay.to_le_bytes());
+        out.extend_from_slice(&[transparent_index, 0, 0x2c, 0, 0, 0, 0]);
+        out.extend_from_slice(&(width as u16).to_le_bytes());
+        out.extend_from_slice(&(height as u16).to_le_bytes());
+        if global.is_some() {
+            out.push(0);
+        } else {
+            out.push(0x80 | (bits - 1));
+            write_table(&mut out, &palette, bits);
+        }
+
+        let indices = map_indices(img, &palette, transparent_index, threshold);
+        let min_code_size = bits.max(2);
+        out.push(min_code_size);
+        write_sub_blocks(&mut out, &lzw_encode(min_code_size, &indices));
+    }
+
+    out.push(0x3b);
+    Ok(out)
+}
+
+/* Reads the header and the global colour table, if there is one */
+pub fn probe<R: Read>(mut reader: R) -> Result<ImageInfo, String> {
+    let mut header = [0; 13];
+    reader
+        .read_exact(&mut header)
+        .map_err(|e| format!("Reading GIF: {}", e))?;
+    if &header[..6] != b"GIF87a" && &header[..6] != b"GIF89a" {
+        return Err("Not a GIF file".to_string());
+    }
+
+    let mut info = ImageInfo::new(
+        u16::from_le_bytes([header[6], header[7]]) as u32,
+        u16::from_le_bytes([header[8], header[9]]) as u32,
+        PixelFormat::Rgba8,
+    );
+    let flags = header[10];
+    if flags & 0x80 != 0 {
+        let mut table = vec![0; 3 * (2 << (flags & 7))];
+        reader
+            .read_exact(&mut table)
+            .map_err(|e| format!("Reading GIF: {}", e))?;
+        info.bit_depth = (flags & 7) + 1;
+        info.palette = Some(table.chunks(3).map(|c| (c[0], c[1], c[2], 255)).collect());
+    }
+    Ok(info)
+}
+
+/* ImageDecoder for the first frame of a GIF */
+pub struct GifDecoder {
+    data: Vec<u8>,
+}
+
+impl GifDecoder {
+    pub fn new(data: Vec<u8>) -> GifDecoder {
+        GifDecoder { data }
+    }
+}
+
+impl ImageDecoder for GifDecoder {
+    fn info(&mut self) -> Result<ImageInfo, String> {
+        probe(self.data.as_slice())
+    }
+
+    fn decode(&mut self) -> Result<Image, String> {
+        decode(&self.data)
+    }
+
+    fn metadata(&self) -> Vec<(String, String)> {
+        match Decoder::new(&self.data) {
+            Ok(decoder) => vec![
+                (
+                    "loop count".to_string(),
+                    format!("{:?}", decoder.loop_count()),
+                ),
+                (
+                    "global palette".to_string(),
+                    decoder.global_palette().len().to_string(),
+                ),
+            ],
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/* ImageEncoder writing a single frame */
+#[derive(Default)]
+pub struct GifEncoder {
+    pub options: EncodeOptions,
+}
+
+impl ImageEncoder for GifEncoder {
+    fn encode(&self, img: &Image) -> Result<Vec<u8>, String> {
+        encode(&[(img.clone(), 0)], &self.options)
+    }
+}
+
+#[test]
+fn test_decode_gif() {
+    /* The noise image needs a clear once the code table is full and is
+     * interlaced, the references were decoded with a different decoder */
+    for name in ["python", "noise"].iter() {
+        let data = std::fs::read(format!("tests/gif/{}.gif", name)).unwrap();
+        let img = decode(&data).unwrap();
+        let reference = crate::png::Parser::with_options(crate::png::DecodeOptions {
+            format: Some(PixelFormat::Rgba8),
+            ..Default::default()
+        })
+        .parse(std::fs::read(format!("tests/gif/{}.png", name)).unwrap())
+        .unwrap();
+        assert_eq!((img.width, img.height), (reference.width, reference.height));
+        assert_eq!(img.data, reference.data, "{}", name);
+    }
+}
+
+#[test]
+fn test_gif_size_limits() {
+    let gif = |screen: u16| {
+        let mut data = b"GIF89a".to_vec();
+        data.extend_from_slice(&screen.to_le_bytes());
+        data.extend_from_slice(&screen.to_le_bytes());
+        data.extend_from_slice(&[0x80, 0, 0, 0, 0, 0, 255, 255, 255]);
+        data.extend_from_slice(&[0x2c, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0]);
+        /* Clear, index 1 and end */
+        data.extend_from_slice(&[2, 2, 0x4c, 0x01, 0, 0x3b]);
+        data
+    };
+
+    /* The frame keeps only the one index its data holds */
+    let data = gif(1);
+    let frame = Decoder::new(&data).unwrap().next().unwrap().unwrap();
+    assert_eq!(frame.indices, [1]);
+    assert_eq!(frame.index(1, 0), None);
+    let img = decode(&data).unwrap();
+    assert_eq!(img.get_rgba16(0), [0xffff; 4]);
+
+    let error = decode(&gif(0xffff)).unwrap_err();
+    assert!(error.contains("too large"), "{}", error);
+}
+
+#[test]
+fn test_animation() {
+    let data = std::fs::read("tests/gif/anim.gif").unwrap();
+    let decoder = Decoder::new(&data).unwrap();
+    assert_eq!((decoder.width(), decoder.height()), (16, 12));
+    assert_eq!(decoder.loop_count(), Some(3));
+
+    let mut canvas = Canvas::new(16, 12).unwrap();
+    let pixel = |img: &Image, x: usize, y: usize| img.get_rgba16(y * 16 + x).map(|v| v >> 8);
+    let frames: Vec<Frame> = decoder.map(|f| f.unwrap()).collect();
+    assert_eq!(
+        frames.iter().map(|f| f.delay).collect::<Vec<_>>(),
+        [10, 20, 30, 40]
+    );
+
+    let img = canvas.draw(&frames[0]);
+    assert_eq!(pixel(img, 5, 0), [0, 255, 0, 255]);
+    /* Index 0 is transparent, the stripes show through */
+    let img = canvas.draw(&frames[1]);
+    assert_eq!(pixel(img, 4, 2), [0, 255, 0, 255]);
+    assert_eq!(pixel(img, 5, 2), [10, 20, 30, 255]);
+    /* The previous frame was disposed to the background */
+    let img = canvas.draw(&frames[2]);
+    assert_eq!(pixel(img, 5, 2), [0, 0, 0, 0]);
+    assert_eq!(pixel(img, 0, 0), [0, 0, 255, 255]);
+    /* And this one restores what was there before it */
+    let img = canvas.draw(&frames[3]);
+    assert_eq!(pixel(img, 0, 0), [255, 0, 0, 255]);
+    assert_eq!(pixel(img, 15, 11), [255, 255, 255, 255]);
+}
+
+#[test]
+fn test_encode_gif() {
+    let data = std::fs::read("tests/gif/anim.gif").unwrap();
+    let decoder = Decoder::new(&data).unwrap();
+    let mut canvas = Canvas::new(decoder.width(), decoder.height()).unwrap();
+    let frames: Vec<(Image, u16)> = decoder
+        .map(|f| f.unwrap())
+        .map(|f| (canvas.draw(&f).clone(), f.delay))
+        .collect();
+
+    /* Few enough colours to come back unchanged, including transparency */
+    for palette in [PaletteMode::PerFrame, PaletteMode::Global].iter() {
+        let options = EncodeOptions {
+            palette: *palette,
+            loop_count: Some(5),
+            ..Default::default()
+        };
+        let encoded = encode(&frames, &options).unwrap();
+        let decoder = Decoder::new(&encoded).unwrap();
+        assert_eq!(decoder.loop_count(), Some(5));
+        let mut canvas = Canvas::new(decoder.width(), decoder.height()).unwrap();
+        for ((img, delay), frame) in frames.iter().zip(decoder) {
+            let frame = frame.unwrap();
+            assert_eq!(frame.delay, *delay);
+            assert_eq!(canvas.draw(&frame).data, img.data);
+        }
+    }
+
+    /* Exactly 256 colours, and noisy enough for the code table to fill up */
+    let noise = decode(&std::fs::read("tests/gif/noise.gif").unwrap()).unwrap();
+    let encoded = encode(&[(noise.clone(), 0)], &EncodeOptions::default()).unwrap();
+    assert_eq!(decode(&encoded).unwrap().data, noise.data);
+
+    /* More colours than fit in a palette */
+    let data = std::fs::read("tests/jpeg/progressive.png").unwrap();
+    let img = crate::png::Parser::new().parse(data).unwrap();
+    let encoded = encode(&[(img.clone(), 0)], &EncodeOptions::default()).unwrap();
+    let decoded = decode(&encoded).unwrap();
+    let error: u64 = (0..img.num_pixels())
+        .map(|i| {
+            let (a, b) = (rgba8(&img, i), rgba8(&decoded, i));
+            (0..3).map(|c| a[c].abs_diff(b[c]) as u64).sum::<u64>()
+        })
+        .sum();
+    assert!(error < img.num_pixels() as u64 * 3);
+}
@@ -0,0 +1,317 @@
+/* ICO and CUR files: a directory of images at several sizes, each stored
+ * as a PNG stream or as a BMP DIB without the file header */
+use std::io::Read;
+
+use crate::bmp;
+use crate::codec::{ImageDecoder, ImageEncoder, ImageInfo};
+use crate::image::{Image, PixelFormat};
+use crate::png;
+
+const DIR_SIZE: usize = 6;
+const ENTRY_SIZE: usize = 16;
+const ICON: u16 = 1;
+const CURSOR: u16 = 2;
+const MAX_SIZE: u32 = 256;
+
+/* One entry of the directory. Sizes of 256 are stored as 0. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub width: u32,
+    pub height: u32,
+    pub colour_count: u8,
+    pub bits_per_pixel: u16,
+    /* Only for cursors, which store it in place of planes and bit count */
+    pub hotspot: Option<(u16, u16)>,
+    pub size: u32,
+    pub offset: u32,
+    pub png: bool,
+}
+
+fn read_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+/* Reads the directory, whether it is a cursor and its entries. The PNG flag
+ * is only known when the data of the entries is there. */
+fn read_directory(data: &[u8]) -> Result<(bool, Vec<Entry>), String> {
+    if data.len() < DIR_SIZE || read_u16(data, 0) != 0 {
+        return Err("Not an ICO or CUR file".to_string());
+    }
+    let cursor = match read_u16(data, 2) {
+        ICON => false,
+        CURSOR => true,
+        t => return Err(format!("Unknown ICO resource type {}", t)),
+    };
+    let count = read_u16(data, 4) as usize;
+    if count == 0 {
+        return Err("ICO file has no images".to_string());
+    }
+    if data.len() < DIR_SIZE + count * ENTRY_SIZE {
+        return Err("Truncated ICO directory".to_string());
+    }
+
+    let entries = (0..count)
+        .map(|i| {
+            let e = &data[DIR_SIZE + i * ENTRY_SIZE..][..ENTRY_SIZE];
+            let size = read_u32(e, 8);
+            let offset = read_u32(e, 12);
+            let png = data
+                .get(offset as usize..)
+                .is_some_and(|d| d.starts_with(&[137, 80, 78, 71]));
+            Entry {
+                width: if e[0] == 0 { MAX_SIZE } else { e[0] as u32 },
+                height: if e[1] == 0 { MAX_SIZE } else { e[1] as u32 },
+                colour_count: e[2],
+                bits_per_pixel: if cursor { 0 } else { read_u16(e, 6) },
+                hotspot: cursor.then(|| (read_u16(e, 4), read_u16(e, 6))),
+                size,
+                offset,
+                png,
+            }
+        })
+        .collect();
+    Ok((cursor, entries))
+}
+
+/* The entry decoded when only one image is wanted: the largest, then the
+ * deepest one */
+fn largest(entries: &[Entry]) -> usize {
+    let (index, _) = entries
+        .iter()
+        .enumerate()
+        .max_by_key(|(i, e)| (e.width * e.height, e.bits_per_pixel, usize::MAX - i))
+        .unwrap();
+    index
+}
+
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    cursor: bool,
+    entries: Vec<Entry>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Decoder<'a>, String> {
+        let (cursor, entries) = read_directory(data)?;
+        Ok(Decoder {
+            data,
+            cursor,
+            entries,
+        })
+    }
+
+    pub fn is_cursor(&self) -> bool {
+        self.cursor
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /* Decodes an entry to RGBA8. The size comes from the stored image,
+     * the directory is not always right about it. */
+    pub fn decode_entry(&self, index: usize) -> Result<Image, String> {
+        let entry = self
+            .entries
+            .get(index)
+            .ok_or(format!("ICO file has no entry {}", index))?;
+        let start = entry.offset as usize;
+        let data = self
+            .data
+            .get(start..start.saturating_add(entry.size as usize))
+            .ok_or(format!("Entry {} is outside the file", index))?;
+        let img = match entry.png {
+            true => png::Parser::new().parse(data.to_vec())?,
+            false => bmp::decode_icon(data)?,
+        };
+        Ok(img.convert(PixelFormat::Rgba8))
+    }
+}
+
+/* Decodes the largest image */
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    let decoder = Decoder::new(data)?;
+    decoder.decode_entry(largest(decoder.entries()))
+}
+
+/* Packs the images into one icon, each as an RGBA8 PNG entry */
+pub fn encode(images: &[Image]) -> Result<Vec<u8>, String> {
+    if images.is_empty() || images.len() > u16::MAX as usize {
+        return Err(format!("Cannot write an ICO with {} images", images.len()));
+    }
+    let mut out = Vec::new();
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out.extend_from_slice(&ICON.to_le_bytes());
+    out.extend_from_slice(&(images.len() as u16).to_le_bytes());
+
+    let mut streams = Vec::with_capacity(images.len());
+    let mut offset = DIR_SIZE + images.len() * ENTRY_SIZE;
+    for img in images {
+        if img.width > MAX_SIZE || img.height > MAX_SIZE {
+            return Err(format!(
+                "Image size {}x{} is too large for ICO",
+                img.width, img.height
+            ));
+        }
+        /* Windows only reads 32-bit RGBA PNG entries */
+        let stream = png::encode(&img.convert(PixelFormat::Rgba8))?;
+        if offset + stream.len() > u32::MAX as usize {
+            return Err("Images are too large for ICO".to_string());
+        }
+        out.push(img.width as u8);
+        out.push(img.height as u8);
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&32u16.to_le_bytes());
+        out.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += stream.len();
+        streams.push(stream);
+    }
+    for stream in streams {
+        out.extend_from_slice(&stream);
+    }
+    Ok(out)
+}
+
+/* Reads the directory and reports the largest entry as RGBA8 */
+pub fn probe<R: Read>(mut reader: R) -> Result<ImageInfo, String> {
+    let mut data = vec![0; DIR_SIZE];
+    reader
+        .read_exact(&mut data)
+        .map_err(|e| format!("Reading ICO: {}", e))?;
+    let count = read_u16(&data, 4) as usize;
+    data.resize(DIR_SIZE + count * ENTRY_SIZE, 0);
+    reader
+        .read_exact(&mut data[DIR_SIZE..])
+        .map_err(|e| format!("Reading ICO: {}", e))?;
+
+    let (_, entries) = read_directory(&data)?;
+    let entry = &entries[largest(&entries)];
+    Ok(ImageInfo::new(
+        entry.width,
+        entry.height,
+        PixelFormat::Rgba8,
+    ))
+}
+
+pub struct IcoDecoder {
+    data: Vec<u8>,
+}
+
+impl IcoDecoder {
+    pub fn new(data: Vec<u8>) -> IcoDecoder {
+        IcoDecoder { data }
+    }
+}
+
+impl ImageDecoder for IcoDecoder {
+    fn info(&mut self) -> Result<ImageInfo, String> {
+        probe(self.data.as_slice())
+    }
+
+    fn decode(&mut self) -> Result<Image, String> {
+        decode(&self.data)
+    }
+
+    fn metadata(&self) -> Vec<(String, String)> {
+        let decoder = match Decoder::new(&self.data) {
+            Ok(decoder) => decoder,
+            Err(_) => return Vec::new(),
+        };
+        let kind = match decoder.is_cursor() {
+            true => "cursor",
+            false => "icon",
+        };
+        let mut entries = vec![("type".to_string(), kind.to_string())];
+        for (i, entry) in decoder.entries().iter().enumerate() {
+            let mut value = format!("{}x{}", entry.width, entry.height);
+            if entry.bits_per_pixel != 0 {
+                value += &format!(", {} bits", entry.bits_per_pixel);
+            }
+            value += if entry.png { ", PNG" } else { ", BMP" };
+            if let Some((x, y)) = entry.hotspot {
+                value += &format!(", hotspot {},{}", x, y);
+            }
+            entries.push((format!("entry {}", i), value));
+        }
+        entries
+    }
+}
+
+/* Writes a single PNG entry icon */
+#[derive(Default)]
+pub struct IcoEncoder;
+
+impl ImageEncoder for IcoEncoder {
+    fn encode(&self, img: &Image) -> Result<Vec<u8>, String> {
+        encode(std::slice::from_ref(img))
+    }
+}
+
+#[test]
+fn test_decode_ico() {
+    use crate::image::PixelData;
+
+    let rgba = |name| crate::open(name).unwrap().convert(PixelFormat::Rgba8);
+    let reference = rgba("tests/png_testsuite/basn6a08.png");
+
+    let data = std::fs::read("tests/ico/multi.ico").unwrap();
+    let decoder = Decoder::new(&data).unwrap();
+    assert!(!decoder.is_cursor());
+    let sizes: Vec<_> = decoder
+        .entries()
+        .iter()
+        .map(|e| (e.width, e.bits_per_pixel, e.png))
+        .collect();
+    assert_eq!(sizes, vec![(16, 4, false), (32, 8, false), (32, 32, true)]);
+    assert_eq!(decode(&data).unwrap(), reference);
+
+    /* The AND mask clears alpha in 4 by 4 squares */
+    let img = decoder.decode_entry(1).unwrap();
+    let palette = rgba("tests/png_testsuite/basn3p08.png");
+    let (PixelData::U8(ours), PixelData::U8(expected)) = (img.data, palette.data) else {
+        panic!("Expected 8-bit samples");
+    };
+    for (i, (a, b)) in ours.chunks(4).zip(expected.chunks(4)).enumerate() {
+        let (x, y) = (i % 32, i / 32);
+        assert_eq!(a[..3], b[..3]);
+        assert_eq!(a[3], if (x / 4 + y / 4) % 2 == 1 { 0 } else { 255 });
+    }
+    assert_eq!(decoder.decode_entry(0).unwrap().width, 16);
+    assert!(decoder.decode_entry(3).is_err());
+
+    /* 32-bit entries keep their own alpha */
+    let data = std::fs::read("tests/ico/basn6a08.cur").unwrap();
+    let decoder = Decoder::new(&data).unwrap();
+    assert!(decoder.is_cursor());
+    assert_eq!(decoder.entries()[0].hotspot, Some((3, 9)));
+    assert_eq!(decoder.decode_entry(0).unwrap(), reference);
+}
+
+#[test]
+fn test_encode_ico() {
+    let data = std::fs::read("tests/png_testsuite/basn2c16.png").unwrap();
+    let large = png::Parser::new().parse(data).unwrap();
+    let small = Image::new(256, 1, PixelFormat::La8);
+    let data = encode(&[small.clone(), large]).unwrap();
+
+    let decoder = Decoder::new(&data).unwrap();
+    let entries = decoder.entries();
+    assert_eq!((entries[0].width, entries[0].height), (256, 1));
+    assert!(entries.iter().all(|e| e.png));
+    assert_eq!(
+        decoder.decode_entry(0).unwrap(),
+        small.convert(PixelFormat::Rgba8)
+    );
+    /* 32x32 has more pixels than 256x1 */
+    let info = probe(data.as_slice()).unwrap();
+    assert_eq!((info.width, info.height), (32, 32));
+
+    assert!(encode(&[Image::new(257, 1, PixelFormat::L8)]).is_err());
+    assert!(encode(&[]).is_err());
+}
@@ -0,0 +1,274 @@
+/* Rec. 709 luma weights, scaled so that they sum to 1 << 16 */
+const LUMA_R: u32 = 13933;
+const LUMA_G: u32 = 46871;
+const LUMA_B: u32 = 4732;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    L8,
+    La8,
+    Rgb8,
+    Rgba8,
+    Bgra8,
+    L16,
+    La16,
+    Rgb16,
+    Rgba16,
+    Rgba32F,
+    /* RGBA8 with the colour channels multiplied by alpha */
+    Rgba8Premultiplied,
+}
+
+impl PixelFormat {
+    pub fn channels(self) -> usize {
+        match self {
+            PixelFormat::L8 | PixelFormat::L16 => 1,
+            PixelFormat::La8 | PixelFormat::La16 => 2,
+            PixelFormat::Rgb8 | PixelFormat::Rgb16 => 3,
+            PixelFormat::Rgba8
+            | PixelFormat::Bgra8
+            | PixelFormat::Rgba16
+            | PixelFormat::Rgba32F
+            | PixelFormat::Rgba8Premultiplied => 4,
+        }
+    }
+
+    pub fn has_alpha(self) -> bool {
+        self.channels() == 2 || self.channels() == 4
+    }
+
+    pub fn is_gray(self) -> bool {
+        self.channels() <= 2
+    }
+
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            PixelFormat::L16 | PixelFormat::La16 | PixelFormat::Rgb16 | PixelFormat::Rgba16 => 2,
+            PixelFormat::Rgba32F => 4,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PixelData {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    F32(Vec<f32>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub data: PixelData,
+}
+
+/* Palette indices together with the palette they refer to */
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    /* Bits per index, rows start on a byte boundary when this is below 8 */
+    pub depth: u8,
+    pub indices: Vec<u8>,
+    /* RGBA entries, alpha comes from tRNS when present */
+    pub palette: Vec<(u8, u8, u8, u8)>,
+}
+
+pub fn luma(r: u16, g: u16, b: u16) -> u16 {
+    ((r as u32 * LUMA_R + g as u32 * LUMA_G + b as u32 * LUMA_B + (1 << 15)) >> 16) as u16
+}
+
+impl PixelData {
+    pub fn new(format: PixelFormat, num_pixels: usize) -> PixelData {
+        let len = num_pixels * format.channels();
+        match format.bytes_per_sample() {
+            1 => PixelData::U8(vec![0; len]),
+            2 => PixelData::U16(vec![0; len]),
+            _ => PixelData::F32(vec![0.0; len]),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            PixelData::U8(d) => d.len(),
+            PixelData::U16(d) => d.len(),
+            PixelData::F32(d) => d.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /* Stores a pixel given as 16-bit RGBA at pixel index `index` */
+    pub fn set_rgba16(&mut self, format: PixelFormat, index: usize, px: [u16; 4]) {
+        let i = index * format.channels();
+        let to8 = |v: u16| (v >> 8) as u8;
+        match (self, format) {
+            (PixelData::U8(d), PixelFormat::L8) => d[i] = to8(luma(px[0], px[1], px[2])),
+            (PixelData::U8(d), PixelFormat::La8) => {
+                d[i] = to8(luma(px[0], px[1], px[2]));
+                d[i + 1] = to8(px[3]);
+            }
+            (PixelData::U8(d), PixelFormat::Rgb8) => {
+                d[i] = to8(px[0]);
+                d[i + 1] = to8(px[1]);
+                d[i + 2] = to8(px[2]);
+            }
+            (PixelData::U8(d), PixelFormat::Rgba8) => {
+                for c in 0..4 {
+                    d[i + c] = to8(px[c]);
+                }
+            }
+            (PixelData::U8(d), PixelFormat::Bgra8) => {
+                d[i] = to8(px[2]);
+                d[i + 1] = to8(px[1]);
+                d[i + 2] = to8(px[0]);
+                d[i + 3] = to8(px[3]);
+            }
+            (PixelData::U8(d), PixelFormat::Rgba8Premultiplied) => {
+                let a = to8(px[3]) as u32;
+                for c in 0..3 {
+                    d[i + c] = ((to8(px[c]) as u32 * a + 127) / 255) as u8;
+                }
+                d[i + 3] = a as u8;
+            }
+            (PixelData::U16(d), PixelFormat::L16) => d[i] = luma(px[0], px[1], px[2]),
+            (PixelData::U16(d), PixelFormat::La16) => {
+                d[i] = luma(px[0], px[1], px[2]);
+                d[i + 1] = px[3];
+            }
+            (PixelData::U16(d), PixelFormat::Rgb16) => d[i..i + 3].copy_from_slice(&px[..3]),
+            (PixelData::U16(d), PixelFormat::Rgba16) => d[i..i + 4].copy_from_slice(&px),
+            (PixelData::F32(d), PixelFormat::Rgba32F) => {
+                for c in 0..4 {
+                    d[i + c] = px[c] as f32 / 65535.0;
+                }
+            }
+            _ => panic!("Pixel data does not match format {:?}", format),
+        }
+    }
+
+    /* Reads the pixel at `index` back as 16-bit RGBA */
+    pub fn get_rgba16(&self, format: PixelFormat, index: usize) -> [u16; 4] {
+        let i = index * format.channels();
+        let to16 = |v: u8| v as u16 * 257;
+        match (self, format) {
+            (PixelData::U8(d), PixelFormat::L8) => {
+                let l = to16(d[i]);
+                [l, l, l, 0xffff]
+            }
+            (PixelData::U8(d), PixelFormat::La8) => {
+                let l = to16(d[i]);
+                [l, l, l, to16(d[i + 1])]
+            }
+            (PixelData::U8(d), PixelFormat::Rgb8) => {
+                [to16(d[i]), to16(d[i + 1]), to16(d[i + 2]), 0xffff]
+            }
+            (PixelData::U8(d), PixelFormat::Rgba8) => {
+                [to16(d[i]), to16(d[i + 1]), to16(d[i + 2]), to16(d[i + 3])]
+            }
+            (PixelData::U8(d), PixelFormat::Bgra8) => {
+                [to16(d[i + 2]), to16(d[i + 1]), to16(d[i]), to16(d[i + 3])]
+            }
+            (PixelData::U8(d), PixelFormat::Rgba8Premultiplied) => {
+                let a = d[i + 3] as u32;
+                let unpremultiply = |v: u8| match a {
+                    0 => 0,
+                    _ => to16(((v as u32 * 255 + a / 2) / a).min(255) as u8),
+                };
+                [
+                    unpremultiply(d[i]),
+                    unpremultiply(d[i + 1]),
+                    unpremultiply(d[i + 2]),
+                    to16(a as u8),
+                ]
+            }
+            (PixelData::U16(d), PixelFormat::L16) => [d[i], d[i], d[i], 0xffff],
+            (PixelData::U16(d), PixelFormat::La16) => [d[i], d[i], d[i], d[i + 1]],
+            (PixelData::U16(d), PixelFormat::Rgb16) => [d[i], d[i + 1], d[i + 2], 0xffff],
+            (PixelData::U16(d), PixelFormat::Rgba16) => [d[i], d[i + 1], d[i + 2], d[i + 3]],
+            (PixelData::F32(d), PixelFormat::Rgba32F) => {
+                let to16 = |v: f32| (v.clamp(0.0, 1.0) * 65535.0 + 0.5) as u16;
+                [to16(d[i]), to16(d[i + 1]), to16(d[i + 2]), to16(d[i + 3])]
+            }
+            _ => panic!("Pixel data does not match format {:?}", format),
+        }
+    }
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Image {
+        Image {
+            width,
+            height,
+            format,
+            data: PixelData::new(format, width as usize * height as usize),
+        }
+    }
+
+    pub fn num_pixels(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    pub fn get_rgba16(&self, index: usize) -> [u16; 4] {
+        self.data.get_rgba16(self.format, index)
+    }
+
+    pub fn set_rgba16(&mut self, index: usize, px: [u16; 4]) {
+        self.data.set_rgba16(self.format, index, px)
+    }
+
+    pub fn convert(&self, format: PixelFormat) -> Image {
+        if format == self.format {
+            return self.clone();
+        }
+
+        let mut result = Image::new(self.width, self.height, format);
+        for i in 0..self.num_pixels() {
+            result.set_rgba16(i, self.get_rgba16(i));
+        }
+
+        result
+    }
+}
+
+#[test]
+fn test_convert_round_trip() {
+    let mut img = Image::new(2, 1, PixelFormat::Rgba8);
+    img.data = PixelData::U8(vec![10, 20, 30, 40, 250, 128, 0, 255]);
+
+    for format in [
+        PixelFormat::Bgra8,
+        PixelFormat::Rgba16,
+        PixelFormat::Rgba32F,
+    ]
+    .iter()
+    {
+        assert_eq!(img.convert(*format).convert(PixelFormat::Rgba8), img);
+    }
+    assert_eq!(
+        img.convert(PixelFormat::Bgra8).data,
+        PixelData::U8(vec![30, 20, 10, 40, 0, 128, 250, 255])
+    );
+    assert_eq!(
+        img.convert(PixelFormat::Rgba8Premultiplied).data,
+        PixelData::U8(vec![2, 3, 5, 40, 250, 128, 0, 255])
+    );
+}
+
+#[test]
+fn test_convert_gray() {
+    let mut img = Image::new(3, 1, PixelFormat::L8);
+    img.data = PixelData::U8(vec![0, 77, 255]);
+
+    /* Gray must survive a trip through RGB unchanged */
+    assert_eq!(img.convert(PixelFormat::Rgb8).convert(PixelFormat::L8), img);
+    assert_eq!(
+        img.convert(PixelFormat::La16).data,
+        PixelData::U16(vec![0, 0xffff, 77 * 257, 0xffff, 0xffff, 0xffff])
+    );
+}
@@ -0,0 +1,1734 @@
+/* Baseline and progressive JPEG decoding (ITU T.81). Scans are entropy decoded
+ * into a plane of coefficients per component, which is transformed, upsampled
+ * and colour converted once all scans have been read. Encoding produces
+ * baseline files. */
+
+use std::io::Read;
+
+use crate::codec::{ImageDecoder, ImageEncoder, ImageInfo};
+use crate::image::{Image, PixelData, PixelFormat};
+use crate::zlib::canonical_codes;
+
+/* Natural order index of the nth coefficient in zigzag order */
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/* Codes up to this length are decoded with a single table lookup */
+const LOOKUP_BITS: usize = 8;
+
+struct HuffmanTable {
+    /* (code length, value) for every LOOKUP_BITS prefix, length 0 for longer codes */
+    lookup: Vec<(u8, u8)>,
+    /* Largest code of each length, -1 when there are none */
+    maxcode: [i32; 17],
+    /* Added to a code of each length to get the index of its value */
+    offset: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    fn new(counts: &[u8], values: Vec<u8>) -> Result<HuffmanTable, String> {
+        /* The values are listed by increasing code length, so their codes are
+         * the canonical codes of those lengths, just like in DEFLATE */
+        let lengths: Vec<u32> = counts
+            .iter()
+            .enumerate()
+            .flat_map(|(i, count)| std::iter::repeat_n(i as u32 + 1, *count as usize))
+            .collect();
+        let codes = canonical_codes(&lengths);
+
+        let mut table = HuffmanTable {
+            lookup: vec![(0, 0); 1 << LOOKUP_BITS],
+            maxcode: [-1; 17],
+            offset: [0; 17],
+            values,
+        };
+        for (i, (len, code)) in lengths.iter().zip(codes.iter()).enumerate() {
+            let (len, code) = (*len as usize, *code as usize);
+            if code >> len != 0 {
+                return Err("Invalid Huffman table".to_string());
+            }
+            if table.maxcode[len] == -1 {
+                table.offset[len] = i as i32 - code as i32;
+            }
+            table.maxcode[len] = code as i32;
+            if len <= LOOKUP_BITS {
+                let shift = LOOKUP_BITS - len;
+                for j in 0..1 << shift {
+                    table.lookup[code << shift | j] = (len as u8, table.values[i]);
+                }
+            }
+        }
+
+        Ok(table)
+    }
+}
+
+/* Reads entropy coded data, removing stuffed zero bytes and stopping at markers */
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u64,
+    bits: usize,
+    /* The marker that ended the entropy coded segment, `pos` points at its 0xFF */
+    marker: Option<u8>,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos,
+            buffer: 0,
+            bits: 0,
+            marker: None,
+        }
+    }
+
+    /* Tops up the buffer, feeding zeros once a marker or the end is reached */
+    fn fill(&mut self) {
+        while self.bits <= 56 {
+            let mut byte = 0;
+            if self.marker.is_none() && self.pos < self.data.len() {
+                byte = self.data[self.pos];
+                if byte != 0xff {
+                    self.pos += 1;
+                } else {
+                    match self.data.get(self.pos + 1) {
+                        Some(0) => self.pos += 2,
+                        /* Fill bytes before a marker */
+                        Some(0xff) => {
+                            self.pos += 1;
+                            continue;
+                        }
+                        next => {
+                            self.marker = Some(*next.unwrap_or(&0xd9));
+                            byte = 0;
+                        }
+                    }
+                }
+            }
+            self.buffer |= (byte as u64) << (56 - self.bits);
+            self.bits += 8;
+        }
+    }
+
+    fn peek(&mut self, n: usize) -> usize {
+        if self.bits < n {
+            self.fill();
+        }
+        (self.buffer >> (64 - n)) as usize
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.buffer <<= n;
+        self.bits -= n;
+    }
+
+    fn bits(&mut self, n: usize) -> usize {
+        if n == 0 {
+            return 0;
+        }
+        let v = self.peek(n);
+        self.consume(n);
+        v
+    }
+
+    fn bit(&mut self) -> bool {
+        self.bits(1) == 1
+    }
+
+    /* Reads an n-bit magnitude and sign extends it as described in F.2.2.1 */
+    fn receive_extend(&mut self, n: usize) -> i32 {
+        let v = self.bits(n) as i32;
+        if n > 0 && v < 1 << (n - 1) {
+            v - (1 << n) + 1
+        } else {
+            v
+        }
+    }
+
+    fn decode(&mut self, table: &HuffmanTable) -> Result<u8, String> {
+        let (len, value) = table.lookup[self.peek(LOOKUP_BITS)];
+        if len > 0 {
+            self.consume(len as usize);
+            return Ok(value);
+        }
+
+        let code = self.peek(16) as i32;
+        for len in LOOKUP_BITS + 1..=16 {
+            let c = code >> (16 - len);
+            if c <= table.maxcode[len] {
+                self.consume(len);
+                return Ok(table.values[(table.offset[len] + c) as usize]);
+            }
+        }
+        Err("Invalid Huffman code".to_string())
+    }
+
+    /* Skips to the RSTn marker that ends a restart interval */
+    fn restart(&mut self) -> Result<(), String> {
+        self.buffer = 0;
+        self.bits = 0;
+        if self.marker.is_none() {
+            self.pos = find_marker(self.data, self.pos);
+            self.marker = self.data.get(self.pos + 1).copied();
+        }
+        match self.marker {
+            Some(0xd0..=0xd7) => {
+                self.pos += 2;
+                self.marker = None;
+                Ok(())
+            }
+            _ => Err("Missing restart marker".to_string()),
+        }
+    }
+}
+
+/* Position of the next 0xFF that starts a marker, or the end of the data */
+fn find_marker(data: &[u8], mut pos: usize) -> usize {
+    while pos + 1 < data.len()
+        && !(data[pos] == 0xff && data[pos + 1] != 0 && data[pos + 1] != 0xff)
+    {
+        pos += 1;
+    }
+    pos.min(data.len())
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<usize, String> {
+    match data.get(pos..pos + 2) {
+        Some(b) => Ok((b[0] as usize) << 8 | b[1] as usize),
+        None => Err("Unexpected end of JPEG data".to_string()),
+    }
+}
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    quant_table: usize,
+    /* Size of the coefficient plane in blocks, padded to whole MCUs */
+    blocks_w: usize,
+    blocks_h: usize,
+    /* Quantised coefficients of every block, in natural order */
+    coefficients: Vec<i16>,
+    /* Size of the component in samples */
+    width: usize,
+    height: usize,
+    dc_table: usize,
+    ac_table: usize,
+    dc_pred: i32,
+}
+
+impl Component {
+    fn block(&mut self, x: usize, y: usize) -> &mut [i16] {
+        let start = (y * self.blocks_w + x) * 64;
+        &mut self.coefficients[start..start + 64]
+    }
+}
+
+/* The components of a scan and the coefficients it contains */
+struct Scan {
+    components: Vec<usize>,
+    /* Spectral selection, zigzag indices of the first and last coefficient */
+    start: usize,
+    end: usize,
+    /* Successive approximation, the bit position of the previous and this scan */
+    high: u8,
+    low: u8,
+}
+
+pub struct Parser {
+    width: usize,
+    height: usize,
+    components: Vec<Component>,
+    h_max: usize,
+    v_max: usize,
+    mcus_x: usize,
+    mcus_y: usize,
+    /* Quantisation tables in natural order */
+    quant_tables: [[u16; 64]; 4],
+    dc_tables: [Option<HuffmanTable>; 4],
+    ac_tables: [Option<HuffmanTable>; 4],
+    restart_interval: usize,
+    /* Transform flag of the Adobe APP14 marker */
+    adobe_transform: Option<u8>,
+    has_frame: bool,
+    progressive: bool,
+    num_scans: usize,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Parser::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser {
+            width: 0,
+            height: 0,
+            components: Vec::new(),
+            h_max: 1,
+            v_max: 1,
+            mcus_x: 0,
+            mcus_y: 0,
+            quant_tables: [[0; 64]; 4],
+            dc_tables: [None, None, None, None],
+            ac_tables: [None, None, None, None],
+            restart_interval: 0,
+            adobe_transform: None,
+            has_frame: false,
+            progressive: false,
+            num_scans: 0,
+        }
+    }
+
+    /* Decodes a JPEG file into L8 or Rgb8, depending on the number of components */
+    pub fn parse(&mut self, data: Vec<u8>) -> Result<Image, String> {
+        self.decode(data, None)
+    }
+
+    /* Like parse, but calls `on_scan` with the image decoded so far after
+     * every scan, so a progressive JPEG can be shown while it loads */
+    pub fn parse_progressive<F: FnMut(&Image)>(
+        &mut self,
+        data: Vec<u8>,
+        mut on_scan: F,
+    ) -> Result<Image, String> {
+        self.decode(data, Some(&mut on_scan))
+    }
+
+    fn decode(
+        &mut self,
+        data: Vec<u8>,
+        mut on_scan: Option<&mut dyn FnMut(&Image)>,
+    ) -> Result<Image, String> {
+        if !data.starts_with(&[0xff, 0xd8]) {
+            return Err("Not a JPEG file".to_string());
+        }
+
+        let mut pos = 2;
+        loop {
+            pos = find_marker(&data, pos);
+            if pos + 1 >= data.len() {
+                return Err("Unexpected end of JPEG data, missing EOI".to_string());
+            }
+            let marker = data[pos + 1];
+            pos += 2;
+            match marker {
+                /* EOI */
+                0xd9 => break,
+                /* Markers without a segment */
+                0x01 | 0xd0..=0xd7 => continue,
+                _ => {}
+            }
+
+            let length = read_u16(&data, pos)?;
+            if length < 2 || pos + length > data.len() {
+                return Err(format!(
+                    "Invalid length {} for marker {:02X}",
+                    length, marker
+                ));
+            }
+            let segment = &data[pos + 2..pos + length];
+            pos += length;
+
+            match marker {
+                0xc0 | 0xc1 => self.read_frame(segment)?,
+                0xc2 => {
+                    self.progressive = true;
+                    self.read_frame(segment)?;
+                }
+                0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                    return Err(format!("Unsupported JPEG process SOF{}", marker - 0xc0))
+                }
+                0xc4 => self.read_huffman_tables(segment)?,
+                0xcc => return Err("Arithmetic coding is not supported".to_string()),
+                0xdb => self.read_quant_tables(segment)?,
+                0xdd => self.restart_interval = read_u16(segment, 0)?,
+                0xda => {
+                    let scan = self.read_scan_header(segment)?;
+                    if self.num_scans == 0 {
+                        self.allocate_planes(data.len() - pos)?;
+                    }
+                    pos = self.decode_scan(&data, pos, &scan)?;
+                    self.num_scans += 1;
+                    if let Some(on_scan) = on_scan.as_mut() {
+                        on_scan(&self.render()?);
+                    }
+                }
+                0xee if segment.starts_with(b"Adobe") && segment.len() >= 12 => {
+                    self.adobe_transform = Some(segment[11]);
+                }
+                /* APPn, COM and friends */
+                _ => {}
+            }
+        }
+
+        if self.num_scans == 0 {
+            return Err("JPEG contains no scans".to_string());
+        }
+        self.render()
+    }
+
+    fn read_frame(&mut self, data: &[u8]) -> Result<(), String> {
+        if self.has_frame {
+            return Err("Multiple frames in JPEG".to_string());
+        }
+        if data.len() < 6 {
+            return Err("Invalid SOF length".to_string());
+        }
+        if data[0] != 8 {
+            return Err(format!("Unsupported sample precision {}", data[0]));
+        }
+        self.height = read_u16(data, 1)?;
+        self.width = read_u16(data, 3)?;
+        if self.width == 0 || self.height == 0 {
+            return Err(format!("Invalid dimensions {}x{}", self.width, self.height));
+        }
+        let num_components = data[5] as usize;
+        if num_components != 1 && num_components != 3 {
+            return Err(format!(
+                "Unsupported number of components {}",
+                num_components
+            ));
+        }
+        if data.len() != 6 + num_components * 3 {
+            return Err("Invalid SOF length".to_string());
+        }
+
+        for c in data[6..].chunks(3) {
+            let (h, v) = ((c[1] >> 4) as usize, (c[1] & 15) as usize);
+            if !(1..=4).contains(&h) || !(1..=4).contains(&v) || c[2] > 3 {
+                return Err(format!("Invalid parameters for component {}", c[0]));
+            }
+            self.components.push(Component {
+                id: c[0],
+                h,
+                v,
+                quant_table: c[2] as usize,
+                blocks_w: 0,
+                blocks_h: 0,
+                coefficients: Vec::new(),
+                width: 0,
+                height: 0,
+                dc_table: 0,
+                ac_table: 0,
+                dc_pred: 0,
+            });
+        }
+
+        self.h_max = self.components.iter().map(|c| c.h).max().unwrap();
+        self.v_max = self.components.iter().map(|c| c.v).max().unwrap();
+        self.mcus_x = self.width.div_ceil(8 * self.h_max);
+        self.mcus_y = self.height.div_ceil(8 * self.v_max);
+        for c in self.components.iter_mut() {
+            c.blocks_w = self.mcus_x * c.h;
+            c.blocks_h = self.mcus_y * c.v;
+            c.width = (self.width * c.h).div_ceil(self.h_max);
+            c.height = (self.height * c.v).div_ceil(self.v_max);
+        }
+        self.has_frame = true;
+
+        Ok(())
+    }
+
+    /* Sizes the coefficient planes at the first scan. Every block costs at
+     * least a bit for its DC coefficient, so the data left bounds the size. */
+    fn allocate_planes(&mut self, remaining: usize) -> Result<(), String> {
+        if self.width * self.height > remaining.saturating_mul(8 * 64) {
+            return Err(format!(
+                "{} bytes of scan data cannot cover {}x{} pixels",
+                remaining, self.width, self.height
+            ));
+        }
+        for c in self.components.iter_mut() {
+            let len = c.blocks_w * c.blocks_h * 64;
+            c.coefficients
+                .try_reserve_exact(len)
+                .map_err(|_| "JPEG image is too large".to_string())?;
+            c.coefficients.resize(len, 0);
+        }
+        Ok(())
+    }
+
+    fn read_huffman_tables(&mut self, mut data: &[u8]) -> Result<(), String> {
+        while !data.is_empty() {
+            if data.len() < 17 {
+                return Err("Invalid DHT length".to_string());
+            }
+            let (class, id) = (data[0] >> 4, (data[0] & 15) as usize);
+            let counts = &data[1..17];
+            let total: usize = counts.iter().map(|c| *c as usize).sum();
+            if class > 1 || id > 3 || data.len() < 17 + total {
+                return Err("Invalid DHT segment".to_string());
+            }
+
+            let table = HuffmanTable::new(counts, data[17..17 + total].to_vec())?;
+            match class {
+                0 => self.dc_tables[id] = Some(table),
+                _ => self.ac_tables[id] = Some(table),
+            }
+            data = &data[17 + total..];
+        }
+        Ok(())
+    }
+
+    fn read_quant_tables(&mut self, mut data: &[u8]) -> Result<(), String> {
+        while !data.is_empty() {
+            let (precision, id) = (data[0] >> 4, (data[0] & 15) as usize);
+            let size = 64 * (precision as usize + 1);
+            if precision > 1 || id > 3 || data.len() < 1 + size {
+                return Err("Invalid DQT segment".to_string());
+            }
+
+            for k in 0..64 {
+                self.quant_tables[id][ZIGZAG[k]] = match precision {
+                    0 => data[1 + k] as u16,
+                    _ => read_u16(data, 1 + 2 * k)? as u16,
+                };
+            }
+            data = &data[1 + size..];
+        }
+        Ok(())
+    }
+
+    fn read_scan_header(&mut self, data: &[u8]) -> Result<Scan, String> {
+        if !self.has_frame {
+            return Err("SOS before SOF".to_string());
+        }
+        let num_components = *data.first().unwrap_or(&0) as usize;
+        if num_components == 0 || num_components > 4 || data.len() != 4 + num_components * 2 {
+            return Err("Invalid SOS segment".to_string());
+        }
+
+        let mut components = Vec::with_capacity(num_components);
+        for c in data[1..1 + num_components * 2].chunks(2) {
+            let index = match self.components.iter().position(|comp| comp.id == c[0]) {
+                Some(index) => index,
+                None => return Err(format!("Scan refers to unknown component {}", c[0])),
+            };
+            let component = &mut self.components[index];
+            component.dc_table = (c[1] >> 4) as usize;
+            component.ac_table = (c[1] & 15) as usize;
+            if component.dc_table > 3 || component.ac_table > 3 {
+                return Err("Invalid Huffman table selector".to_string());
+            }
+            components.push(index);
+        }
+
+        let params = &data[1 + num_components * 2..];
+        let scan = Scan {
+            components,
+            start: params[0] as usize,
+            end: params[1] as usize,
+            high: params[2] >> 4,
+            low: params[2] & 15,
+        };
+        if !self.progressive {
+            if scan.start != 0 || scan.end != 63 || params[2] != 0 {
+                return Err("Invalid spectral selection for a sequential JPEG".to_string());
+            }
+        } else if scan.end > 63 || scan.start > scan.end || (scan.start == 0) != (scan.end == 0) {
+            return Err(format!(
+                "Invalid spectral selection {}-{}",
+                scan.start, scan.end
+            ));
+        } else if scan.start > 0 && scan.components.len() != 1 {
+            return Err("AC scans can only contain a single component".to_string());
+        } else if scan.low > 13 || (scan.high != 0 && scan.high != scan.low + 1) {
+            return Err("Invalid successive approximation".to_string());
+        }
+        Ok(scan)
+    }
+
+    /* Decodes the entropy coded data of a scan starting at `pos`, returns the
+     * position of the marker that follows it */
+    fn decode_scan(&mut self, data: &[u8], pos: usize, scan: &Scan) -> Result<usize, String> {
+        /* Refining DC scans are not Huffman coded, the others use one kind of table */
+        let needs_dc = scan.start == 0 && scan.high == 0;
+        let needs_ac = !self.progressive || scan.start > 0;
+        for &c in scan.components.iter() {
+            let component = &self.components[c];
+            if (needs_dc && self.dc_tables[component.dc_table].is_none())
+                || (needs_ac && self.ac_tables[component.ac_table].is_none())
+            {
+                return Err("Scan uses an undefined Huffman table".to_string());
+            }
+        }
+
+        /* A scan with a single component has one block per MCU and only covers
+         * the blocks inside the component */
+        let single = scan.components.len() == 1;
+        let (mcus_x, num_mcus) = if single {
+            let c = &self.components[scan.components[0]];
+            let blocks_x = c.width.div_ceil(8);
+            (blocks_x, blocks_x * c.height.div_ceil(8))
+        } else {
+            (self.mcus_x, self.mcus_x * self.mcus_y)
+        };
+
+        let mut reader = BitReader::new(data, pos);
+        let mut eob_run = 0;
+        for &c in scan.components.iter() {
+            self.components[c].dc_pred = 0;
+        }
+        for mcu in 0..num_mcus {
+            if self.restart_interval > 0 && mcu > 0 && mcu % self.restart_interval == 0 {
+                reader.restart()?;
+                eob_run = 0;
+                for &c in scan.components.iter() {
+                    self.components[c].dc_pred = 0;
+                }
+            }
+
+            let (mcu_x, mcu_y) = (mcu % mcus_x, mcu / mcus_x);
+            for &c in scan.components.iter() {
+                let component = &mut self.components[c];
+                let dc = self.dc_tables[component.dc_table].as_ref();
+                let ac = self.ac_tables[component.ac_table].as_ref();
+                let (h, v) = if single {
+                    (1, 1)
+                } else {
+                    (component.h, component.v)
+                };
+                for y in 0..v {
+                    for x in 0..h {
+                        let mut pred = component.dc_pred;
+                        let block = component.block(mcu_x * h + x, mcu_y * v + y);
+                        let r = &mut reader;
+                        match (self.progressive, scan.start, scan.high) {
+                            (false, _, _) => {
+                                decode_block(r, dc.unwrap(), ac.unwrap(), &mut pred, block)?
+                            }
+                            (true, 0, 0) => {
+                                decode_dc_first(r, dc.unwrap(), &mut pred, block, scan.low)?
+                            }
+                            (true, 0, _) => decode_dc_refine(r, block, scan.low),
+                            (true, _, 0) => {
+                                decode_ac_first(r, ac.unwrap(), block, scan, &mut eob_run)?
+                            }
+                            (true, _, _) => {
+                                decode_ac_refine(r, ac.unwrap(), block, scan, &mut eob_run)?
+                            }
+                        }
+                        component.dc_pred = pred;
+                    }
+                }
+            }
+        }
+
+        Ok(match reader.marker {
+            Some(_) => reader.pos,
+            None => find_marker(data, reader.pos),
+        })
+    }
+
+    /* Whether three components hold YCbCr rather than RGB */
+    fn is_ycbcr(&self) -> bool {
+        match self.adobe_transform {
+            Some(transform) => transform != 0,
+            None => {
+                let ids: Vec<u8> = self.components.iter().map(|c| c.id).collect();
+                ids != b"RGB"
+            }
+        }
+    }
+
+    fn render(&self) -> Result<Image, String> {
+        let (width, height) = (self.width, self.height);
+        let planes: Vec<Vec<u8>> = self
+            .components
+            .iter()
+            .map(|c| {
+                let samples = self.samples(c);
+                upsample(c, &samples, (width, height), (self.h_max, self.v_max))
+            })
+            .collect();
+
+        let data = if planes.len() == 1 {
+            planes.into_iter().next().unwrap()
+        } else {
+            let ycbcr = self.is_ycbcr();
+            let mut data = Vec::with_capacity(width * height * 3);
+            for ((a, b), c) in planes[0].iter().zip(&planes[1]).zip(&planes[2]) {
+                if ycbcr {
+                    data.extend_from_slice(&ycbcr_to_rgb(*a, *b, *c));
+                } else {
+                    data.extend_from_slice(&[*a, *b, *c]);
+                }
+            }
+            data
+        };
+
+        Ok(Image {
+            width: width as u32,
+            height: height as u32,
+            format: match self.components.len() {
+                1 => PixelFormat::L8,
+                _ => PixelFormat::Rgb8,
+            },
+            data: PixelData::U8(data),
+        })
+    }
+
+    /* Dequantises and transforms every block of a component, the result has a
+     * stride of blocks_w * 8 */
+    fn samples(&self, c: &Component) -> Vec<u8> {
+        let stride = c.blocks_w * 8;
+        let quant = &self.quant_tables[c.quant_table];
+        let mut samples = vec![0; stride * c.blocks_h * 8];
+        let mut block = [0i32; 64];
+        for by in 0..c.blocks_h {
+            for bx in 0..c.blocks_w {
+                let start = (by * c.blocks_w + bx) * 64;
+                for (i, v) in c.coefficients[start..start + 64].iter().enumerate() {
+                    block[i] = *v as i32 * quant[i] as i32;
+                }
+                idct(&block, &mut samples[by * 8 * stride + bx * 8..], stride);
+            }
+        }
+        samples
+    }
+}
+
+/* Decodes the DC difference and AC coefficients of one sequential block */
+fn decode_block(
+    reader: &mut BitReader,
+    dc: &HuffmanTable,
+    ac: &HuffmanTable,
+    pred: &mut i32,
+    block: &mut [i16],
+) -> Result<(), String> {
+    let size = reader.decode(dc)? as usize;
+    if size > 11 {
+        return Err("Invalid DC coefficient size".to_string());
+    }
+    *pred += reader.receive_extend(size);
+    block[0] = *pred as i16;
+
+    let mut k = 1;
+    while k < 64 {
+        let rs = reader.decode(ac)?;
+        let (run, size) = ((rs >> 4) as usize, (rs & 15) as usize);
+        if size == 0 {
+            if run != 15 {
+                break;
+            }
+            k += 16;
+            continue;
+        }
+        k += run;
+        if k > 63 {
+            return Err("AC coefficient index out of range".to_string());
+        }
+        block[ZIGZAG[k]] = reader.receive_extend(size) as i16;
+        k += 1;
+    }
+    Ok(())
+}
+
+fn decode_dc_first(
+    reader: &mut BitReader,
+    dc: &HuffmanTable,
+    pred: &mut i32,
+    block: &mut [i16],
+    low: u8,
+) -> Result<(), String> {
+    let size = reader.decode(dc)? as usize;
+    if size > 11 {
+        return Err("Invalid DC coefficient size".to_string());
+    }
+    *pred += reader.receive_extend(size);
+    block[0] = (*pred << low) as i16;
+    Ok(())
+}
+
+fn decode_dc_refine(reader: &mut BitReader, block: &mut [i16], low: u8) {
+    if reader.bit() {
+        block[0] |= 1 << low;
+    }
+}
+
+fn decode_ac_first(
+    reader: &mut BitReader,
+    ac: &HuffmanTable,
+    block: &mut [i16],
+    scan: &Scan,
+    eob_run: &mut usize,
+) -> Result<(), String> {
+    if *eob_run > 0 {
+        *eob_run -= 1;
+        return Ok(());
+    }
+
+    let mut k = scan.start;
+    while k <= scan.end {
+        let rs = reader.decode(ac)?;
+        let (run, size) = ((rs >> 4) as usize, (rs & 15) as usize);
+        if size == 0 {
+            if run < 15 {
+                /* This block ends the band, as do the next eob_run blocks */
+                *eob_run = (1 << run) - 1 + reader.bits(run);
+                break;
+            }
+            k += 16;
+            continue;
+        }
+        k += run;
+        if k > scan.end {
+            return Err("AC coefficient index out of range".to_string());
+        }
+        block[ZIGZAG[k]] = (reader.receive_extend(size) << scan.low) as i16;
+        k += 1;
+    }
+    Ok(())
+}
+
+/* Adds a correction bit to a coefficient that is already non-zero */
+fn refine(reader: &mut BitReader, coefficient: &mut i16, bit: i16) {
+    if reader.bit() && *coefficient & bit == 0 {
+        if *coefficient >= 0 {
+            *coefficient += bit;
+        } else {
+            *coefficient -= bit;
+        }
+    }
+}
+
+/* The AC refinement pass from G.1.2.3: new coefficients of magnitude 1 are
+ * placed among the zero ones, coefficients passed over get a correction bit */
+fn decode_ac_refine(
+    reader: &mut BitReader,
+    ac: &HuffmanTable,
+    block: &mut [i16],
+    scan: &Scan,
+    eob_run: &mut usize,
+) -> Result<(), String> {
+    let bit = 1i16 << scan.low;
+    let mut k = scan.start;
+
+    if *eob_run == 0 {
+        while k <= scan.end {
+            let rs = reader.decode(ac)?;
+            let (mut run, size) = ((rs >> 4) as usize, (rs & 15) as usize);
+            let mut value = 0;
+            match size {
+                0 if run < 15 => {
+                    *eob_run = (1 << run) + reader.bits(run);
+                    break;
+                }
+                0 => {}
+                1 => value = if reader.bit() { bit } else { -bit },
+                _ => return Err("Invalid AC refinement size".to_string()),
+            }
+
+            /* Skip `run` zero coefficients, refining the non-zero ones on the way */
+            while k <= scan.end {
+                let coefficient = &mut block[ZIGZAG[k]];
+                if *coefficient != 0 {
+                    refine(reader, coefficient, bit);
+                } else if run == 0 {
+                    break;
+                } else {
+                    run -= 1;
+                }
+                k += 1;
+            }
+            if value != 0 {
+                if k > scan.end {
+                    return Err("AC coefficient index out of range".to_string());
+                }
+                block[ZIGZAG[k]] = value;
+            }
+            k += 1;
+        }
+    }
+
+    if *eob_run > 0 {
+        while k <= scan.end {
+            let coefficient = &mut block[ZIGZAG[k]];
+            if *coefficient != 0 {
+                refine(reader, coefficient, bit);
+            }
+            k += 1;
+        }
+        *eob_run -= 1;
+    }
+    Ok(())
+}
+
+/* Fixed point constants of the islow IDCT from the IJG library */
+const CONST_BITS: i32 = 13;
+const PASS1_BITS: i32 = 2;
+const FIX_0_298631336: i32 = 2446;
+const FIX_0_390180644: i32 = 3196;
+const FIX_0_541196100: i32 = 4433;
+const FIX_0_765366865: i32 = 6270;
+const FIX_0_899976223: i32 = 7373;
+const FIX_1_175875602: i32 = 9633;
+const FIX_1_501321110: i32 = 12299;
+const FIX_1_847759065: i32 = 15137;
+const FIX_1_961570560: i32 = 16069;
+const FIX_2_053119869: i32 = 16819;
+const FIX_2_562915447: i32 = 20995;
+const FIX_3_072711026: i32 = 25172;
+
+/* One dimensional 8 point IDCT, returns the outputs scaled by 1 << CONST_BITS */
+fn idct_1d(v: [i32; 8]) -> [i32; 8] {
+    /* Even part */
+    let z1 = (v[2] + v[6]) * FIX_0_541196100;
+    let tmp2 = z1 - v[6] * FIX_1_847759065;
+    let tmp3 = z1 + v[2] * FIX_0_765366865;
+    let tmp0 = (v[0] + v[4]) << CONST_BITS;
+    let tmp1 = (v[0] - v[4]) << CONST_BITS;
+    let (tmp10, tmp13) = (tmp0 + tmp3, tmp0 - tmp3);
+    let (tmp11, tmp12) = (tmp1 + tmp2, tmp1 - tmp2);
+
+    /* Odd part */
+    let (mut t0, mut t1, mut t2, mut t3) = (v[7], v[5], v[3], v[1]);
+    let (mut z1, mut z2, mut z3, mut z4) = (t0 + t3, t1 + t2, t0 + t2, t1 + t3);
+    let z5 = (z3 + z4) * FIX_1_175875602;
+    t0 *= FIX_0_298631336;
+    t1 *= FIX_2_053119869;
+    t2 *= FIX_3_072711026;
+    t3 *= FIX_1_501321110;
+    z1 *= -FIX_0_899976223;
+    z2 *= -FIX_2_562915447;
+    z3 = z3 * -FIX_1_961570560 + z5;
+    z4 = z4 * -FIX_0_390180644 + z5;
+    t0 += z1 + z3;
+    t1 += z2 + z4;
+    t2 += z2 + z3;
+    t3 += z1 + z4;
+
+    [
+        tmp10 + t3,
+        tmp11 + t2,
+        tmp12 + t1,
+        tmp13 + t0,
+        tmp13 - t0,
+        tmp12 - t1,
+        tmp11 - t2,
+        tmp10 - t3,
+    ]
+}
+
+fn descale(x: i32, n: i32) -> i32 {
+    (x + (1 << (n - 1))) >> n
+}
+
+/* Accurate integer inverse DCT of a dequantised block, writing 8x8 samples */
+fn idct(block: &[i32; 64], out: &mut [u8], stride: usize) {
+    let mut workspace = [0i32; 64];
+    for x in 0..8 {
+        let column: [i32; 8] = std::array::from_fn(|y| block[y * 8 + x]);
+        if column[1..].iter().all(|v| *v == 0) {
+            for y in 0..8 {
+                workspace[y * 8 + x] = column[0] << PASS1_BITS;
+            }
+            continue;
+        }
+        for (y, v) in idct_1d(column).iter().enumerate() {
+            workspace[y * 8 + x] = descale(*v, CONST_BITS - PASS1_BITS);
+        }
+    }
+
+    for y in 0..8 {
+        let row: [i32; 8] = std::array::from_fn(|x| workspace[y * 8 + x]);
+        for (x, v) in idct_1d(row).iter().enumerate() {
+            let v = descale(*v, CONST_BITS + PASS1_BITS + 3) + 128;
+            out[y * stride + x] = v.clamp(0, 255) as u8;
+        }
+    }
+}
+
+/* Scales a component to the full image size. Subsampled components are
+ * interpolated linearly between sample centres, which for 2x subsampling is
+ * the triangle filter libjpeg calls fancy upsampling. */
+fn upsample(c: &Component, samples: &[u8], size: (usize, usize), max: (usize, usize)) -> Vec<u8> {
+    let ((width, height), (h_max, v_max)) = (size, max);
+    let stride = c.blocks_w * 8;
+    if c.width == width && c.height == height {
+        let mut result = Vec::with_capacity(width * height);
+        for y in 0..height {
+            result.extend_from_slice(&samples[y * stride..y * stride + width]);
+        }
+        return result;
+    }
+
+    /* Source position of each output coordinate as (index, weight of the next
+     * sample) in units of 1 / (2 * max) */
+    let positions = |size: usize, src_size: usize, factor: usize, max: usize| {
+        let den = 2 * max;
+        (0..size)
+            .map(|x| {
+                let num = (2 * x + 1) * factor;
+                if num <= max {
+                    return (0, 0);
+                }
+                let num = num - max;
+                let (i, f) = (num / den, num % den);
+                if i + 1 < src_size {
+                    (i, f)
+                } else {
+                    (src_size - 1, 0)
+                }
+            })
+            .collect::<Vec<(usize, usize)>>()
+    };
+    let xs = positions(width, c.width, c.h, h_max);
+    let ys = positions(height, c.height, c.v, v_max);
+    let (dx, dy) = (2 * h_max, 2 * v_max);
+
+    let mut result = Vec::with_capacity(width * height);
+    for (y, fy) in ys.iter() {
+        let row0 = &samples[y * stride..];
+        let row1 = &samples[(y + (*fy > 0) as usize) * stride..];
+        for (x, fx) in xs.iter() {
+            let x1 = x + (*fx > 0) as usize;
+            let top = row0[*x] as usize * (dx - fx) + row0[x1] as usize * fx;
+            let bottom = row1[*x] as usize * (dx - fx) + row1[x1] as usize * fx;
+            let v = (top * (dy - fy) + bottom * fy + dx * dy / 2) / (dx * dy);
+            result.push(v as u8);
+        }
+    }
+    result
+}
+
+/* JFIF YCbCr to RGB with 16-bit fixed point coefficients */
+fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let (y, cb, cr) = (y as i32, cb as i32 - 128, cr as i32 - 128);
+    let r = y + ((91881 * cr + 32768) >> 16);
+    let g = y + ((-22554 * cb - 46802 * cr + 32768) >> 16);
+    let b = y + ((116130 * cb + 32768) >> 16);
+    [
+        r.clamp(0, 255) as u8,
+        g.clamp(0, 255) as u8,
+        b.clamp(0, 255) as u8,
+    ]
+}
+
+/* Annex K quantisation tables, in natural order, used as is at quality 50 */
+const LUMA_QUANT: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+const CHROMA_QUANT: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/* Annex K Huffman tables, as the number of codes of each length and the values */
+const LUMA_DC_COUNTS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const CHROMA_DC_COUNTS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const LUMA_AC_COUNTS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+const LUMA_AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+const CHROMA_AC_COUNTS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const CHROMA_AC_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsampling {
+    /* Chroma at full resolution */
+    S444,
+    /* Chroma at half the horizontal resolution */
+    S422,
+    /* Chroma at half the horizontal and vertical resolution */
+    S420,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodeOptions {
+    /* 1 to 100, scales the standard quantisation tables the way libjpeg does */
+    pub quality: u8,
+    /* Ignored for gray images */
+    pub subsampling: Subsampling,
+    /* Build Huffman tables for the image instead of using the standard ones */
+    pub optimize_huffman: bool,
+    /* Write a JFIF APP0 marker */
+    pub jfif: bool,
+    /* MCUs between restart markers, 0 for none */
+    pub restart_interval: u16,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            quality: 75,
+            subsampling: Subsampling::S420,
+            optimize_huffman: false,
+            jfif: true,
+            restart_interval: 0,
+        }
+    }
+}
+
+fn scale_quant(table: &[u8; 64], quality: u8) -> [u16; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+    table.map(|v| ((v as u32 * scale + 50) / 100).clamp(1, 255) as u16)
+}
+
+/* Huffman table for encoding, in the form it is stored in DHT */
+struct HuffmanCodes {
+    counts: [u8; 16],
+    values: Vec<u8>,
+    /* (code, length) of every value */
+    codes: Vec<(u16, u8)>,
+}
+
+impl HuffmanCodes {
+    fn new(counts: &[u8; 16], values: &[u8]) -> HuffmanCodes {
+        let lengths: Vec<u32> = counts
+            .iter()
+            .enumerate()
+            .flat_map(|(i, count)| std::iter::repeat_n(i as u32 + 1, *count as usize))
+            .collect();
+        let mut codes = vec![(0, 0); 256];
+        for ((value, code), len) in values.iter().zip(canonical_codes(&lengths)).zip(lengths) {
+            codes[*value as usize] = (code, len as u8);
+        }
+
+        HuffmanCodes {
+            counts: *counts,
+            values: values.to_vec(),
+            codes,
+        }
+    }
+
+    /* Code lengths for the given symbol frequencies, limited to 16 bits as
+     * described in Annex K.2 */
+    fn optimal(frequencies: &[u32; 256]) -> HuffmanCodes {
+        /* Symbol 256 reserves the all ones code, which JPEG does not allow */
+        let mut freq: Vec<u64> = frequencies.iter().map(|f| *f as u64).collect();
+        freq.push(1);
+        let mut code_size = [0usize; 257];
+        let mut others = [None; 257];
+
+        loop {
+            /* The two least frequent trees, preferring higher symbols on ties */
+            let mut least = (None, None);
+            for (i, f) in freq.iter().enumerate().filter(|(_, f)| **f > 0) {
+                match least {
+                    (None, _) => least.0 = Some(i),
+                    (Some(a), _) if *f <= freq[a] => least = (Some(i), least.0),
+                    (Some(_), None) => least.1 = Some(i),
+                    (Some(_), Some(b)) if *f <= freq[b] => least.1 = Some(i),
+                    _ => {}
+                }
+            }
+            let (mut v1, mut v2) = match least {
+                (Some(v1), Some(v2)) => (v1, v2),
+                _ => break,
+            };
+
+            freq[v1] += freq[v2];
+            freq[v2] = 0;
+            code_size[v1] += 1;
+            while let Some(next) = others[v1] {
+                v1 = next;
+                code_size[v1] += 1;
+            }
+            others[v1] = Some(v2);
+            code_size[v2] += 1;
+            while let Some(next) = others[v2] {
+                v2 = next;
+                code_size[v2] += 1;
+            }
+        }
+
+        let mut bits = [0u8; 33];
+        for size in code_size.iter().filter(|s| **s > 0) {
+            bits[*size] += 1;
+        }
+        /* Move codes longer than 16 bits up the tree */
+        for i in (17..33).rev() {
+            while bits[i] > 0 {
+                let mut j = i - 2;
+                while bits[j] == 0 {
+                    j -= 1;
+                }
+                bits[i] -= 2;
+                bits[i - 1] += 1;
+                bits[j + 1] += 2;
+                bits[j] -= 1;
+            }
+        }
+        /* Drop the reserved code */
+        let mut i = 16;
+        while bits[i] == 0 {
+            i -= 1;
+        }
+        bits[i] -= 1;
+
+        let mut values: Vec<u8> = (0..256)
+            .filter(|v| code_size[*v] > 0)
+            .map(|v| v as u8)
+            .collect();
+        values.sort_by_key(|v| code_size[*v as usize]);
+        let mut counts = [0; 16];
+        counts.copy_from_slice(&bits[1..17]);
+        HuffmanCodes::new(&counts, &values)
+    }
+}
+
+/* Writes entropy coded data, stuffing a zero after every 0xFF */
+struct BitWriter {
+    data: Vec<u8>,
+    buffer: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, len: u8) {
+        self.buffer = self.buffer << len | value;
+        self.bits += len as u32;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            let byte = (self.buffer >> self.bits) as u8;
+            self.data.push(byte);
+            if byte == 0xff {
+                self.data.push(0);
+            }
+        }
+        self.buffer &= (1 << self.bits) - 1;
+    }
+
+    /* Pads the last byte with ones */
+    fn flush(&mut self) {
+        if self.bits > 0 {
+            self.write((1 << (8 - self.bits)) - 1, (8 - self.bits) as u8);
+        }
+    }
+}
+
+/* A Huffman coded value for the luma (0) or chroma (1) tables, or the
+ * magnitude bits that follow one */
+enum Token {
+    Dc(usize, u8),
+    Ac(usize, u8),
+    Bits(u32, u8),
+    Restart(u8),
+}
+
+/* Size category and magnitude bits of a coefficient, as in F.1.2.1 */
+fn category(v: i32) -> (u8, u32) {
+    let size = 32 - v.unsigned_abs().leading_zeros();
+    let bits = if v < 0 { v - 1 } else { v } as u32 & ((1 << size) - 1);
+    (size as u8, bits)
+}
+
+/* Turns quantised blocks in zigzag order into tokens, `blocks` holds the
+ * component and coefficients of every block in scan order */
+fn tokenize(
+    blocks: &[(usize, [i16; 64])],
+    blocks_per_mcu: usize,
+    restart_interval: usize,
+    mut emit: impl FnMut(Token),
+) {
+    let mut preds = [0i32; 3];
+    for (i, (c, block)) in blocks.iter().enumerate() {
+        let mcu = i / blocks_per_mcu;
+        if restart_interval > 0
+            && i % blocks_per_mcu == 0
+            && mcu > 0
+            && mcu.is_multiple_of(restart_interval)
+        {
+            emit(Token::Restart(((mcu / restart_interval - 1) % 8) as u8));
+            preds = [0; 3];
+        }
+
+        let table = (*c > 0) as usize;
+        let (size, bits) = category(block[0] as i32 - preds[*c]);
+        preds[*c] = block[0] as i32;
+        emit(Token::Dc(table, size));
+        emit(Token::Bits(bits, size));
+
+        let mut run = 0;
+        for v in block[1..].iter() {
+            if *v == 0 {
+                run += 1;
+                continue;
+            }
+            while run >= 16 {
+                emit(Token::Ac(table, 0xf0));
+                run -= 16;
+            }
+            let (size, bits) = category(*v as i32);
+            emit(Token::Ac(table, run << 4 | size));
+            emit(Token::Bits(bits, size));
+            run = 0;
+        }
+        if run > 0 {
+            emit(Token::Ac(table, 0));
+        }
+    }
+}
+
+/* Forward DCT and quantisation of an 8x8 block of level shifted samples,
+ * returning the coefficients in zigzag order */
+fn fdct_quantise(samples: &[f32; 64], quant: &[u16; 64], cos: &[[f32; 8]; 8]) -> [i16; 64] {
+    let mut rows = [0f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| samples[y * 8 + x] * cos[u][x]).sum();
+        }
+    }
+
+    let mut block = [0i16; 64];
+    for (k, n) in ZIGZAG.iter().enumerate() {
+        let (v, u) = (n / 8, n % 8);
+        let coefficient: f32 = (0..8).map(|y| rows[y * 8 + u] * cos[v][y]).sum();
+        block[k] = (coefficient / quant[*n] as f32).round() as i16;
+    }
+    block
+}
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, body: &[u8]) {
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+/* Encodes an image as a baseline JPEG. Gray formats become a single
+ * component, everything else YCbCr, alpha is dropped. */
+pub fn encode(img: &Image, options: &EncodeOptions) -> Result<Vec<u8>, String> {
+    let (width, height) = (img.width as usize, img.height as usize);
+    if width == 0 || height == 0 || width > 65535 || height > 65535 {
+        return Err(format!(
+            "Cannot encode a {}x{} image as JPEG",
+            width, height
+        ));
+    }
+
+    let gray = img.format.is_gray();
+    let (h_max, v_max) = match (gray, options.subsampling) {
+        (true, _) | (_, Subsampling::S444) => (1, 1),
+        (_, Subsampling::S422) => (2, 1),
+        (_, Subsampling::S420) => (2, 2),
+    };
+    let (mcus_x, mcus_y) = (width.div_ceil(8 * h_max), height.div_ceil(8 * v_max));
+
+    /* Full resolution planes, padded to whole MCUs by repeating the edges */
+    let (plane_w, plane_h) = (mcus_x * 8 * h_max, mcus_y * 8 * v_max);
+    let num_planes = if gray { 1 } else { 3 };
+    let mut planes = vec![vec![0f32; plane_w * plane_h]; num_planes];
+    for y in 0..plane_h {
+        for x in 0..plane_w {
+            let px = img.get_rgba16(y.min(height - 1) * width + x.min(width - 1));
+            let [r, g, b] = [px[0], px[1], px[2]].map(|v| (v >> 8) as f32);
+            let i = y * plane_w + x;
+            if gray {
+                planes[0][i] = r - 128.0;
+            } else {
+                planes[0][i] = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+                planes[1][i] = -0.168736 * r - 0.331264 * g + 0.5 * b;
+                planes[2][i] = 0.5 * r - 0.418688 * g - 0.081312 * b;
+            }
+        }
+    }
+
+    /* Subsampled chroma is the average of the samples it covers */
+    let chroma_w = plane_w / h_max;
+    for plane in planes.iter_mut().skip(1) {
+        let mut reduced = vec![0f32; chroma_w * (plane_h / v_max)];
+        for (i, v) in reduced.iter_mut().enumerate() {
+            let (x, y) = (i % chroma_w * h_max, i / chroma_w * v_max);
+            for dy in 0..v_max {
+                for dx in 0..h_max {
+                    *v += plane[(y + dy) * plane_w + x + dx];
+                }
+            }
+            *v /= (h_max * v_max) as f32;
+        }
+        *plane = reduced;
+    }
+
+    let quant = [
+        scale_quant(&LUMA_QUANT, options.quality),
+        scale_quant(&CHROMA_QUANT, options.quality),
+    ];
+    let cos: [[f32; 8]; 8] = std::array::from_fn(|u| {
+        let c = if u == 0 { 0.5f32.sqrt() } else { 1.0 };
+        std::array::from_fn(|x| {
+            c / 2.0 * ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / 16.0).cos()
+        })
+    });
+
+    /* Sampling factors of each component */
+    let factors: Vec<(usize, usize)> = (0..num_planes)
+        .map(|c| if c == 0 { (h_max, v_max) } else { (1, 1) })
+        .collect();
+    let blocks_per_mcu: usize = factors.iter().map(|(h, v)| h * v).sum();
+    let mut blocks = Vec::with_capacity(mcus_x * mcus_y * blocks_per_mcu);
+    let mut samples = [0f32; 64];
+    for mcu_y in 0..mcus_y {
+        for mcu_x in 0..mcus_x {
+            for (c, (h, v)) in factors.iter().enumerate() {
+                let stride = mcus_x * 8 * h;
+                for by in 0..*v {
+                    for bx in 0..*h {
+                        let (x0, y0) = ((mcu_x * h + bx) * 8, (mcu_y * v + by) * 8);
+                        for (i, s) in samples.iter_mut().enumerate() {
+                            *s = planes[c][(y0 + i / 8) * stride + x0 + i % 8];
+                        }
+                        let block = fdct_quantise(&samples, &quant[(c > 0) as usize], &cos);
+                        blocks.push((c, block));
+                    }
+                }
+            }
+        }
+    }
+
+    let restart_interval = options.restart_interval as usize;
+    let num_tables = if gray { 2 } else { 4 };
+    let tables: Vec<HuffmanCodes> = if options.optimize_huffman {
+        let mut frequencies = [[0u32; 256]; 4];
+        tokenize(
+            &blocks,
+            blocks_per_mcu,
+            restart_interval,
+            |token| match token {
+                Token::Dc(table, v) => frequencies[table * 2][v as usize] += 1,
+                Token::Ac(table, v) => frequencies[table * 2 + 1][v as usize] += 1,
+                _ => {}
+            },
+        );
+        frequencies
+            .iter()
+            .take(num_tables)
+            .map(HuffmanCodes::optimal)
+            .collect()
+    } else {
+        vec![
+            HuffmanCodes::new(&LUMA_DC_COUNTS, &DC_VALUES),
+            HuffmanCodes::new(&LUMA_AC_COUNTS, &LUMA_AC_VALUES),
+            HuffmanCodes::new(&CHROMA_DC_COUNTS, &DC_VALUES),
+            HuffmanCodes::new(&CHROMA_AC_COUNTS, &CHROMA_AC_VALUES),
+        ]
+        .into_iter()
+        .take(num_tables)
+        .collect()
+    };
+
+    let mut out = vec![0xff, 0xd8];
+    if options.jfif {
+        /* Version 1.01, no units, 1:1 pixel aspect ratio, no thumbnail */
+        write_segment(&mut out, 0xe0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+    }
+
+    let mut dqt = Vec::new();
+    for (id, table) in quant.iter().take(num_planes.min(2)).enumerate() {
+        dqt.push(id as u8);
+        dqt.extend(ZIGZAG.iter().map(|n| table[*n] as u8));
+    }
+    write_segment(&mut out, 0xdb, &dqt);
+
+    let mut sof = vec![8];
+    sof.extend_from_slice(&(height as u16).to_be_bytes());
+    sof.extend_from_slice(&(width as u16).to_be_bytes());
+    sof.push(num_planes as u8);
+    for (c, (h, v)) in factors.iter().enumerate() {
+        sof.extend_from_slice(&[c as u8 + 1, (h << 4 | v) as u8, (c > 0) as u8]);
+    }
+    write_segment(&mut out, 0xc0, &sof);
+
+    let mut dht = Vec::new();
+    for (i, table) in tables.iter().enumerate() {
+        /* Class in the high nibble, id in the low one */
+        dht.push((((i % 2) << 4) | (i / 2)) as u8);
+        dht.extend_from_slice(&table.counts);
+        dht.extend_from_slice(&table.values);
+    }
+    write_segment(&mut out, 0xc4, &dht);
+
+    if restart_interval > 0 {
+        write_segment(&mut out, 0xdd, &options.restart_interval.to_be_bytes());
+    }
+
+    let mut sos = vec![num_planes as u8];
+    for c in 0..num_planes {
+        let table = (c > 0) as u8;
+        sos.extend_from_slice(&[c as u8 + 1, table << 4 | table]);
+    }
+    sos.extend_from_slice(&[0, 63, 0]);
+    write_segment(&mut out, 0xda, &sos);
+
+    let mut writer = BitWriter {
+        data: out,
+        buffer: 0,
+        bits: 0,
+    };
+    tokenize(
+        &blocks,
+        blocks_per_mcu,
+        restart_interval,
+        |token| match token {
+            Token::Dc(table, v) | Token::Ac(table, v) => {
+                let i = table * 2 + matches!(token, Token::Ac(..)) as usize;
+                let (code, len) = tables[i].codes[v as usize];
+                writer.write(code as u32, len);
+            }
+            Token::Bits(bits, len) => writer.write(bits, len),
+            Token::Restart(n) => {
+                writer.flush();
+                writer.data.extend_from_slice(&[0xff, 0xd0 + n]);
+            }
+        },
+    );
+    writer.flush();
+
+    let mut out = writer.data;
+    out.extend_from_slice(&[0xff, 0xd9]);
+    Ok(out)
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> Result<u8, String> {
+    let mut byte = [0];
+    reader
+        .read_exact(&mut byte)
+        .map_err(|e| format!("Reading JPEG: {}", e))?;
+    Ok(byte[0])
+}
+
+/* Reads markers up to and including the frame header, skipping the segments
+ * before it. No entropy coded data is touched. */
+pub fn probe<R: Read>(mut reader: R) -> Result<ImageInfo, String> {
+    if read_byte(&mut reader)? != 0xff || read_byte(&mut reader)? != 0xd8 {
+        return Err("Not a JPEG file".to_string());
+    }
+
+    loop {
+        while read_byte(&mut reader)? != 0xff {}
+        let mut marker = read_byte(&mut reader)?;
+        while marker == 0xff {
+            marker = read_byte(&mut reader)?;
+        }
+        match marker {
+            0xd9 => return Err("JPEG contains no frame".to_string()),
+            0x00 | 0x01 | 0xd0..=0xd8 => continue,
+            _ => {}
+        }
+
+        let length = (read_byte(&mut reader)? as usize) << 8 | read_byte(&mut reader)? as usize;
+        let mut segment = vec![0; length.saturating_sub(2)];
+        reader
+            .read_exact(&mut segment)
+            .map_err(|e| format!("Reading JPEG: {}", e))?;
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            if segment.len() < 6 {
+                return Err("Invalid SOF length".to_string());
+            }
+            let format = match segment[5] {
+                1 => PixelFormat::L8,
+                _ => PixelFormat::Rgb8,
+            };
+            let mut info = ImageInfo::new(
+                read_u16(&segment, 3)? as u32,
+                read_u16(&segment, 1)? as u32,
+                format,
+            );
+            info.bit_depth = segment[0];
+            return Ok(info);
+        }
+    }
+}
+
+pub struct JpegDecoder {
+    data: Vec<u8>,
+    parser: Parser,
+}
+
+impl JpegDecoder {
+    pub fn new(data: Vec<u8>) -> JpegDecoder {
+        JpegDecoder {
+            data,
+            parser: Parser::new(),
+        }
+    }
+}
+
+impl ImageDecoder for JpegDecoder {
+    fn info(&mut self) -> Result<ImageInfo, String> {
+        probe(self.data.as_slice())
+    }
+
+    fn decode(&mut self) -> Result<Image, String> {
+        self.parser = Parser::new();
+        self.parser.parse(self.data.clone())
+    }
+
+    fn metadata(&self) -> Vec<(String, String)> {
+        vec![
+            (
+                "progressive".to_string(),
+                self.parser.progressive.to_string(),
+            ),
+            ("scans".to_string(), self.parser.num_scans.to_string()),
+            (
+                "components".to_string(),
+                self.parser.components.len().to_string(),
+            ),
+        ]
+    }
+}
+
+#[derive(Default)]
+pub struct JpegEncoder {
+    pub options: EncodeOptions,
+}
+
+impl ImageEncoder for JpegEncoder {
+    fn encode(&self, img: &Image) -> Result<Vec<u8>, String> {
+        encode(img, &self.options)
+    }
+
+    fn set_quality(&mut self, quality: u8) {
+        self.options.quality = quality;
+    }
+}
+
+/* Compares against a reference decoded with a libjpeg compatible decoder,
+ * which rounds differently in places */
+#[cfg(test)]
+fn assert_matches_reference(img: &Image, name: &str) {
+    let data = std::fs::read(format!("tests/jpeg/{}.png", name)).unwrap();
+    let reference = crate::png::Parser::new().parse(data).unwrap();
+
+    assert_eq!(img.width, reference.width);
+    assert_eq!(img.height, reference.height);
+    assert_eq!(img.format, reference.format);
+    match (&img.data, &reference.data) {
+        (PixelData::U8(a), PixelData::U8(b)) => {
+            assert!(a.iter().zip(b.iter()).all(|(x, y)| x.abs_diff(*y) <= 4))
+        }
+        _ => panic!("Expected 8-bit samples"),
+    }
+}
+
+#[test]
+fn test_baseline() {
+    for name in ["basn0g08", "s35n3p04", "python"].iter() {
+        let data = std::fs::read(format!("tests/jpeg/{}.jpg", name)).unwrap();
+        let img = Parser::new().parse(data).unwrap();
+        assert_matches_reference(&img, name);
+    }
+}
+
+#[test]
+fn test_progressive() {
+    /* Spectral selection and successive approximation over 9 scans */
+    let data = std::fs::read("tests/jpeg/progressive.jpg").unwrap();
+    let mut scans = Vec::new();
+    let img = Parser::new()
+        .parse_progressive(data, |img| scans.push(img.clone()))
+        .unwrap();
+
+    assert_matches_reference(&img, "progressive");
+    assert_eq!(scans.len(), 9);
+    assert_eq!(scans.last(), Some(&img));
+    assert_ne!(scans[0], img);
+}
+
+#[test]
+fn test_jpeg_size_limits() {
+    /* A frame header alone allocates nothing */
+    let mut data = vec![0xff, 0xd8];
+    write_segment(
+        &mut data,
+        0xc0,
+        &[
+            8, 0xff, 0xff, 0xff, 0xff, 3, 1, 0x11, 0, 2, 0x11, 1, 3, 0x11, 1,
+        ],
+    );
+    data.extend_from_slice(&[0xff, 0xd9]);
+    assert!(Parser::new().parse(data).is_err());
+
+    /* Patch python.jpg to 65535x65535, far more than its scan can cover */
+    let mut data = std::fs::read("tests/jpeg/python.jpg").unwrap();
+    let sof = data.windows(2).position(|m| m == [0xff, 0xc0]).unwrap();
+    data[sof + 5..sof + 9].copy_from_slice(&[0xff; 4]);
+    let error = Parser::new().parse(data).unwrap_err();
+    assert!(error.contains("cannot cover"), "{}", error);
+}
+
+#[test]
+fn test_encode() {
+    let data = std::fs::read("tests/jpeg/progressive.png").unwrap();
+    let img = crate::png::Parser::new().parse(data).unwrap();
+    let PixelData::U8(original) = &img.data else {
+        panic!("Expected 8-bit samples")
+    };
+
+    let mut sizes = Vec::new();
+    for subsampling in [Subsampling::S444, Subsampling::S422, Subsampling::S420].iter() {
+        for optimize_huffman in [false, true].iter() {
+            let options = EncodeOptions {
+                quality: 90,
+                subsampling: *subsampling,
+                optimize_huffman: *optimize_huffman,
+                restart_interval: 7,
+                ..Default::default()
+            };
+            let encoded = encode(&img, &options).unwrap();
+            sizes.push(encoded.len());
+
+            let decoded = Parser::new().parse(encoded).unwrap();
+            assert_eq!((decoded.width, decoded.height), (img.width, img.height));
+            let PixelData::U8(data) = &decoded.data else {
+                panic!("Expected 8-bit samples")
+            };
+            let error: u64 = data
+                .iter()
+                .zip(original.iter())
+                .map(|(a, b)| a.abs_diff(*b) as u64)
+                .sum();
+            assert!(error < data.len() as u64 * 2, "{:?}", options);
+        }
+    }
+    /* Optimised tables only ever make the file smaller */
+    assert!(sizes.chunks(2).all(|s| s[1] < s[0]));
+
+    let options = EncodeOptions {
+        optimize_huffman: true,
+        ..Default::default()
+    };
+    let gray = encode(&img.convert(PixelFormat::L8), &options).unwrap();
+    assert_eq!(Parser::new().parse(gray).unwrap().format, PixelFormat::L8);
+}
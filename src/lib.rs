@@ -0,0 +1,20 @@
+pub mod bmp;
+pub mod check;
+pub mod codec;
+pub mod format;
+pub mod gif;
+pub mod ico;
+pub mod image;
+pub mod jpeg;
+pub mod png;
+pub mod pnm;
+pub mod qoi;
+pub mod tga;
+pub mod tiff;
+pub mod transform;
+pub mod webp;
+mod zlib;
+
+pub use codec::{ImageDecoder, ImageEncoder, ImageInfo, Registry};
+pub use format::{decode, guess_format, open, probe, ImageFormat};
+pub use image::{Image, IndexedImage, PixelData, PixelFormat};
@@ -0,0 +1,2034 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+use crate::codec::{ImageDecoder, ImageEncoder, ImageInfo};
+use crate::image::{Image, IndexedImage, PixelData, PixelFormat};
+use crate::transform::{Channel, Layout, Transform, TransformWriter, TransformedImage};
+use crate::zlib;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
+enum ChunkType {
+    /* Required */
+    IHDR,
+    PLTE,
+    IDAT,
+    IEND,
+    /* Optional */
+    TEXT,
+    PHYS,
+    ZTXT,
+    GAMA,
+    SBIT,
+    BKGD,
+    CHRM,
+    HIST,
+    TIME,
+    ITXT,
+    TRNS,
+
+    /* Not defined by the spec */
+    UNKNOWN,
+}
+
+const fn to_u32(a: [u8; 4]) -> u32 {
+    let mut result: u32 = 0;
+    result |= (a[0] as u32) << (8 * 3);
+    result |= (a[1] as u32) << (8 * 2);
+    result |= (a[2] as u32) << 8;
+    result |= a[3] as u32;
+
+    result
+}
+
+const PNG_CRC_TABLE: [u64; 256] = make_crc_table();
+
+const fn make_crc_table() -> [u64; 256] {
+    let mut result: [u64; 256] = [0; 256];
+
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u64;
+        let mut j = 0;
+        while j < 8 {
+            if (c & 1) != 0 {
+                c = 0xedb88320 ^ (c >> 1);
+            } else {
+                c >>= 1;
+            }
+            j += 1;
+        }
+        result[i] = c;
+        i += 1;
+    }
+
+    result
+}
+
+fn calc_crc<'a, I: IntoIterator<Item = &'a u8>>(data: I) -> u64 {
+    let mut result = 0xffffffff_u64;
+
+    for byte in data {
+        result = PNG_CRC_TABLE[((result ^ *byte as u64) & 0xff) as usize] ^ (result >> 8);
+    }
+
+    result ^ 0xffffffff
+}
+
+/* Adam7 interlacing passes */
+const STARTING_ROW: [usize; 7] = [0, 0, 4, 0, 2, 0, 1];
+const STARTING_COL: [usize; 7] = [0, 4, 0, 2, 0, 1, 0];
+const ROW_INCREMENT: [usize; 7] = [8, 8, 8, 4, 4, 2, 2];
+const COL_INCREMENT: [usize; 7] = [8, 8, 4, 4, 2, 2, 1];
+
+const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/* A raw chunk as stored in the file */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk<'a> {
+    pub chunk_type: [u8; 4],
+    pub length: u32,
+    pub data: &'a [u8],
+    pub stored_crc: u32,
+    pub computed_crc: u32,
+    /* Offset of the length field from the start of the file */
+    pub offset: usize,
+}
+
+impl<'a> Chunk<'a> {
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.chunk_type).to_string()
+    }
+
+    pub fn crc_matches(&self) -> bool {
+        self.stored_crc == self.computed_crc
+    }
+}
+
+/* Iterates over the chunks of a PNG file without decoding them. CRC
+ * mismatches are reported through the chunks, only truncated chunks end the
+ * iteration with an error. Iteration stops after IEND. */
+#[derive(Debug, Clone)]
+pub struct Chunks<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+pub fn chunks(data: &[u8]) -> Result<Chunks<'_>, String> {
+    if !data.starts_with(&PNG_SIGNATURE) {
+        return Err("Not a PNG file".to_string());
+    }
+
+    Ok(Chunks {
+        data,
+        offset: PNG_SIGNATURE.len(),
+        done: false,
+    })
+}
+
+impl<'a> Chunks<'a> {
+    /* The bytes that were not read yet, after IEND these are trailing data */
+    pub fn remainder(&self) -> &'a [u8] {
+        &self.data[self.offset..]
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<Chunk<'a>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset == self.data.len() {
+            return None;
+        }
+
+        let data = &self.data[self.offset..];
+        let read_u32 = |i: usize| to_u32([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        if data.len() < 12 {
+            self.done = true;
+            return Some(Err(format!(
+                "Truncated chunk header at offset {}",
+                self.offset
+            )));
+        }
+
+        let length = read_u32(0);
+        if (data.len() - 12) < length as usize {
+            self.done = true;
+            return Some(Err(format!("Truncated chunk at offset {}", self.offset)));
+        }
+
+        let end = 8 + length as usize;
+        let chunk = Chunk {
+            chunk_type: [data[4], data[5], data[6], data[7]],
+            length,
+            data: &data[8..end],
+            stored_crc: read_u32(end),
+            computed_crc: calc_crc(&data[4..end]) as u32,
+            offset: self.offset,
+        };
+
+        self.offset += end + 4;
+        self.done = &chunk.chunk_type == b"IEND";
+        Some(Ok(chunk))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColourType {
+    Grayscale,
+    TrueColour,
+    Indexed,
+    GrayscaleAlpha,
+    TrueColourAlpha,
+    Invalid,
+}
+
+impl ColourType {
+    pub fn from_byte(b: u8) -> ColourType {
+        match b {
+            0 => ColourType::Grayscale,
+            2 => ColourType::TrueColour,
+            3 => ColourType::Indexed,
+            4 => ColourType::GrayscaleAlpha,
+            6 => ColourType::TrueColourAlpha,
+            _ => ColourType::Invalid,
+        }
+    }
+
+    /* Number of samples stored per pixel in the image data */
+    pub fn channels(self) -> usize {
+        match self {
+            ColourType::Grayscale | ColourType::Indexed => 1,
+            ColourType::GrayscaleAlpha => 2,
+            ColourType::TrueColour => 3,
+            ColourType::TrueColourAlpha => 4,
+            ColourType::Invalid => 0,
+        }
+    }
+
+    pub fn allows_depth(self, depth: u8) -> bool {
+        match self {
+            ColourType::Grayscale => [1, 2, 4, 8, 16].contains(&depth),
+            ColourType::Indexed => [1, 2, 4, 8].contains(&depth),
+            ColourType::TrueColour | ColourType::GrayscaleAlpha | ColourType::TrueColourAlpha => {
+                depth == 8 || depth == 16
+            }
+            ColourType::Invalid => false,
+        }
+    }
+}
+
+/* The contents of IHDR */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u8,
+    pub colour_type: ColourType,
+    pub compression: u8,
+    pub filter: u8,
+    pub interlace: u8,
+}
+
+impl Header {
+    /* Reads the fields without checking them against the spec */
+    pub fn from_bytes(data: &[u8]) -> Result<Header, String> {
+        if data.len() != 13 {
+            return Err(format!("IHDR has length {} instead of 13", data.len()));
+        }
+
+        Ok(Header {
+            width: to_u32([data[0], data[1], data[2], data[3]]),
+            height: to_u32([data[4], data[5], data[6], data[7]]),
+            depth: data[8],
+            colour_type: ColourType::from_byte(data[9]),
+            compression: data[10],
+            filter: data[11],
+            interlace: data[12],
+        })
+    }
+
+    /* Size of the inflated image data, filter type bytes included, None
+     * when it does not fit in a usize */
+    pub fn image_data_len(&self) -> Option<usize> {
+        let bits_per_pixel = self.colour_type.channels() * self.depth as usize;
+        let size = |w: usize, h: usize| match w {
+            0 => Some(0),
+            _ => w
+                .checked_mul(bits_per_pixel)?
+                .div_ceil(8)
+                .checked_add(1)?
+                .checked_mul(h),
+        };
+        let (width, height) = (self.width as usize, self.height as usize);
+        if self.interlace == 0 {
+            return size(width, height);
+        }
+
+        (0..7).try_fold(0usize, |total, pass| {
+            let w = (width + COL_INCREMENT[pass] - 1 - STARTING_COL[pass]) / COL_INCREMENT[pass];
+            let h = (height + ROW_INCREMENT[pass] - 1 - STARTING_ROW[pass]) / ROW_INCREMENT[pass];
+            total.checked_add(size(w, h)?)
+        })
+    }
+}
+
+/* The layout an image decodes to when the caller does not ask for one */
+fn native_format(colour_type: ColourType, depth: u8, has_transparency: bool) -> PixelFormat {
+    let alpha = has_transparency
+        || colour_type == ColourType::GrayscaleAlpha
+        || colour_type == ColourType::TrueColourAlpha;
+    match (colour_type, depth == 16, alpha) {
+        (ColourType::Indexed, _, _) => PixelFormat::Rgba8,
+        (ColourType::Grayscale, false, false) => PixelFormat::L8,
+        (ColourType::Grayscale, true, false) => PixelFormat::L16,
+        (ColourType::Grayscale, false, true) | (ColourType::GrayscaleAlpha, false, _) => {
+            PixelFormat::La8
+        }
+        (ColourType::Grayscale, true, true) | (ColourType::GrayscaleAlpha, true, _) => {
+            PixelFormat::La16
+        }
+        (_, false, false) => PixelFormat::Rgb8,
+        (_, true, false) => PixelFormat::Rgb16,
+        (_, false, true) => PixelFormat::Rgba8,
+        (_, true, true) => PixelFormat::Rgba16,
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    /* Layout of the returned pixels, None keeps the layout of the source */
+    pub format: Option<PixelFormat>,
+    /* Keep 1, 2 and 4-bit palette indices packed in parse_indexed */
+    pub pack_indices: bool,
+    /* Applied in order by parse_transformed */
+    pub transforms: Vec<Transform>,
+    /* Flatten transparent pixels onto this colour, making the output opaque */
+    pub background: Option<Background>,
+    /* Reduce samples to the precision given by sBIT and scale them back up */
+    pub significant_bits: bool,
+    /* Exponent of the display, e.g. 2.2. Images with gAMA are corrected for it. */
+    pub display_gamma: Option<f32>,
+}
+
+/* Ancillary information collected while reading the chunks */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /* sBIT, in the order the channels are stored. Indexed images list the
+     * bits of the palette's red, green and blue. */
+    pub significant_bits: Option<Vec<u8>>,
+    /* gAMA times 100000 */
+    pub gamma: Option<u32>,
+    /* bKGD as stored in the file, for indexed images .0 is the palette index */
+    pub background: Option<(u16, u16, u16)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Background {
+    /* The colour from bKGD, or white if the file has none */
+    File,
+    /* 16-bit RGB in the same encoding as the image samples */
+    Colour(u16, u16, u16),
+}
+
+#[derive(Debug)]
+pub struct Parser {
+    width: u32,
+    height: u32,
+    depth: u8,
+    colour_type: ColourType,
+    compression: u8,
+    filter: u8,
+    interlace: u8,
+    plte: Vec<(u8, u8, u8, u8)>,
+    transparency: (u16, u16, u16),
+    has_transparency: bool,
+    metadata: Metadata,
+    // maps samples to gamma corrected samples of the same depth
+    gamma_table: Option<Vec<u16>>,
+    // set once samples or the palette have been corrected for the display
+    gamma_corrected: bool,
+    options: DecodeOptions,
+    // File data: PNG chunks
+    compressed_data: VecDeque<u8>,
+
+    has_end: bool,
+    // encoded zlib data
+    encoded_data: VecDeque<u8>,
+    // decoded zlib data
+    decoded_data: Vec<u8>,
+}
+
+/* What parse produces from the decoded samples */
+struct Output {
+    background: Option<[u16; 3]>,
+    format: PixelFormat,
+    /* Converts samples to linear light for blending */
+    exponent: Option<f32>,
+}
+
+/* Blends a 16-bit RGBA pixel onto an opaque background. With an exponent
+ * the samples are converted to linear light first. */
+fn composite(px: [u16; 4], bg: [u16; 3], exponent: Option<f32>) -> [u16; 4] {
+    match px[3] {
+        0xffff => return px,
+        0 => return [bg[0], bg[1], bg[2], 0xffff],
+        _ => {}
+    }
+
+    let alpha = px[3] as f32 / 65535.0;
+    let mut result = [0xffff; 4];
+    for c in 0..3 {
+        let (fg, bg) = (px[c] as f32 / 65535.0, bg[c] as f32 / 65535.0);
+        let v = match exponent {
+            Some(e) => (fg.powf(e) * alpha + bg.powf(e) * (1.0 - alpha)).powf(1.0 / e),
+            None => fg * alpha + bg * (1.0 - alpha),
+        };
+        result[c] = (v * 65535.0 + 0.5) as u16;
+    }
+
+    result
+}
+
+/* Shifts a `depth`-bit sample down to its `bits` significant bits and scales
+ * the result back up to the full range of `depth` */
+fn rescale_significant(v: u16, depth: usize, bits: usize) -> u16 {
+    let max = (1u32 << depth) - 1;
+    let significant_max = (1u32 << bits) - 1;
+    let v = (v >> (depth - bits)) as u32;
+
+    ((v * max + significant_max / 2) / significant_max) as u16
+}
+
+/* Maps every `depth`-bit sample to sample ^ (1 / combined), where combined
+ * is the product of the file gamma and the display exponent */
+fn make_gamma_table(depth: u8, combined: f32) -> Vec<u16> {
+    let max = ((1u32 << depth) - 1) as f32;
+    (0..=max as u32)
+        .map(|v| ((v as f32 / max).powf(1.0 / combined) * max + 0.5) as u16)
+        .collect()
+}
+
+/* Undoes the filter of one scanline given the unfiltered one above it,
+ * `bpp` is the distance in bytes to the corresponding byte on the left */
+fn unfilter_row(
+    filter: u8,
+    src: &[u8],
+    previous: Option<&[u8]>,
+    current: &mut [u8],
+    bpp: usize,
+) -> Result<(), String> {
+    let paeth_predictor = |a, b, c| -> u8 {
+        let a = a as i32;
+        let b = b as i32;
+        let c = c as i32;
+        let p = a + b - c;
+        let pa = (p - a).abs();
+        let pb = (p - b).abs();
+        let pc = (p - c).abs();
+        if pa <= pb && pa <= pc {
+            a as u8
+        } else if pb <= pc {
+            b as u8
+        } else {
+            c as u8
+        }
+    };
+
+    for x in 0..current.len() {
+        let a = if x >= bpp { current[x - bpp] } else { 0 };
+        let b = previous.map_or(0, |p| p[x]);
+        let c = if x >= bpp {
+            previous.map_or(0, |p| p[x - bpp])
+        } else {
+            0
+        };
+
+        current[x] = match filter {
+            0 => src[x],
+            1 => src[x].wrapping_add(a),
+            2 => src[x].wrapping_add(b),
+            3 => src[x].wrapping_add(((a as u16 + b as u16) / 2) as u8),
+            4 => src[x].wrapping_add(paeth_predictor(a, b, c)),
+            _ => return Err(format!("Corrupted data: {}", filter)),
+        };
+    }
+
+    Ok(())
+}
+
+/* Reads sample `index` of an unfiltered scanline */
+fn get_sample(row: &[u8], index: usize, depth: usize) -> u16 {
+    match depth {
+        16 => (row[index * 2] as u16) << 8 | row[index * 2 + 1] as u16,
+        8 => row[index] as u16,
+        _ => {
+            let bit = index * depth;
+            (row[bit / 8] >> (8 - depth - bit % 8)) as u16 & ((1 << depth) - 1)
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser::with_options(DecodeOptions::default())
+    }
+
+    pub fn with_options(options: DecodeOptions) -> Parser {
+        Parser {
+            width: 0,
+            height: 0,
+            depth: 0,
+            colour_type: ColourType::Invalid,
+            compression: 0,
+            filter: 0,
+            interlace: 0,
+            plte: Vec::new(),
+            transparency: (255, 255, 255),
+            has_transparency: false,
+            metadata: Metadata::default(),
+            gamma_table: None,
+            gamma_corrected: false,
+            options,
+            compressed_data: VecDeque::new(),
+            has_end: false,
+            encoded_data: VecDeque::new(),
+            decoded_data: Vec::new(),
+        }
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /* IHDR of the last parsed image */
+    pub fn header(&self) -> Header {
+        Header {
+            width: self.width,
+            height: self.height,
+            depth: self.depth,
+            colour_type: self.colour_type,
+            compression: self.compression,
+            filter: self.filter,
+            interlace: self.interlace,
+        }
+    }
+
+    /* The layout decoding produces when the caller did not ask for one */
+    pub fn native_format(&self) -> PixelFormat {
+        native_format(self.colour_type, self.depth, self.has_transparency)
+    }
+
+    pub fn parse(&mut self, data: Vec<u8>) -> Result<Image, String> {
+        self.read_chunks(data)?;
+        self.render()
+    }
+
+    /* Like parse, but calls `f` with every row as it is unfiltered instead of
+     * building the whole image. Interlaced images are rendered first. */
+    pub fn parse_rows(
+        &mut self,
+        data: Vec<u8>,
+        f: &mut dyn FnMut(u32, &Image),
+    ) -> Result<(), String> {
+        self.read_chunks(data)?;
+        if self.interlace == 1 {
+            let image = self.render()?;
+            for y in 0..image.height {
+                f(y, &crate::codec::row(&image, y));
+            }
+            return Ok(());
+        }
+
+        let output = self.output()?;
+        let width = self.width as usize;
+        let bits_per_pixel = self.colour_type.channels() * self.depth as usize;
+        let stride = (width * bits_per_pixel).div_ceil(8);
+        let bpp = std::cmp::max(1, bits_per_pixel / 8);
+        let mut row = Image::new(self.width, 1, output.format);
+        let (mut previous, mut current) = (vec![0; stride], vec![0; stride]);
+        for y in 0..self.height as usize {
+            let start = y * (stride + 1);
+            let src = &self.decoded_data[start + 1..start + 1 + stride];
+            let above = (y > 0).then_some(previous.as_slice());
+            unfilter_row(self.decoded_data[start], src, above, &mut current, bpp)?;
+            for x in 0..width {
+                let px = self.output_pixel(&current, x, &output)?;
+                row.set_rgba16(x, px);
+            }
+            f(y as u32, &row);
+            std::mem::swap(&mut previous, &mut current);
+        }
+        Ok(())
+    }
+
+    /* The background, pixel format and blending exponent of the output */
+    fn output(&self) -> Result<Output, String> {
+        let background = match self.options.background {
+            Some(Background::File) => Some(self.file_background()?),
+            Some(Background::Colour(r, g, b)) => Some([r, g, b]),
+            None => None,
+        };
+        let format = match (self.options.format, background) {
+            (Some(format), _) => format,
+            (None, Some(bg)) => self.opaque_format(bg),
+            (None, None) => self.native_format(),
+        };
+        /* Blending happens in linear light when we know how samples are encoded */
+        let exponent = match self.gamma_corrected {
+            true => self.options.display_gamma,
+            false => self.metadata.gamma.map(|g| 100000.0 / g as f32),
+        };
+        Ok(Output {
+            background,
+            format,
+            exponent,
+        })
+    }
+
+    /* Pixel `x` of an unfiltered row, composited when there is a background */
+    fn output_pixel(&self, row: &[u8], x: usize, output: &Output) -> Result<[u16; 4], String> {
+        let px = self.get_pixel(row, x)?;
+        Ok(match output.background {
+            Some(bg) => composite(px, bg, output.exponent),
+            None => px,
+        })
+    }
+
+    /* Builds the output image from the inflated data */
+    fn render(&self) -> Result<Image, String> {
+        let output = self.output()?;
+        let mut image = Image::new(self.width, self.height, output.format);
+        self.for_each_pixel(|index, row, x| {
+            let px = self.output_pixel(row, x, &output)?;
+            image.set_rgba16(index, px);
+            Ok(())
+        })?;
+
+        Ok(image)
+    }
+
+    /* The native format without alpha, as used when compositing onto `bg` */
+    fn opaque_format(&self, bg: [u16; 3]) -> PixelFormat {
+        let gray = bg[0] == bg[1] && bg[1] == bg[2];
+        match self.native_format() {
+            PixelFormat::L8 | PixelFormat::La8 if gray => PixelFormat::L8,
+            PixelFormat::L16 | PixelFormat::La16 if gray => PixelFormat::L16,
+            PixelFormat::L16 | PixelFormat::La16 | PixelFormat::Rgb16 | PixelFormat::Rgba16 => {
+                PixelFormat::Rgb16
+            }
+            _ => PixelFormat::Rgb8,
+        }
+    }
+
+    /* bKGD scaled to 16-bit RGB */
+    fn file_background(&self) -> Result<[u16; 3], String> {
+        let (r, g, b) = match self.metadata.background {
+            Some(bg) => bg,
+            None => return Ok([0xffff; 3]),
+        };
+        if self.colour_type == ColourType::Indexed {
+            return match self.plte.get(r as usize) {
+                Some(entry) => Ok([
+                    entry.0 as u16 * 257,
+                    entry.1 as u16 * 257,
+                    entry.2 as u16 * 257,
+                ]),
+                None => Err(format!("bKGD palette index {} out of range", r)),
+            };
+        }
+
+        let max = (1u32 << self.depth) - 1;
+        let scale = |v: u16| {
+            let v = match &self.gamma_table {
+                Some(table) => table[v as usize],
+                None => v,
+            };
+            (v as u32 * 0xffff / max) as u16
+        };
+        Ok([scale(r), scale(g), scale(b)])
+    }
+
+    /* Decodes an indexed image to its palette indices instead of colours */
+    pub fn parse_indexed(&mut self, data: Vec<u8>) -> Result<IndexedImage, String> {
+        self.read_chunks(data)?;
+
+        if self.colour_type != ColourType::Indexed {
+            return Err(format!("Not an indexed image: {:?}", self.colour_type));
+        }
+
+        let depth = if self.options.pack_indices {
+            self.depth as usize
+        } else {
+            8
+        };
+        let stride = (self.width as usize * depth).div_ceil(8);
+        let mut indices = vec![0; stride * self.height as usize];
+        let palette_len = self.plte.len();
+
+        self.for_each_pixel(|index, row, x| {
+            let value = get_sample(row, x, self.depth as usize);
+            if value as usize >= palette_len {
+                return Err(format!(
+                    "Palette index {} out of range ({} entries)",
+                    value, palette_len
+                ));
+            }
+
+            let (y, x) = (index / self.width as usize, index % self.width as usize);
+            let bit = x * depth;
+            indices[y * stride + bit / 8] |= (value as u8) << (8 - depth - bit % 8);
+            Ok(())
+        })?;
+
+        Ok(IndexedImage {
+            width: self.width,
+            height: self.height,
+            depth: depth as u8,
+            indices,
+            palette: self.plte.clone(),
+        })
+    }
+
+    /* Decodes to scanlines in the layout produced by the configured transforms.
+     * Before transforming, palette images are expanded to RGBA and tRNS
+     * becomes an alpha channel. */
+    pub fn parse_transformed(&mut self, data: Vec<u8>) -> Result<TransformedImage, String> {
+        self.read_chunks(data)?;
+
+        let mut writer = TransformWriter::new(
+            self.width,
+            self.height,
+            self.input_layout(),
+            &self.options.transforms,
+        );
+        let mut samples = Vec::with_capacity(4);
+        self.for_each_pixel(|index, row, x| {
+            self.get_samples(row, x, &mut samples)?;
+            writer.write(index, &samples);
+            Ok(())
+        })?;
+
+        Ok(writer.finish())
+    }
+
+    /* Layout of the samples returned by get_samples */
+    fn input_layout(&self) -> Layout {
+        let mut channels = match self.colour_type {
+            ColourType::Grayscale => vec![Channel::Gray],
+            ColourType::GrayscaleAlpha => vec![Channel::Gray, Channel::Alpha],
+            ColourType::TrueColour => vec![Channel::Red, Channel::Green, Channel::Blue],
+            _ => vec![Channel::Red, Channel::Green, Channel::Blue, Channel::Alpha],
+        };
+        let mut depth = self.depth;
+        if self.colour_type == ColourType::Indexed {
+            depth = 8;
+        }
+        if self.has_transparency {
+            channels.push(Channel::Alpha);
+            depth = depth.max(8);
+        }
+
+        Layout { depth, channels }
+    }
+
+    /* Reads the samples of pixel `x` of an unfiltered scanline */
+    fn get_samples(&self, row: &[u8], x: usize, out: &mut Vec<u16>) -> Result<(), String> {
+        let depth = self.depth as usize;
+        out.clear();
+
+        if self.colour_type == ColourType::Indexed {
+            let (r, g, b, a) = self.palette_entry(get_sample(row, x, depth))?;
+            out.extend([r as u16, g as u16, b as u16, a as u16].iter());
+            return Ok(());
+        }
+
+        let mut samples = [0; 4];
+        let transparent = self.read_samples(row, x, &mut samples);
+        out.extend_from_slice(&samples[..self.colour_type.channels()]);
+
+        if self.has_transparency {
+            /* Sub-byte gray is expanded so the alpha channel fits */
+            if depth < 8 {
+                out[0] = (out[0] as u32 * 255 / ((1 << depth) - 1)) as u16;
+            }
+            let max = if depth == 16 { 0xffff } else { 0xff };
+            out.push(if transparent { 0 } else { max });
+        }
+
+        Ok(())
+    }
+
+    /* Reads every chunk up to IEND and inflates the image data */
+    fn read_chunks(&mut self, data: Vec<u8>) -> Result<(), String> {
+        self.compressed_data = data.into_iter().collect();
+
+        self.parse_png_header()?;
+        while !self.has_end {
+            if let Err(e) = self.parse_chunk() {
+                return Err(format!("Error while parsing PNG: {}", e));
+            }
+        }
+        self.decoded_data = zlib::parse(&mut self.encoded_data)?;
+        /* Checked before the output is allocated from the header's size */
+        let expected = self.header().image_data_len().ok_or(format!(
+            "Image size {}x{} is too large",
+            self.width, self.height
+        ))?;
+        if self.decoded_data.len() < expected {
+            return Err(format!(
+                "Not enough image data: {} bytes, expected {}",
+                self.decoded_data.len(),
+                expected
+            ));
+        }
+
+        if self.colour_type == ColourType::Indexed && self.plte.is_empty() {
+            return Err("Missing PLTE chunk".to_string());
+        }
+
+        if self.colour_type == ColourType::Indexed && self.options.significant_bits {
+            if let Some(bits) = &self.metadata.significant_bits {
+                for entry in self.plte.iter_mut() {
+                    entry.0 = rescale_significant(entry.0 as u16, 8, bits[0] as usize) as u8;
+                    entry.1 = rescale_significant(entry.1 as u16, 8, bits[1] as usize) as u8;
+                    entry.2 = rescale_significant(entry.2 as u16, 8, bits[2] as usize) as u8;
+                }
+            }
+        }
+
+        if let (Some(file_gamma), Some(display_gamma)) =
+            (self.metadata.gamma, self.options.display_gamma)
+        {
+            /* Palettes are corrected once instead of for every pixel */
+            let depth = match self.colour_type {
+                ColourType::Indexed => 8,
+                _ => self.depth,
+            };
+            let table = make_gamma_table(depth, file_gamma as f32 / 100000.0 * display_gamma);
+            if self.colour_type == ColourType::Indexed {
+                for entry in self.plte.iter_mut() {
+                    entry.0 = table[entry.0 as usize] as u8;
+                    entry.1 = table[entry.1 as usize] as u8;
+                    entry.2 = table[entry.2 as usize] as u8;
+                }
+            } else {
+                self.gamma_table = Some(table);
+            }
+            self.gamma_corrected = true;
+        }
+
+        Ok(())
+    }
+
+    /* Unfilters the image data and calls `f` with the pixel index in the
+     * final image, the unfiltered scanline and the pixel's position in it */
+    fn for_each_pixel<F>(&self, mut f: F) -> Result<(), String>
+    where
+        F: FnMut(usize, &[u8], usize) -> Result<(), String>,
+    {
+        let passes = match self.interlace {
+            1 => 0..7,
+            _ => 7..8,
+        };
+
+        let mut offset = 0;
+        for pass in passes {
+            /* Pass 7 stands for a non-interlaced image */
+            let (start_row, start_col, row_inc, col_inc) = match pass {
+                7 => (0, 0, 1, 1),
+                _ => (
+                    STARTING_ROW[pass],
+                    STARTING_COL[pass],
+                    ROW_INCREMENT[pass],
+                    COL_INCREMENT[pass],
+                ),
+            };
+            let w = (self.width as usize + col_inc - 1 - start_col) / col_inc;
+            let h = (self.height as usize + row_inc - 1 - start_row) / row_inc;
+            if w == 0 || h == 0 {
+                continue;
+            }
+
+            let data = self.reverse_filter(w, h, offset)?;
+            let stride = data.len() / h;
+            offset += (stride + 1) * h;
+
+            for (y, row) in data.chunks(stride).enumerate() {
+                let image_row = start_row + y * row_inc;
+                for x in 0..w {
+                    let index = image_row * self.width as usize + start_col + x * col_inc;
+                    f(index, row, x)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /* Converts pixel `x` of an unfiltered scanline to 16-bit RGBA */
+    fn get_pixel(&self, row: &[u8], x: usize) -> Result<[u16; 4], String> {
+        if self.colour_type == ColourType::Indexed {
+            let entry = self.palette_entry(get_sample(row, x, self.depth as usize))?;
+            return Ok([
+                entry.0 as u16 * 257,
+                entry.1 as u16 * 257,
+                entry.2 as u16 * 257,
+                entry.3 as u16 * 257,
+            ]);
+        }
+
+        let mut samples = [0; 4];
+        let transparent = self.read_samples(row, x, &mut samples);
+        let scale = 0xffff / ((1u32 << self.depth) - 1) as u16;
+        let a = if transparent { 0 } else { 0xffff };
+
+        let px = match self.colour_type {
+            ColourType::Grayscale => {
+                let v = samples[0] * scale;
+                [v, v, v, a]
+            }
+            ColourType::TrueColour => [
+                samples[0] * scale,
+                samples[1] * scale,
+                samples[2] * scale,
+                a,
+            ],
+            ColourType::GrayscaleAlpha => {
+                let v = samples[0] * scale;
+                [v, v, v, samples[1] * scale]
+            }
+            _ => [
+                samples[0] * scale,
+                samples[1] * scale,
+                samples[2] * scale,
+                samples[3] * scale,
+            ],
+        };
+
+        Ok(px)
+    }
+
+    fn palette_entry(&self, index: u16) -> Result<(u8, u8, u8, u8), String> {
+        match self.plte.get(index as usize) {
+            Some(entry) => Ok(*entry),
+            None => Err(format!(
+                "Palette index {} out of range ({} entries)",
+                index,
+                self.plte.len()
+            )),
+        }
+    }
+
+    /* Reads the samples of pixel `x` of an unfiltered scanline of a
+     * non-indexed image, applying sBIT when requested. Returns whether the
+     * pixel matches the tRNS colour. */
+    fn read_samples(&self, row: &[u8], x: usize, samples: &mut [u16; 4]) -> bool {
+        let depth = self.depth as usize;
+        let channels = self.colour_type.channels();
+        for (c, sample) in samples.iter_mut().enumerate().take(channels) {
+            *sample = get_sample(row, x * channels + c, depth);
+        }
+
+        let transparent = self.has_transparency
+            && match self.colour_type {
+                ColourType::Grayscale => samples[0] == self.transparency.0,
+                _ => (samples[0], samples[1], samples[2]) == self.transparency,
+            };
+
+        if self.options.significant_bits {
+            if let Some(bits) = &self.metadata.significant_bits {
+                for c in 0..channels {
+                    samples[c] = rescale_significant(samples[c], depth, bits[c] as usize);
+                }
+            }
+        }
+
+        if let Some(table) = &self.gamma_table {
+            /* Alpha is linear and never corrected */
+            let colours = match self.colour_type {
+                ColourType::Grayscale | ColourType::GrayscaleAlpha => 1,
+                _ => 3,
+            };
+            for sample in samples.iter_mut().take(colours) {
+                *sample = table[*sample as usize];
+            }
+        }
+
+        transparent
+    }
+
+    /* Undoes the scanline filters of a (sub)image of `width`x`height` pixels
+     * starting at `offset` in the decoded data. Returns the unfiltered rows
+     * without their filter type byte. */
+    fn reverse_filter(
+        &self,
+        width: usize,
+        height: usize,
+        offset: usize,
+    ) -> Result<Vec<u8>, String> {
+        let bits_per_pixel = self.colour_type.channels() * self.depth as usize;
+        let stride = (width * bits_per_pixel).div_ceil(8);
+        let bpp = std::cmp::max(1, bits_per_pixel / 8);
+
+        if self.decoded_data.len() < offset + (stride + 1) * height {
+            return Err("Not enough image data".to_string());
+        }
+
+        let mut result = vec![0; stride * height];
+        for y in 0..height {
+            let row_index = offset + y * (stride + 1);
+            let filter = self.decoded_data[row_index];
+            let src = &self.decoded_data[(row_index + 1)..(row_index + 1 + stride)];
+            let (previous, current) = result.split_at_mut(y * stride);
+            let previous = match y {
+                0 => None,
+                _ => Some(&previous[(y - 1) * stride..]),
+            };
+            unfilter_row(filter, src, previous, &mut current[..stride], bpp)?;
+        }
+
+        Ok(result)
+    }
+
+    fn parse_png_header(&mut self) -> Result<(), String> {
+        for byte in PNG_SIGNATURE.iter() {
+            let b = self.parse_u8()?;
+            if b != *byte {
+                return Err("Not a PNG file".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_chunk_type(&mut self) -> Result<(ChunkType, u32), String> {
+        if self.compressed_data.len() < 8 {
+            return Err("Not enough data to determine chunk header".to_string());
+        }
+
+        let length = self.parse_u32()?;
+
+        let headers: [(u32, ChunkType); 15] = [
+            (to_u32([73, 72, 68, 82]), ChunkType::IHDR),
+            (to_u32([80, 76, 84, 69]), ChunkType::PLTE),
+            (to_u32([73, 68, 65, 84]), ChunkType::IDAT),
+            (to_u32([73, 69, 78, 68]), ChunkType::IEND),
+            (to_u32([116, 69, 88, 116]), ChunkType::TEXT),
+            (to_u32([112, 72, 89, 115]), ChunkType::PHYS),
+            (to_u32([122, 84, 88, 116]), ChunkType::ZTXT),
+            (to_u32([103, 65, 77, 65]), ChunkType::GAMA),
+            (to_u32([115, 66, 73, 84]), ChunkType::SBIT),
+            (to_u32([98, 75, 71, 68]), ChunkType::BKGD),
+            (to_u32([99, 72, 82, 77]), ChunkType::CHRM),
+            (to_u32([104, 73, 83, 84]), ChunkType::HIST),
+            (to_u32([116, 73, 77, 69]), ChunkType::TIME),
+            (to_u32([105, 84, 88, 116]), ChunkType::ITXT),
+            (to_u32([116, 82, 78, 83]), ChunkType::TRNS),
+        ];
+
+        if self.compressed_data.len() < (length as usize + 4) {
+            return Err("Not enough data".to_string());
+        }
+        let crc1 = calc_crc(self.compressed_data.iter().take(length as usize + 4));
+        let crc2 = self.peek_u32(length + 4)?;
+
+        if crc1 != crc2 as u64 {
+            return Err("Corrupted data".to_string());
+        }
+
+        let chunk_type = self.parse_u32()?;
+        for header in &headers {
+            if chunk_type == header.0 {
+                return Ok((header.1.clone(), length));
+            }
+        }
+
+        Ok((ChunkType::UNKNOWN, length))
+    }
+
+    fn parse_u32(&mut self) -> Result<u32, String> {
+        let mut result: u32 = 0;
+        if self.compressed_data.len() < 4 {
+            return Err("Not enough data".to_string());
+        }
+        for i in 0..4 {
+            let byte = self.compressed_data.pop_front().unwrap() as u32;
+            result |= byte << (8 * (3 - i));
+        }
+
+        Ok(result)
+    }
+
+    fn peek_u32(&self, offset: u32) -> Result<u32, String> {
+        let mut result: u32 = 0;
+        if self.compressed_data.len() < (offset as usize + 4) {
+            return Err("Not enough data".to_string());
+        }
+        for i in 0..4 {
+            let byte = self.compressed_data[offset as usize + i] as u32;
+            result |= byte << (8 * (3 - i));
+        }
+
+        Ok(result)
+    }
+
+    fn parse_u16(&mut self) -> Result<u32, String> {
+        let mut result: u32 = 0;
+        if self.compressed_data.len() < 2 {
+            return Err("Not enough data".to_string());
+        }
+        for i in 0..2 {
+            let byte = self.compressed_data.pop_front().unwrap() as u32;
+            result |= byte << (8 * (1 - i));
+        }
+
+        Ok(result)
+    }
+
+    fn parse_u8(&mut self) -> Result<u8, String> {
+        if self.compressed_data.is_empty() {
+            return Err("Not enough data".to_string());
+        }
+
+        Ok(self.compressed_data.pop_front().unwrap())
+    }
+
+    fn parse_ihdr(&mut self, _: u32) -> Result<(), String> {
+        self.width = self.parse_u32()?;
+        self.height = self.parse_u32()?;
+        self.depth = self.parse_u8()?;
+        self.colour_type = ColourType::from_byte(self.parse_u8()?);
+        if !self.colour_type.allows_depth(self.depth) {
+            return Err(format!(
+                "Invalid bit depth {} for {:?}",
+                self.depth, self.colour_type
+            ));
+        }
+        self.compression = self.parse_u8()?;
+        self.filter = self.parse_u8()?;
+        self.interlace = self.parse_u8()?;
+        match self.interlace {
+            0 | 1 => {}
+            _ => return Err("Invalid interlace method".to_string()),
+        };
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_idat(&mut self, length: u32) -> Result<(), String> {
+        if self.compressed_data.len() < length as usize {
+            return Err("Not enough data".to_string());
+        }
+        let new_data = self.compressed_data.split_off(length as usize);
+        self.encoded_data.append(&mut self.compressed_data);
+        self.compressed_data = new_data;
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_plte(&mut self, length: u32) -> Result<(), String> {
+        if !length.is_multiple_of(3) {
+            return Err("Corrupted data".to_string());
+        }
+
+        self.plte = Vec::with_capacity((length / 3) as usize);
+        for _ in 0..(length / 3) {
+            let r = self.parse_u8()?;
+            let g = self.parse_u8()?;
+            let b = self.parse_u8()?;
+            self.plte.push((r, g, b, 255));
+        }
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_ztxt(&mut self, length: u32) -> Result<(), String> {
+        let mut size = 0;
+        let mut keyword = Vec::new();
+        loop {
+            let c = self.parse_u8()? as char;
+            size += 1;
+            if c == '\0' {
+                break;
+            }
+            keyword.push(c);
+            if size > 79 {
+                return Err("Corrupted PNG zTXt header".to_string());
+            }
+        }
+        let _method = self.parse_u8()?;
+        for _ in 0..(length - size - 1) {
+            self.parse_u8()?;
+        }
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_phys(&mut self, _length: u32) -> Result<(), String> {
+        let _ppu_x = self.parse_u32()?;
+        let _ppu_y = self.parse_u32()?;
+        let _unit = self.parse_u8()?;
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_iend(&mut self, _length: u32) -> Result<(), String> {
+        self.has_end = true;
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_gama(&mut self, _length: u32) -> Result<(), String> {
+        let gamma = self.parse_u32()?;
+        if gamma > 0 {
+            self.metadata.gamma = Some(gamma);
+        }
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_sbit(&mut self, _length: u32) -> Result<(), String> {
+        let (channels, depth) = match self.colour_type {
+            ColourType::Indexed => (3, 8),
+            ColourType::Invalid => return Err("Got sBIT before colour type".to_string()),
+            _ => (self.colour_type.channels(), self.depth),
+        };
+
+        let mut bits = Vec::with_capacity(channels);
+        for _ in 0..channels {
+            let b = self.parse_u8()?;
+            if b == 0 || b > depth {
+                return Err(format!("Invalid sBIT value {} for depth {}", b, depth));
+            }
+            bits.push(b);
+        }
+        self.metadata.significant_bits = Some(bits);
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_bkgd(&mut self, _length: u32) -> Result<(), String> {
+        let background = match self.colour_type {
+            ColourType::Grayscale | ColourType::GrayscaleAlpha => {
+                let v = self.parse_u16()? as u16;
+                (v, v, v)
+            }
+            ColourType::TrueColour | ColourType::TrueColourAlpha => {
+                let r = self.parse_u16()? as u16;
+                let g = self.parse_u16()? as u16;
+                let b = self.parse_u16()? as u16;
+                (r, g, b)
+            }
+            ColourType::Indexed => (self.parse_u8()? as u16, 0, 0),
+            ColourType::Invalid => return Err("Got bKGD before colour type".to_string()),
+        };
+        /* Samples index the gamma table, so they have to fit the bit depth */
+        if self.colour_type == ColourType::Indexed {
+            if background.0 as usize >= self.plte.len() {
+                return Err(format!("bKGD palette index {} out of range", background.0));
+            }
+        } else {
+            let max = ((1u32 << self.depth) - 1) as u16;
+            if background.0 > max || background.1 > max || background.2 > max {
+                return Err(format!(
+                    "bKGD {:?} exceeds the bit depth {}",
+                    background, self.depth
+                ));
+            }
+        }
+        self.metadata.background = Some(background);
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_chrm(&mut self, _length: u32) -> Result<(), String> {
+        let _wpx = self.parse_u32()?;
+        let _wpy = self.parse_u32()?;
+        let _redx = self.parse_u32()?;
+        let _redy = self.parse_u32()?;
+        let _greenx = self.parse_u32()?;
+        let _greeny = self.parse_u32()?;
+        let _bluex = self.parse_u32()?;
+        let _bluey = self.parse_u32()?;
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_hist(&mut self, length: u32) -> Result<(), String> {
+        let mut hist = Vec::with_capacity((length / 2) as usize);
+        for _ in 0..(length / 2) {
+            hist.push(self.parse_u16()?);
+        }
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_time(&mut self, _length: u32) -> Result<(), String> {
+        let _year1 = self.parse_u8()?;
+        let _year2 = self.parse_u8()?;
+        let _month = self.parse_u8()?;
+        let _day = self.parse_u8()?;
+        let _hour = self.parse_u8()?;
+        let _min = self.parse_u8()?;
+        let _sec = self.parse_u8()?;
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_trns(&mut self, length: u32) -> Result<(), String> {
+        match self.colour_type {
+            ColourType::Grayscale => {
+                let rgb = (self.parse_u8()? as u16) << 8 | self.parse_u8()? as u16;
+                self.transparency = (rgb, rgb, rgb);
+                self.has_transparency = true;
+            }
+            ColourType::TrueColour => {
+                let r = (self.parse_u8()? as u16) << 8 | self.parse_u8()? as u16;
+                let g = (self.parse_u8()? as u16) << 8 | self.parse_u8()? as u16;
+                let b = (self.parse_u8()? as u16) << 8 | self.parse_u8()? as u16;
+                self.transparency = (r, g, b);
+                self.has_transparency = true;
+            }
+            ColourType::Indexed => {
+                if self.plte.is_empty() {
+                    return Err("Expected PLTE before TRNS chunk".to_string());
+                }
+                if length as usize > self.plte.len() {
+                    return Err(format!(
+                        "TRNS has {} entries but the palette only {}",
+                        length,
+                        self.plte.len()
+                    ));
+                }
+                for i in 0..length as usize {
+                    let a = self.parse_u8()?;
+                    self.plte[i].3 = a;
+                }
+            }
+            ColourType::Invalid => {
+                return Err("Expected IHDR before TRNS chunk".to_string());
+            }
+            _ => {
+                return Err(format!("Not valid for ColourType: {:?}", self.colour_type));
+            }
+        }
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_str(&mut self) -> Result<(String, usize), String> {
+        let mut size = 0;
+        let mut result = Vec::new();
+        loop {
+            let c = self.parse_u8()? as char;
+            size += 1;
+            if c == '\0' {
+                break;
+            }
+            result.push(c);
+        }
+
+        Ok((result.into_iter().collect(), size))
+    }
+
+    fn parse_itxt(&mut self, length: u32) -> Result<(), String> {
+        let mut total_size = 0;
+        let (_keyword, size) = self.parse_str()?;
+        total_size += size;
+        let _compr_flag = self.parse_u8()?;
+        let _compr_method = self.parse_u8()?;
+        let (_lang, size) = self.parse_str()?;
+        total_size += size;
+        let (_translated_keyword, size) = self.parse_str()?;
+        total_size += size;
+        total_size += 2; // compression bytes
+
+        let bytes_left = length as i32 - total_size as i32;
+        if bytes_left < 0 {
+            return Err("Expected a length > 0 for text string".to_string());
+        }
+        let mut text_str = Vec::with_capacity(bytes_left as usize);
+        for _ in 0..bytes_left {
+            let c = self.parse_u8()? as char;
+            text_str.push(c);
+        }
+
+        let _text_str: String = text_str.into_iter().collect();
+
+        //println!("{}, {}, {}", _keyword, _lang, _text_str);
+
+        let _crc = self.parse_u32()?;
+        Ok(())
+    }
+
+    fn parse_text(&mut self, length: u32) -> Result<(), String> {
+        let (_keyword, size) = self.parse_str()?;
+        let bytes_left = length as usize - size;
+        if bytes_left == 0 {
+            return Err("Expected a length > 0 for text string".to_string());
+        }
+        let mut text_str = Vec::new();
+        for _ in 0..bytes_left {
+            let c = self.parse_u8()? as char;
+            text_str.push(c);
+        }
+
+        let _text_str: String = text_str.into_iter().collect();
+        //println!("{}: {}", keyword, text_str);
+        let _crc = self.parse_u32()?;
+        //println!("crc: {}", crc);
+
+        Ok(())
+    }
+
+    fn parse_chunk(&mut self) -> Result<(), String> {
+        let chunk_type = self.get_chunk_type();
+        if chunk_type.is_err() {
+            return Err("Failed to read a valid PNG chunk header".to_string());
+        }
+
+        let (chunk_type, length) = chunk_type.unwrap();
+
+        match chunk_type {
+            ChunkType::IHDR => self.parse_ihdr(length),
+            ChunkType::IDAT => self.parse_idat(length),
+            ChunkType::PLTE => self.parse_plte(length),
+            ChunkType::IEND => self.parse_iend(length),
+            ChunkType::TEXT => self.parse_text(length),
+            ChunkType::PHYS => self.parse_phys(length),
+            ChunkType::ZTXT => self.parse_ztxt(length),
+            ChunkType::GAMA => self.parse_gama(length),
+            ChunkType::SBIT => self.parse_sbit(length),
+            ChunkType::BKGD => self.parse_bkgd(length),
+            ChunkType::CHRM => self.parse_chrm(length),
+            ChunkType::HIST => self.parse_hist(length),
+            ChunkType::TIME => self.parse_time(length),
+            ChunkType::ITXT => self.parse_itxt(length),
+            ChunkType::TRNS => self.parse_trns(length),
+
+            ChunkType::UNKNOWN => {
+                if self.compressed_data.len() < length as usize {
+                    return Err("Not enough data".to_string());
+                }
+                let new_data = self.compressed_data.split_off(length as usize);
+                self.compressed_data = new_data;
+
+                let _crc = self.parse_u32()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/* Reads exactly `len` bytes, growing the buffer as they arrive since the
+ * length comes from the file */
+fn read_bytes<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    reader
+        .take(len as u64)
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Reading PNG: {}", e))?;
+    if data.len() < len {
+        return Err("Reading PNG: unexpected end of file".to_string());
+    }
+    Ok(data)
+}
+
+/* Keyword and text of a tEXt chunk, or of an uncompressed iTXt chunk */
+fn read_text(chunk_type: &[u8], data: &[u8]) -> Option<(String, String)> {
+    let end = data.iter().position(|b| *b == 0)?;
+    let keyword: String = data[..end].iter().map(|b| *b as char).collect();
+    let rest = &data[end + 1..];
+    if chunk_type == b"tEXt" {
+        return Some((keyword, rest.iter().map(|b| *b as char).collect()));
+    }
+
+    /* Compression flag and method, then language and translated keyword */
+    if rest.len() < 2 || rest[0] != 0 {
+        return None;
+    }
+    let mut fields = rest[2..].splitn(3, |b| *b == 0);
+    let text = fields.nth(2)?;
+    Some((keyword, String::from_utf8_lossy(text).into_owned()))
+}
+
+/* Reads the signature and IHDR, 33 bytes in total. With `ancillary` it
+ * continues up to the first IDAT and collects the palette, gamma and text
+ * chunks, without it the pixel format cannot account for tRNS. */
+pub fn probe<R: Read>(mut reader: R, ancillary: bool) -> Result<ImageInfo, String> {
+    if read_bytes(&mut reader, 8)? != PNG_SIGNATURE {
+        return Err("Not a PNG file".to_string());
+    }
+
+    let mut header: Option<Header> = None;
+    let mut palette: Option<Vec<(u8, u8, u8, u8)>> = None;
+    let mut has_transparency = false;
+    let (mut gamma, mut text) = (None, Vec::new());
+    loop {
+        let head = read_bytes(&mut reader, 8)?;
+        let length = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize;
+        let chunk_type = &head[4..8];
+        if length > i32::MAX as usize {
+            return Err(format!("Chunk length {} is too large", length));
+        }
+        if header.is_none() && chunk_type != b"IHDR" {
+            return Err("First chunk is not IHDR".to_string());
+        }
+        if chunk_type == b"IDAT" || chunk_type == b"IEND" {
+            break;
+        }
+
+        let wanted = [b"IHDR", b"PLTE", b"tRNS", b"gAMA", b"tEXt", b"iTXt"];
+        if !wanted.iter().any(|t| t == &chunk_type) {
+            io::copy(&mut (&mut reader).take(length as u64 + 4), &mut io::sink())
+                .map_err(|e| format!("Reading PNG: {}", e))?;
+            continue;
+        }
+        let data = read_bytes(&mut reader, length + 4)?;
+        let data = &data[..length];
+        match chunk_type {
+            b"IHDR" => header = Some(Header::from_bytes(data)?),
+            b"PLTE" => {
+                if length == 0 || !length.is_multiple_of(3) || length > 768 {
+                    return Err(format!("Invalid PLTE length {}", length));
+                }
+                palette = Some(data.chunks(3).map(|c| (c[0], c[1], c[2], 255)).collect());
+            }
+            b"tRNS" => {
+                has_transparency = true;
+                for (entry, alpha) in palette.iter_mut().flatten().zip(data) {
+                    entry.3 = *alpha;
+                }
+            }
+            b"gAMA" if length == 4 => {
+                gamma = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+            }
+            _ => text.extend(read_text(chunk_type, data)),
+        }
+        if !ancillary {
+            break;
+        }
+    }
+
+    let header = header.ok_or("Missing IHDR chunk")?;
+    let mut info = ImageInfo::new(
+        header.width,
+        header.height,
+        native_format(header.colour_type, header.depth, has_transparency),
+    );
+    info.bit_depth = header.depth;
+    info.palette = palette.filter(|_| header.colour_type == ColourType::Indexed);
+    info.gamma = gamma;
+    info.text = text;
+    Ok(info)
+}
+
+fn write_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+    let crc = calc_crc(&out[start..]) as u32;
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/* Picks the filter per row whose output has the smallest sum of absolute
+ * values, taking the bytes as signed */
+fn filter_rows(data: &[u8], stride: usize, bpp: usize) -> Vec<u8> {
+    let paeth_predictor = |a: u8, b: u8, c: u8| -> u8 {
+        let p = a as i32 + b as i32 - c as i32;
+        let pa = (p - a as i32).abs();
+        let pb = (p - b as i32).abs();
+        let pc = (p - c as i32).abs();
+        if pa <= pb && pa <= pc {
+            a
+        } else if pb <= pc {
+            b
+        } else {
+            c
+        }
+    };
+
+    let zeros = vec![0; stride];
+    let mut out = Vec::with_capacity((stride + 1) * data.len() / stride.max(1));
+    let mut candidates = vec![vec![0; stride]; 5];
+    for (y, row) in data.chunks(stride).enumerate() {
+        let previous = match y {
+            0 => &zeros[..],
+            _ => &data[(y - 1) * stride..y * stride],
+        };
+        for x in 0..stride {
+            let a = if x >= bpp { row[x - bpp] } else { 0 };
+            let b = previous[x];
+            let c = if x >= bpp { previous[x - bpp] } else { 0 };
+            candidates[0][x] = row[x];
+            candidates[1][x] = row[x].wrapping_sub(a);
+            candidates[2][x] = row[x].wrapping_sub(b);
+            candidates[3][x] = row[x].wrapping_sub(((a as u16 + b as u16) / 2) as u8);
+            candidates[4][x] = row[x].wrapping_sub(paeth_predictor(a, b, c));
+        }
+
+        let cost = |r: &Vec<u8>| {
+            r.iter()
+                .map(|v| (*v as i8).unsigned_abs() as u32)
+                .sum::<u32>()
+        };
+        let (filter, best) = candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, r)| cost(r))
+            .unwrap();
+        out.push(filter as u8);
+        out.extend_from_slice(best);
+    }
+    out
+}
+
+/* Writes a non-interlaced PNG at 8 or 16 bits per sample, gray or truecolour
+ * with or without alpha depending on the image. Float images are stored at
+ * 16 bits and premultiplied ones are converted back to straight alpha. */
+pub fn encode(img: &Image) -> Result<Vec<u8>, String> {
+    if img.width == 0
+        || img.height == 0
+        || img.width > i32::MAX as u32
+        || img.height > i32::MAX as u32
+    {
+        return Err(format!(
+            "Image size {}x{} cannot be stored as PNG",
+            img.width, img.height
+        ));
+    }
+    let (format, colour_type) = match (img.format.is_gray(), img.format.has_alpha()) {
+        (true, false) => (PixelFormat::L8, 0),
+        (true, true) => (PixelFormat::La8, 4),
+        (false, false) => (PixelFormat::Rgb8, 2),
+        (false, true) => (PixelFormat::Rgba8, 6),
+    };
+    let depth = match img.format.bytes_per_sample() {
+        1 => 8,
+        _ => 16,
+    };
+    let format = match (depth, format) {
+        (8, format) => format,
+        (_, PixelFormat::L8) => PixelFormat::L16,
+        (_, PixelFormat::La8) => PixelFormat::La16,
+        (_, PixelFormat::Rgb8) => PixelFormat::Rgb16,
+        _ => PixelFormat::Rgba16,
+    };
+    let img = img.convert(format);
+    let samples = match &img.data {
+        PixelData::U8(data) => data.clone(),
+        PixelData::U16(data) => data.iter().flat_map(|v| v.to_be_bytes()).collect(),
+        PixelData::F32(_) => unreachable!(),
+    };
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&img.width.to_be_bytes());
+    ihdr.extend_from_slice(&img.height.to_be_bytes());
+    ihdr.extend_from_slice(&[depth, colour_type, 0, 0, 0]);
+
+    let bpp = format.channels() * depth as usize / 8;
+    let filtered = filter_rows(&samples, img.width as usize * bpp, bpp);
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut out, b"IHDR", &ihdr);
+    write_chunk(&mut out, b"IDAT", &zlib::compress(&filtered));
+    write_chunk(&mut out, b"IEND", &[]);
+    Ok(out)
+}
+
+/* ImageDecoder for PNG files, decoding with the given options */
+pub struct PngDecoder {
+    data: Vec<u8>,
+    parser: Parser,
+}
+
+impl PngDecoder {
+    pub fn new(data: Vec<u8>) -> PngDecoder {
+        PngDecoder::with_options(data, DecodeOptions::default())
+    }
+
+    pub fn with_options(data: Vec<u8>, options: DecodeOptions) -> PngDecoder {
+        PngDecoder {
+            data,
+            parser: Parser::with_options(options),
+        }
+    }
+}
+
+impl ImageDecoder for PngDecoder {
+    /* The pixel format does not account for compositing onto a background */
+    fn info(&mut self) -> Result<ImageInfo, String> {
+        let mut info = probe(self.data.as_slice(), true)?;
+        if let Some(format) = self.parser.options.format {
+            info.pixel_format = format;
+        }
+        Ok(info)
+    }
+
+    fn decode(&mut self) -> Result<Image, String> {
+        self.parser = Parser::with_options(self.parser.options.clone());
+        self.parser.parse(self.data.clone())
+    }
+
+    /* Non-interlaced images are unfiltered a row at a time */
+    fn decode_rows(&mut self, f: &mut dyn FnMut(u32, &Image)) -> Result<(), String> {
+        self.parser = Parser::with_options(self.parser.options.clone());
+        self.parser.parse_rows(self.data.clone(), f)
+    }
+
+    fn metadata(&self) -> Vec<(String, String)> {
+        let header = self.parser.header();
+        let metadata = self.parser.metadata();
+        let mut entries = vec![
+            ("bit depth".to_string(), header.depth.to_string()),
+            (
+                "colour type".to_string(),
+                format!("{:?}", header.colour_type),
+            ),
+            (
+                "interlaced".to_string(),
+                (header.interlace == 1).to_string(),
+            ),
+        ];
+        if let Some(gamma) = metadata.gamma {
+            entries.push(("gamma".to_string(), (gamma as f32 / 100000.0).to_string()));
+        }
+        if let Some(bits) = &metadata.significant_bits {
+            entries.push(("significant bits".to_string(), format!("{:?}", bits)));
+        }
+        if let Some(bg) = metadata.background {
+            entries.push(("background".to_string(), format!("{:?}", bg)));
+        }
+        entries
+    }
+}
+
+/* Writes 8 or 16-bit PNG, see encode() */
+#[derive(Default)]
+pub struct PngEncoder;
+
+impl ImageEncoder for PngEncoder {
+    fn encode(&self, img: &Image) -> Result<Vec<u8>, String> {
+        encode(img)
+    }
+}
+
+#[test]
+fn test_output_formats() {
+    let data = std::fs::read("tests/png_testsuite/basn2c16.png").unwrap();
+    let native = Parser::new().parse(data.clone()).unwrap();
+    assert_eq!(native.format, PixelFormat::Rgb16);
+
+    for format in [PixelFormat::L8, PixelFormat::Bgra8, PixelFormat::Rgba32F].iter() {
+        let mut parser = Parser::with_options(DecodeOptions {
+            format: Some(*format),
+            ..Default::default()
+        });
+        let img = parser.parse(data.clone()).unwrap();
+        assert_eq!(img.format, *format);
+        assert_eq!(img, native.convert(*format));
+    }
+}
+
+#[test]
+fn test_image_data_limits() {
+    let png = |size: u32, depth: u8| {
+        let mut data = vec![137, 80, 78, 71, 13, 10, 26, 10];
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&size.to_be_bytes());
+        ihdr.extend_from_slice(&size.to_be_bytes());
+        ihdr.extend_from_slice(&[depth, 6, 0, 0, 0]);
+        write_chunk(&mut data, b"IHDR", &ihdr);
+        write_chunk(&mut data, b"IDAT", &zlib::compress(&[0; 16]));
+        write_chunk(&mut data, b"IEND", &[]);
+        data
+    };
+
+    /* A huge header with a few bytes of data fails before allocating */
+    let error = Parser::new().parse(png(60000, 8)).unwrap_err();
+    assert!(error.contains("Not enough image data"), "{}", error);
+    /* And one whose data length does not even fit in a usize */
+    let error = Parser::new().parse(png(i32::MAX as u32, 16)).unwrap_err();
+    assert!(error.contains("too large"), "{}", error);
+    assert!(crate::decode(&png(i32::MAX as u32, 16)).is_err());
+}
+
+#[test]
+fn test_indexed() {
+    let data = std::fs::read("tests/png_testsuite/basi3p02.png").unwrap();
+    let rgba = Parser::new().parse(data.clone()).unwrap();
+    let indexed = Parser::new().parse_indexed(data.clone()).unwrap();
+    assert_eq!(indexed.depth, 8);
+
+    for (i, index) in indexed.indices.iter().enumerate() {
+        let (r, g, b, a) = indexed.palette[*index as usize];
+        let expected = [
+            r as u16 * 257,
+            g as u16 * 257,
+            b as u16 * 257,
+            a as u16 * 257,
+        ];
+        assert_eq!(rgba.get_rgba16(i), expected);
+    }
+
+    let mut parser = Parser::with_options(DecodeOptions {
+        pack_indices: true,
+        ..Default::default()
+    });
+    let packed = parser.parse_indexed(data).unwrap();
+    assert_eq!(packed.depth, 2);
+    assert_eq!(packed.indices.len(), 32 * 32 / 4);
+    for (i, index) in indexed.indices.iter().enumerate() {
+        let value = packed.indices[i / 4] >> (6 - (i % 4) * 2) & 0b11;
+        assert_eq!(value, *index);
+    }
+}
+
+#[test]
+fn test_transforms() {
+    let data = std::fs::read("tests/png_testsuite/basn6a16.png").unwrap();
+    let rgba = Parser::new().parse(data.clone()).unwrap();
+
+    let mut parser = Parser::with_options(DecodeOptions {
+        transforms: vec![Transform::Strip16, Transform::SwapAlpha, Transform::Bgr],
+        ..Default::default()
+    });
+    let img = parser.parse_transformed(data).unwrap();
+    assert_eq!(img.layout.depth, 8);
+    assert_eq!(img.stride, 32 * 4);
+
+    for (i, px) in img.data.chunks(4).enumerate() {
+        let [r, g, b, a] = rgba.get_rgba16(i);
+        let expected = [
+            (a >> 8) as u8,
+            (b >> 8) as u8,
+            (g >> 8) as u8,
+            (r >> 8) as u8,
+        ];
+        assert_eq!(px, expected);
+    }
+}
+
+#[test]
+fn test_background() {
+    let opaque = |name, background| {
+        let data = std::fs::read(name).unwrap();
+        let mut parser = Parser::with_options(DecodeOptions {
+            background: Some(background),
+            ..Default::default()
+        });
+        parser.parse(data).unwrap()
+    };
+
+    /* Fully transparent pixels take the bKGD colour, which is red */
+    let name = "tests/png_testsuite/tbrn2c08.png";
+    let rgba = Parser::new().parse(std::fs::read(name).unwrap()).unwrap();
+    let img = opaque(name, Background::File);
+    assert_eq!(img.format, PixelFormat::Rgb8);
+    for i in 0..img.num_pixels() {
+        match rgba.get_rgba16(i)[3] {
+            0 => assert_eq!(img.get_rgba16(i), [0xffff, 0, 0, 0xffff]),
+            _ => assert_eq!(img.get_rgba16(i), rgba.get_rgba16(i)),
+        }
+    }
+
+    let img = opaque(
+        "tests/png_testsuite/basn4a08.png",
+        Background::Colour(0, 0, 0),
+    );
+    assert_eq!(img.format, PixelFormat::L8);
+    let img = opaque(
+        "tests/png_testsuite/basn4a08.png",
+        Background::Colour(0xffff, 0, 0),
+    );
+    assert_eq!(img.format, PixelFormat::Rgb8);
+
+    /* An 8-bit bKGD sample of 0x1234 would index past the gamma table */
+    let mut data = std::fs::read("tests/png_testsuite/basn4a08.png").unwrap();
+    let idat = data.windows(4).position(|w| w == b"IDAT").unwrap() - 4;
+    let mut bkgd = Vec::new();
+    write_chunk(&mut bkgd, b"bKGD", &[0x12, 0x34]);
+    data.splice(idat..idat, bkgd);
+    let mut parser = Parser::with_options(DecodeOptions {
+        background: Some(Background::File),
+        display_gamma: Some(2.2),
+        ..Default::default()
+    });
+    let error = parser.parse(data).unwrap_err();
+    assert!(error.contains("exceeds the bit depth"), "{}", error);
+}
+
+#[test]
+fn test_significant_bits() {
+    let data = std::fs::read("tests/png_testsuite/cs3n2c16.png").unwrap();
+    let raw = Parser::new().parse(data.clone()).unwrap();
+    let mut parser = Parser::with_options(DecodeOptions {
+        significant_bits: true,
+        ..Default::default()
+    });
+    let img = parser.parse(data).unwrap();
+    assert_eq!(parser.metadata().significant_bits, Some(vec![13, 13, 13]));
+
+    let (PixelData::U16(raw), PixelData::U16(rescaled)) = (raw.data, img.data) else {
+        panic!("Expected 16-bit samples");
+    };
+    for (v, r) in raw.iter().zip(rescaled.iter()) {
+        assert_eq!(*r, (((*v >> 3) as u32 * 0xffff + 0xfff) / 0x1fff) as u16);
+    }
+    assert_eq!(rescale_significant(0b100, 3, 2), 0b101);
+    assert_eq!(rescale_significant(0xe0, 8, 3), 0xff);
+}
+
+#[test]
+fn test_gamma() {
+    let decode = |name, display_gamma| {
+        let data = std::fs::read(name).unwrap();
+        let mut parser = Parser::with_options(DecodeOptions {
+            format: Some(PixelFormat::Rgba8),
+            display_gamma,
+            ..Default::default()
+        });
+        parser.parse(data).unwrap()
+    };
+
+    /* gAMA 2.5 and a display exponent of 1 / 2.5 cancel out */
+    let plain = decode("tests/png_testsuite/g25n2c08.png", None);
+    let corrected = decode("tests/png_testsuite/g25n2c08.png", Some(1.0 / 2.5));
+    assert_eq!(plain, corrected);
+
+    for name in [
+        "tests/png_testsuite/g10n2c08.png",
+        "tests/png_testsuite/g10n3p04.png",
+    ]
+    .iter()
+    {
+        let plain = decode(name, None);
+        let corrected = decode(name, Some(2.2));
+        for i in 0..plain.num_pixels() {
+            let [r, _, _, a] = plain.get_rgba16(i);
+            let expected = ((r as f32 / 65535.0).powf(1.0 / 2.2) * 255.0 + 0.5) as u16 * 257;
+            assert_eq!(corrected.get_rgba16(i)[0], expected);
+            assert_eq!(corrected.get_rgba16(i)[3], a);
+        }
+    }
+
+    /* Corrected palettes are blended with the display exponent too */
+    let mut data = std::fs::read("tests/png_testsuite/g10n3p04.png").unwrap();
+    let idat = data.windows(4).position(|w| w == b"IDAT").unwrap() - 4;
+    let mut trns = Vec::new();
+    write_chunk(&mut trns, b"tRNS", &[0x80; 10]);
+    data.splice(idat..idat, trns);
+    let parse = |background, display_gamma| {
+        let mut parser = Parser::with_options(DecodeOptions {
+            format: Some(PixelFormat::Rgba8),
+            background,
+            display_gamma,
+            ..Default::default()
+        });
+        parser.parse(data.clone()).unwrap()
+    };
+    let white = Background::Colour(0xffff, 0xffff, 0xffff);
+    let translucent = parse(None, Some(2.2));
+    let opaque = parse(Some(white), Some(2.2));
+    for i in 0..opaque.num_pixels() {
+        let [r, _, _, _] = composite(translucent.get_rgba16(i), [0xffff; 3], Some(2.2));
+        assert_eq!(opaque.get_rgba16(i)[0], (r >> 8) * 257);
+    }
+}
+
+#[test]
+fn test_chunks() {
+    let mut data = std::fs::read("tests/png_testsuite/basn0g01.png").unwrap();
+    let names: Vec<String> = chunks(&data).unwrap().map(|c| c.unwrap().name()).collect();
+    assert_eq!(names, vec!["IHDR", "gAMA", "IDAT", "IEND"]);
+
+    /* Corrupt the CRC of gAMA and add data after IEND */
+    data[8 + 25 + 12] ^= 0xff;
+    data.extend_from_slice(b"garbage");
+    let mut iter = chunks(&data).unwrap();
+    let ihdr = iter.next().unwrap().unwrap();
+    assert_eq!((ihdr.offset, ihdr.length), (8, 13));
+    assert!(ihdr.crc_matches());
+    assert!(!iter.next().unwrap().unwrap().crc_matches());
+    assert_eq!(iter.by_ref().count(), 2);
+    assert_eq!(iter.remainder(), b"garbage");
+}
+
+#[test]
+fn test_probe() {
+    /* Counts how many bytes were taken from the file */
+    struct Counter<'a>(&'a [u8], usize);
+    impl Read for Counter<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.read(buf)?;
+            self.1 += n;
+            Ok(n)
+        }
+    }
+
+    let data = std::fs::read("tests/png_testsuite/tbrn2c08.png").unwrap();
+    let mut counter = Counter(&data, 0);
+    let info = probe(&mut counter, false).unwrap();
+    assert_eq!(counter.1, 33);
+    assert_eq!((info.width, info.height, info.bit_depth), (32, 32, 8));
+    /* tRNS comes later */
+    assert_eq!(info.pixel_format, PixelFormat::Rgb8);
+    let info = probe(data.as_slice(), true).unwrap();
+    assert_eq!(info.pixel_format, PixelFormat::Rgba8);
+    assert_eq!(info.gamma, Some(100000));
+
+    let data = std::fs::read("tests/png_testsuite/tbbn3p08.png").unwrap();
+    let info = probe(data.as_slice(), true).unwrap();
+    assert_eq!(info.palette.unwrap()[0], (255, 255, 255, 0));
+
+    let data = std::fs::read("tests/png_testsuite/ct1n0g04.png").unwrap();
+    let info = probe(data.as_slice(), true).unwrap();
+    assert_eq!(info.text[0], ("Title".to_string(), "PngSuite".to_string()));
+
+    /* Lengths from the file are checked before they are trusted */
+    let mut data = std::fs::read("tests/png_testsuite/tbbn3p08.png").unwrap();
+    let plte = data.windows(4).position(|w| w == b"PLTE").unwrap();
+    data[plte - 1] -= 1;
+    assert!(probe(data.as_slice(), true).is_err());
+    let mut data = data[..33].to_vec();
+    data.extend_from_slice(b"\x7f\xff\xff\xf0tEXtTitle\0");
+    assert!(probe(data.as_slice(), true).is_err());
+}
+
+#[test]
+fn test_encode() {
+    for name in [
+        "basn0g08", "basn0g16", "basn2c08", "basn3p04", "basn4a16", "basn6a08",
+    ] {
+        let data = std::fs::read(format!("tests/png_testsuite/{}.png", name)).unwrap();
+        let img = Parser::new().parse(data).unwrap();
+        let encoded = encode(&img).unwrap();
+        assert!(chunks(&encoded).unwrap().all(|c| c.unwrap().crc_matches()));
+        assert_eq!(Parser::new().parse(encoded).unwrap(), img, "{}", name);
+    }
+
+    /* Float images are stored at 16 bits */
+    let img = Image::new(3, 2, PixelFormat::Rgba32F);
+    let decoded = Parser::new().parse(encode(&img).unwrap()).unwrap();
+    assert_eq!(decoded.format, PixelFormat::Rgba16);
+}
@@ -0,0 +1,478 @@
+use std::io::{self, BufWriter, Read, Write};
+
+use crate::codec::{ImageDecoder, ImageEncoder, ImageInfo};
+use crate::image::{Image, PixelData, PixelFormat};
+
+/* The layout an image is written in, Netpbm only knows unsigned straight
+ * alpha samples in the order gray or red, green, blue, followed by alpha */
+fn stored_format(format: PixelFormat) -> PixelFormat {
+    match format {
+        PixelFormat::Bgra8 | PixelFormat::Rgba8Premultiplied => PixelFormat::Rgba8,
+        PixelFormat::Rgba32F => PixelFormat::Rgba16,
+        _ => format,
+    }
+}
+
+fn write_samples<W: Write>(out: &mut W, data: &PixelData) -> io::Result<()> {
+    match data {
+        PixelData::U8(data) => out.write_all(data),
+        PixelData::U16(data) => {
+            for v in data.iter() {
+                out.write_all(&v.to_be_bytes())?;
+            }
+            Ok(())
+        }
+        PixelData::F32(_) => unreachable!(),
+    }
+}
+
+/* Writes a binary Netpbm file: P5 for gray, P6 for RGB and a P7 PAM with
+ * TUPLTYPE GRAYSCALE_ALPHA or RGB_ALPHA when the image has alpha. 16-bit
+ * formats use a maxval of 65535. */
+pub fn write_pnm<W: Write>(out: W, img: &Image) -> io::Result<()> {
+    let format = stored_format(img.format);
+    let converted;
+    let img = if format == img.format {
+        img
+    } else {
+        converted = img.convert(format);
+        &converted
+    };
+
+    let mut out = BufWriter::new(out);
+    let maxval = match format.bytes_per_sample() {
+        1 => 255,
+        _ => 65535,
+    };
+    match (format.is_gray(), format.has_alpha()) {
+        (true, false) => write!(out, "P5\n{} {}\n{}\n", img.width, img.height, maxval)?,
+        (false, false) => write!(out, "P6\n{} {}\n{}\n", img.width, img.height, maxval)?,
+        (gray, true) => write!(
+            out,
+            "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+            img.width,
+            img.height,
+            format.channels(),
+            maxval,
+            if gray { "GRAYSCALE_ALPHA" } else { "RGB_ALPHA" }
+        )?,
+    }
+    write_samples(&mut out, &img.data)?;
+    out.flush()
+}
+
+/* Writes a plain (ASCII) P3 file. Alpha is dropped and gray is written as
+ * RGB, lines are kept within the 70 characters the format allows. */
+pub fn write_plain_ppm<W: Write>(out: W, img: &Image) -> io::Result<()> {
+    let format = match img.format.bytes_per_sample() {
+        1 => PixelFormat::Rgb8,
+        _ => PixelFormat::Rgb16,
+    };
+    let img = img.convert(format);
+    let maxval = match format {
+        PixelFormat::Rgb8 => 255,
+        _ => 65535,
+    };
+
+    let mut out = BufWriter::new(out);
+    write!(out, "P3\n{} {}\n{}\n", img.width, img.height, maxval)?;
+    let mut line_len = 0;
+    for i in 0..img.num_pixels() {
+        let px = img.get_rgba16(i);
+        for v in px[..3].iter() {
+            let v = match format {
+                PixelFormat::Rgb8 => v >> 8,
+                _ => *v,
+            };
+            /* At most 5 digits and a separator */
+            if line_len + 6 > 70 {
+                out.write_all(b"\n")?;
+                line_len = 0;
+            } else if line_len > 0 {
+                out.write_all(b" ")?;
+                line_len += 1;
+            }
+            let s = v.to_string();
+            out.write_all(s.as_bytes())?;
+            line_len += s.len();
+        }
+    }
+    out.write_all(b"\n")?;
+    out.flush()
+}
+
+/* Reads the header tokens and raster of a Netpbm file */
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /* Skips whitespace and comments, which run from '#' to the end of the line */
+    fn skip_whitespace(&mut self) {
+        while let Some(&b) = self.data.get(self.pos) {
+            if b == b'#' {
+                while self.pos < self.data.len() && !matches!(self.data[self.pos], b'\n' | b'\r') {
+                    self.pos += 1;
+                }
+            } else if b.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<&'a [u8], String> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.pos < self.data.len() && !self.data[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        match self.pos - start {
+            0 => Err("Unexpected end of Netpbm data".to_string()),
+            _ => Ok(&self.data[start..self.pos]),
+        }
+    }
+
+    fn number(&mut self) -> Result<u32, String> {
+        let token = self.token()?;
+        std::str::from_utf8(token)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| {
+                format!(
+                    "Expected a number, got {:?}",
+                    String::from_utf8_lossy(token)
+                )
+            })
+    }
+
+    /* A plain PBM sample, these need no whitespace between them */
+    fn bit(&mut self) -> Result<u32, String> {
+        self.skip_whitespace();
+        let b = self.data.get(self.pos).copied();
+        self.pos += 1;
+        match b {
+            Some(b'0') => Ok(0),
+            Some(b'1') => Ok(1),
+            Some(b) => Err(format!("Invalid PBM sample {:?}", b as char)),
+            None => Err("Unexpected end of Netpbm data".to_string()),
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err(format!(
+                "Raster has {} bytes, expected {}",
+                self.data.len() - self.pos,
+                len
+            ));
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+}
+
+/* Parses the P7 header up to and including ENDHDR, returns width, height,
+ * depth and maxval */
+fn read_pam_header(reader: &mut Reader) -> Result<(u32, u32, u32, u32), String> {
+    let (mut width, mut height, mut depth, mut maxval) = (None, None, None, None);
+    loop {
+        match reader.token()? {
+            b"WIDTH" => width = Some(reader.number()?),
+            b"HEIGHT" => height = Some(reader.number()?),
+            b"DEPTH" => depth = Some(reader.number()?),
+            b"MAXVAL" => maxval = Some(reader.number()?),
+            /* The channel layout follows from DEPTH, the name is informational */
+            b"TUPLTYPE" => {
+                while reader.pos < reader.data.len() && reader.data[reader.pos] != b'\n' {
+                    reader.pos += 1;
+                }
+            }
+            b"ENDHDR" => break,
+            token => {
+                return Err(format!(
+                    "Unknown PAM header field {:?}",
+                    String::from_utf8_lossy(token)
+                ))
+            }
+        }
+    }
+
+    match (width, height, depth, maxval) {
+        (Some(w), Some(h), Some(d), Some(m)) => Ok((w, h, d, m)),
+        _ => Err("PAM header misses WIDTH, HEIGHT, DEPTH or MAXVAL".to_string()),
+    }
+}
+
+struct Header {
+    magic: u8,
+    width: u32,
+    height: u32,
+    channels: u32,
+    maxval: u32,
+    /* The layout decode() produces */
+    format: PixelFormat,
+}
+
+/* Parses the header up to the raster */
+fn read_header(reader: &mut Reader) -> Result<Header, String> {
+    let magic = match reader.data {
+        [b'P', m @ b'1'..=b'7', ..] => *m,
+        _ => return Err("Not a Netpbm file".to_string()),
+    };
+    reader.pos = 2;
+
+    let (width, height, channels, maxval) = match magic {
+        b'7' => read_pam_header(reader)?,
+        b'1' | b'4' => (reader.number()?, reader.number()?, 1, 1),
+        _ => {
+            let channels = if magic == b'3' || magic == b'6' { 3 } else { 1 };
+            (
+                reader.number()?,
+                reader.number()?,
+                channels,
+                reader.number()?,
+            )
+        }
+    };
+    if width == 0 || height == 0 {
+        return Err(format!("Invalid dimensions {}x{}", width, height));
+    }
+    if maxval == 0 || maxval > 65535 {
+        return Err(format!("Invalid maxval {}", maxval));
+    }
+    let format = match (channels, maxval > 255) {
+        (1, false) => PixelFormat::L8,
+        (2, false) => PixelFormat::La8,
+        (3, false) => PixelFormat::Rgb8,
+        (4, false) => PixelFormat::Rgba8,
+        (1, true) => PixelFormat::L16,
+        (2, true) => PixelFormat::La16,
+        (3, true) => PixelFormat::Rgb16,
+        (4, true) => PixelFormat::Rgba16,
+        _ => return Err(format!("Unsupported PAM depth {}", channels)),
+    };
+    Ok(Header {
+        magic,
+        width,
+        height,
+        channels,
+        maxval,
+        format,
+    })
+}
+
+/* Decodes a PBM, PGM, PPM or PAM file, plain or binary. Samples are rescaled
+ * from maxval to 8 bits, or to 16 bits when maxval is above 255. PBM files
+ * become L8 with 1 as black. */
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    let mut reader = Reader { data, pos: 0 };
+    let Header {
+        magic,
+        width,
+        height,
+        channels,
+        maxval,
+        format,
+    } = read_header(&mut reader)?;
+    /* Binary rasters start after a single whitespace character */
+    if magic >= b'4' {
+        reader.pos += 1;
+    }
+
+    let num_samples = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|n| n.checked_mul(channels as usize))
+        .ok_or(format!("Image size {}x{} is too large", width, height))?;
+    /* Checked before allocating: plain samples take at least a byte each */
+    let raster_len = match magic {
+        b'4' => (width as usize).div_ceil(8).checked_mul(height as usize),
+        _ if magic >= b'5' && maxval > 255 => num_samples.checked_mul(2),
+        _ => Some(num_samples),
+    };
+    let remaining = data.len().saturating_sub(reader.pos);
+    match raster_len {
+        Some(len) if len <= remaining => {}
+        _ => {
+            return Err(format!(
+                "Raster has {} bytes, too few for {}x{}",
+                remaining, width, height
+            ))
+        }
+    }
+    let mut samples = Vec::with_capacity(num_samples);
+    match magic {
+        b'1' => {
+            for _ in 0..num_samples {
+                samples.push(1 - reader.bit()?);
+            }
+        }
+        b'4' => {
+            let stride = (width as usize).div_ceil(8);
+            let raster = reader.bytes(stride * height as usize)?;
+            for row in raster.chunks(stride) {
+                for x in 0..width as usize {
+                    samples.push(1 - (row[x / 8] >> (7 - x % 8) & 1) as u32);
+                }
+            }
+        }
+        b'2' | b'3' => {
+            for _ in 0..num_samples {
+                samples.push(reader.number()?);
+            }
+        }
+        _ if maxval > 255 => {
+            let raster = reader.bytes(num_samples * 2)?;
+            samples.extend(raster.chunks(2).map(|b| (b[0] as u32) << 8 | b[1] as u32));
+        }
+        _ => samples.extend(reader.bytes(num_samples)?.iter().map(|b| *b as u32)),
+    }
+
+    if let Some(v) = samples.iter().find(|v| **v > maxval) {
+        return Err(format!("Sample {} is larger than maxval {}", v, maxval));
+    }
+    let data = match maxval {
+        255 => PixelData::U8(samples.into_iter().map(|v| v as u8).collect()),
+        65535 => PixelData::U16(samples.into_iter().map(|v| v as u16).collect()),
+        _ if maxval > 255 => PixelData::U16(
+            samples
+                .into_iter()
+                .map(|v| ((v * 65535 + maxval / 2) / maxval) as u16)
+                .collect(),
+        ),
+        _ => PixelData::U8(
+            samples
+                .into_iter()
+                .map(|v| ((v * 255 + maxval / 2) / maxval) as u8)
+                .collect(),
+        ),
+    };
+
+    Ok(Image {
+        width,
+        height,
+        format,
+        data,
+    })
+}
+
+/* Headers are free form, this is how much of a file probe looks at */
+const MAX_HEADER: usize = 64 * 1024;
+
+/* Reads the header, a little at a time until it is complete */
+pub fn probe<R: Read>(reader: R) -> Result<ImageInfo, String> {
+    let mut reader = reader.take(MAX_HEADER as u64);
+    let mut data = Vec::new();
+    loop {
+        let mut buf = [0; 256];
+        let n = reader.read(&mut buf).map_err(|e| e.to_string())?;
+        data.extend_from_slice(&buf[..n]);
+
+        let mut header_reader = Reader {
+            data: &data,
+            pos: 0,
+        };
+        /* A number at the very end of what was read could continue */
+        match read_header(&mut header_reader) {
+            Ok(header) if header_reader.pos < data.len() || n == 0 => {
+                let mut info = ImageInfo::new(header.width, header.height, header.format);
+                info.bit_depth = (u32::BITS - header.maxval.leading_zeros()) as u8;
+                return Ok(info);
+            }
+            Err(e) if n == 0 || !data.starts_with(b"P") => return Err(e),
+            _ => {}
+        }
+    }
+}
+
+pub struct PnmDecoder {
+    data: Vec<u8>,
+}
+
+impl PnmDecoder {
+    pub fn new(data: Vec<u8>) -> PnmDecoder {
+        PnmDecoder { data }
+    }
+}
+
+impl ImageDecoder for PnmDecoder {
+    fn info(&mut self) -> Result<ImageInfo, String> {
+        probe(self.data.as_slice())
+    }
+
+    fn decode(&mut self) -> Result<Image, String> {
+        decode(&self.data)
+    }
+}
+
+/* Writes binary PGM, PPM or PAM, or a plain PPM */
+#[derive(Default)]
+pub struct PnmEncoder {
+    pub plain: bool,
+}
+
+impl ImageEncoder for PnmEncoder {
+    fn encode(&self, img: &Image) -> Result<Vec<u8>, String> {
+        let mut out = Vec::new();
+        let result = if self.plain {
+            write_plain_ppm(&mut out, img)
+        } else {
+            write_pnm(&mut out, img)
+        };
+        result.map_err(|e| e.to_string())?;
+        Ok(out)
+    }
+}
+
+#[test]
+fn test_write_pnm() {
+    let mut img = Image::new(2, 1, PixelFormat::La8);
+    img.data = PixelData::U8(vec![10, 20, 30, 40]);
+    let mut out = Vec::new();
+    write_pnm(&mut out, &img).unwrap();
+    let header = "P7\nWIDTH 2\nHEIGHT 1\nDEPTH 2\nMAXVAL 255\nTUPLTYPE GRAYSCALE_ALPHA\nENDHDR\n";
+    assert_eq!(&out[..header.len()], header.as_bytes());
+    assert_eq!(&out[header.len()..], &[10, 20, 30, 40]);
+
+    let img = img.convert(PixelFormat::Rgb16);
+    let mut out = Vec::new();
+    write_pnm(&mut out, &img).unwrap();
+    assert_eq!(&out[..15], b"P6\n2 1\n65535\n\x0a\x0a");
+    assert_eq!(out.len(), 13 + 2 * 3 * 2);
+
+    let img = Image::new(40, 1, PixelFormat::Rgb8);
+    let mut out = Vec::new();
+    write_plain_ppm(&mut out, &img).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.starts_with("P3\n40 1\n255\n"));
+    assert!(text.lines().all(|l| l.len() <= 70));
+    assert_eq!(text.split_whitespace().count(), 4 + 40 * 3);
+}
+
+#[test]
+fn test_decode_pnm() {
+    let plain = b"P1\n# a comment\n3 2\n0 1 0\n110";
+    let img = decode(plain).unwrap();
+    assert_eq!(img.format, PixelFormat::L8);
+    assert_eq!(img.data, PixelData::U8(vec![255, 0, 255, 0, 0, 255]));
+
+    let img = decode(b"P2 2 1 15 # maxval 15\n0 15").unwrap();
+    assert_eq!(img.data, PixelData::U8(vec![0, 255]));
+    assert!(decode(b"P2 2 1 15 0 16").is_err());
+
+    /* Sizes the data cannot hold fail before allocating */
+    assert!(decode(b"P6 65535 65535 255\n").is_err());
+    assert!(decode(b"P5 4000000000 4000000000 65535\n").is_err());
+    assert!(decode(b"P1 3 2 0 1 0 1").is_err());
+
+    let mut img = Image::new(3, 2, PixelFormat::Rgba16);
+    img.data = PixelData::U16((0..24).map(|v| v * 2000).collect());
+    for format in [PixelFormat::L8, PixelFormat::La16, PixelFormat::Rgba16].iter() {
+        let img = img.convert(*format);
+        let mut out = Vec::new();
+        write_pnm(&mut out, &img).unwrap();
+        assert_eq!(decode(&out).unwrap(), img);
+    }
+}
@@ -0,0 +1,514 @@
+/* The Quite OK Image format. Pixels are coded one after another as runs,
+ * references to a 64 entry index of recently seen colours, small differences
+ * to the previous pixel or literal values. Decoder and Encoder work on
+ * streams a row or a few pixels at a time. */
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use crate::codec::{ImageDecoder, ImageEncoder, ImageInfo};
+use crate::image::{Image, PixelData, PixelFormat};
+
+const MAGIC: &[u8; 4] = b"qoif";
+const HEADER_SIZE: usize = 14;
+const END_MARKER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xc0;
+const OP_RGB: u8 = 0xfe;
+const OP_RGBA: u8 = 0xff;
+
+/* Runs are stored with a bias of -1, 63 and 64 would clash with OP_RGB and OP_RGBA */
+const MAX_RUN: u8 = 62;
+
+/* The largest image the specification allows */
+const MAX_PIXELS: u64 = 400_000_000;
+
+/* Gamma times 100000 of the two colour spaces, as in PNG's gAMA */
+const SRGB_GAMMA: u32 = 45455;
+const LINEAR_GAMMA: u32 = 100000;
+
+/* Informative only, it does not change how pixels are coded */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColourSpace {
+    /* sRGB colour with linear alpha */
+    #[default]
+    Srgb,
+    /* All channels linear */
+    Linear,
+}
+
+fn hash(px: [u8; 4]) -> usize {
+    let [r, g, b, a] = px.map(|v| v as usize);
+    (r * 3 + g * 5 + b * 7 + a * 11) % 64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    /* 3 for RGB, 4 for RGBA */
+    pub channels: u8,
+    pub colour_space: ColourSpace,
+}
+
+impl Header {
+    fn from_bytes(data: &[u8; HEADER_SIZE]) -> Result<Header, String> {
+        if &data[..4] != MAGIC {
+            return Err("Not a QOI file".to_string());
+        }
+        let width = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let height = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+        if width == 0 || height == 0 || width as u64 * height as u64 > MAX_PIXELS {
+            return Err(format!("Invalid image size {}x{}", width, height));
+        }
+        let channels = data[12];
+        if channels != 3 && channels != 4 {
+            return Err(format!("Invalid number of channels {}", channels));
+        }
+        let colour_space = match data[13] {
+            0 => ColourSpace::Srgb,
+            1 => ColourSpace::Linear,
+            v => return Err(format!("Invalid colour space {}", v)),
+        };
+        Ok(Header {
+            width,
+            height,
+            channels,
+            colour_space,
+        })
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut data = [0; HEADER_SIZE];
+        data[..4].copy_from_slice(MAGIC);
+        data[4..8].copy_from_slice(&self.width.to_be_bytes());
+        data[8..12].copy_from_slice(&self.height.to_be_bytes());
+        data[12] = self.channels;
+        data[13] = match self.colour_space {
+            ColourSpace::Srgb => 0,
+            ColourSpace::Linear => 1,
+        };
+        data
+    }
+
+    fn format(self) -> PixelFormat {
+        match self.channels {
+            3 => PixelFormat::Rgb8,
+            _ => PixelFormat::Rgba8,
+        }
+    }
+
+    fn info(self) -> ImageInfo {
+        let mut info = ImageInfo::new(self.width, self.height, self.format());
+        info.gamma = Some(match self.colour_space {
+            ColourSpace::Srgb => SRGB_GAMMA,
+            ColourSpace::Linear => LINEAR_GAMMA,
+        });
+        info
+    }
+}
+
+/* Decodes rows from a reader, the header is read by new() */
+pub struct Decoder<R: Read> {
+    reader: BufReader<R>,
+    header: Header,
+    index: [[u8; 4]; 64],
+    px: [u8; 4],
+    /* Repeats of px still to come */
+    run: u8,
+    rows_read: u32,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(reader: R) -> Result<Decoder<R>, String> {
+        let mut reader = BufReader::new(reader);
+        let mut data = [0; HEADER_SIZE];
+        reader
+            .read_exact(&mut data)
+            .map_err(|e| format!("Reading QOI header: {}", e))?;
+        Ok(Decoder {
+            reader,
+            header: Header::from_bytes(&data)?,
+            index: [[0; 4]; 64],
+            px: [0, 0, 0, 255],
+            run: 0,
+            rows_read: 0,
+        })
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let mut byte = [0];
+        self.reader
+            .read_exact(&mut byte)
+            .map_err(|_| "Truncated QOI data".to_string())?;
+        Ok(byte[0])
+    }
+
+    fn next_pixel(&mut self) -> Result<[u8; 4], String> {
+        if self.run > 0 {
+            self.run -= 1;
+            return Ok(self.px);
+        }
+
+        let b1 = self.byte()?;
+        let [r, g, b, a] = self.px;
+        self.px = match b1 {
+            OP_RGB => [self.byte()?, self.byte()?, self.byte()?, a],
+            OP_RGBA => [self.byte()?, self.byte()?, self.byte()?, self.byte()?],
+            _ => match b1 & 0xc0 {
+                OP_INDEX => self.index[b1 as usize],
+                OP_DIFF => [
+                    r.wrapping_add((b1 >> 4 & 3).wrapping_sub(2)),
+                    g.wrapping_add((b1 >> 2 & 3).wrapping_sub(2)),
+                    b.wrapping_add((b1 & 3).wrapping_sub(2)),
+                    a,
+                ],
+                OP_LUMA => {
+                    let b2 = self.byte()?;
+                    let dg = (b1 & 0x3f).wrapping_sub(32);
+                    [
+                        r.wrapping_add(dg.wrapping_add(b2 >> 4).wrapping_sub(8)),
+                        g.wrapping_add(dg),
+                        b.wrapping_add(dg.wrapping_add(b2 & 15).wrapping_sub(8)),
+                        a,
+                    ]
+                }
+                _ => {
+                    self.run = b1 & 0x3f;
+                    self.px
+                }
+            },
+        };
+        self.index[hash(self.px)] = self.px;
+        Ok(self.px)
+    }
+
+    /* Decodes the next row into `row`, which holds width times channels bytes */
+    pub fn read_row(&mut self, row: &mut [u8]) -> Result<(), String> {
+        let channels = self.header.channels as usize;
+        if row.len() != self.header.width as usize * channels {
+            return Err(format!(
+                "Row buffer of {} bytes, expected {}",
+                row.len(),
+                self.header.width as usize * channels
+            ));
+        }
+        if self.rows_read == self.header.height {
+            return Err("All rows have been read".to_string());
+        }
+        for px in row.chunks_mut(channels) {
+            px.copy_from_slice(&self.next_pixel()?[..channels]);
+        }
+        self.rows_read += 1;
+        Ok(())
+    }
+}
+
+/* Encodes pixels to a writer, the header is written by new() */
+pub struct Encoder<W: Write> {
+    writer: BufWriter<W>,
+    header: Header,
+    index: [[u8; 4]; 64],
+    prev: [u8; 4],
+    run: u8,
+    pixels_left: u64,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W, header: Header) -> Result<Encoder<W>, String> {
+        /* Checks the fields the way a decoder would */
+        let header = Header::from_bytes(&header.to_bytes())?;
+        let mut writer = BufWriter::new(writer);
+        writer
+            .write_all(&header.to_bytes())
+            .map_err(|e| e.to_string())?;
+        Ok(Encoder {
+            writer,
+            header,
+            index: [[0; 4]; 64],
+            prev: [0, 0, 0, 255],
+            run: 0,
+            pixels_left: header.width as u64 * header.height as u64,
+        })
+    }
+
+    fn write_op(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.writer.write_all(bytes).map_err(|e| e.to_string())
+    }
+
+    fn write_pixel(&mut self, px: [u8; 4]) -> Result<(), String> {
+        if px == self.prev {
+            self.run += 1;
+            if self.run == MAX_RUN {
+                self.write_op(&[OP_RUN | (self.run - 1)])?;
+                self.run = 0;
+            }
+            return Ok(());
+        }
+        if self.run > 0 {
+            self.write_op(&[OP_RUN | (self.run - 1)])?;
+            self.run = 0;
+        }
+
+        let i = hash(px);
+        let prev = self.prev;
+        self.prev = px;
+        if self.index[i] == px {
+            return self.write_op(&[OP_INDEX | i as u8]);
+        }
+        self.index[i] = px;
+        if px[3] != prev[3] {
+            return self.write_op(&[OP_RGBA, px[0], px[1], px[2], px[3]]);
+        }
+
+        let [dr, dg, db] = [0, 1, 2].map(|c| px[c].wrapping_sub(prev[c]) as i8);
+        let (dr_dg, db_dg) = (dr.wrapping_sub(dg), db.wrapping_sub(dg));
+        if [dr, dg, db].iter().all(|d| (-2..2).contains(d)) {
+            let op = OP_DIFF | ((dr + 2) << 4 | (dg + 2) << 2 | (db + 2)) as u8;
+            self.write_op(&[op])
+        } else if (-32..32).contains(&dg) && (-8..8).contains(&dr_dg) && (-8..8).contains(&db_dg) {
+            let b2 = ((dr_dg + 8) << 4 | (db_dg + 8)) as u8;
+            self.write_op(&[OP_LUMA | (dg + 32) as u8, b2])
+        } else {
+            self.write_op(&[OP_RGB, px[0], px[1], px[2]])
+        }
+    }
+
+    /* Encodes any number of whole pixels of the header's channels */
+    pub fn write_pixels(&mut self, data: &[u8]) -> Result<(), String> {
+        let channels = self.header.channels as usize;
+        if !data.len().is_multiple_of(channels) {
+            return Err(format!(
+                "{} bytes are not whole pixels of {} channels",
+                data.len(),
+                channels
+            ));
+        }
+        if (data.len() / channels) as u64 > self.pixels_left {
+            return Err("More pixels than the image has".to_string());
+        }
+        self.pixels_left -= (data.len() / channels) as u64;
+        for px in data.chunks(channels) {
+            let a = px.get(3).copied().unwrap_or(255);
+            self.write_pixel([px[0], px[1], px[2], a])?;
+        }
+        Ok(())
+    }
+
+    /* Ends the last run and writes the end marker, all pixels must have been written */
+    pub fn finish(mut self) -> Result<W, String> {
+        if self.pixels_left > 0 {
+            return Err(format!("{} pixels were not written", self.pixels_left));
+        }
+        if self.run > 0 {
+            self.write_op(&[OP_RUN | (self.run - 1)])?;
+        }
+        self.write_op(&END_MARKER)?;
+        self.writer.into_inner().map_err(|e| e.to_string())
+    }
+}
+
+/* Decodes to RGB8 or RGBA8, as the header says */
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    let mut decoder = Decoder::new(data)?;
+    let header = decoder.header();
+    /* A one byte run covers at most 62 pixels */
+    let pixels = header.width as u64 * header.height as u64;
+    if pixels > (data.len() - HEADER_SIZE) as u64 * MAX_RUN as u64 {
+        return Err(format!(
+            "{} bytes are too few for {}x{} pixels",
+            data.len(),
+            header.width,
+            header.height
+        ));
+    }
+    let mut img = Image::new(header.width, header.height, header.format());
+    let row_len = header.width as usize * header.channels as usize;
+    let PixelData::U8(pixels) = &mut img.data else {
+        unreachable!()
+    };
+    for row in pixels.chunks_mut(row_len) {
+        decoder.read_row(row)?;
+    }
+    Ok(img)
+}
+
+/* Writes RGBA when the image has alpha, RGB otherwise */
+pub fn encode(img: &Image, colour_space: ColourSpace) -> Result<Vec<u8>, String> {
+    let format = match img.format.has_alpha() {
+        true => PixelFormat::Rgba8,
+        false => PixelFormat::Rgb8,
+    };
+    let img = img.convert(format);
+    let PixelData::U8(data) = &img.data else {
+        unreachable!()
+    };
+    let header = Header {
+        width: img.width,
+        height: img.height,
+        channels: format.channels() as u8,
+        colour_space,
+    };
+    let mut encoder = Encoder::new(Vec::new(), header)?;
+    encoder.write_pixels(data)?;
+    encoder.finish()
+}
+
+pub fn probe<R: Read>(mut reader: R) -> Result<ImageInfo, String> {
+    let mut data = [0; HEADER_SIZE];
+    reader
+        .read_exact(&mut data)
+        .map_err(|e| format!("Reading QOI header: {}", e))?;
+    Ok(Header::from_bytes(&data)?.info())
+}
+
+pub struct QoiDecoder {
+    data: Vec<u8>,
+}
+
+impl QoiDecoder {
+    pub fn new(data: Vec<u8>) -> QoiDecoder {
+        QoiDecoder { data }
+    }
+}
+
+impl ImageDecoder for QoiDecoder {
+    fn info(&mut self) -> Result<ImageInfo, String> {
+        probe(self.data.as_slice())
+    }
+
+    fn decode(&mut self) -> Result<Image, String> {
+        decode(&self.data)
+    }
+
+    /* Only one row is decoded at a time */
+    fn decode_rows(&mut self, f: &mut dyn FnMut(u32, &Image)) -> Result<(), String> {
+        let mut decoder = Decoder::new(self.data.as_slice())?;
+        let header = decoder.header();
+        let mut row = Image::new(header.width, 1, header.format());
+        for y in 0..header.height {
+            let PixelData::U8(data) = &mut row.data else {
+                unreachable!()
+            };
+            decoder.read_row(data)?;
+            f(y, &row);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> Vec<(String, String)> {
+        match probe(self.data.as_slice()) {
+            Ok(info) => {
+                let colour_space = match info.gamma {
+                    Some(LINEAR_GAMMA) => "linear",
+                    _ => "sRGB",
+                };
+                vec![("colour space".to_string(), colour_space.to_string())]
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct QoiEncoder {
+    pub colour_space: ColourSpace,
+}
+
+impl ImageEncoder for QoiEncoder {
+    fn encode(&self, img: &Image) -> Result<Vec<u8>, String> {
+        encode(img, self.colour_space)
+    }
+}
+
+#[test]
+fn test_qoi_round_trip() {
+    for entry in std::fs::read_dir("tests/png_testsuite").unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        /* Files starting with x are corrupt */
+        if !name.ends_with(".png") || name.starts_with('x') {
+            continue;
+        }
+        let img = crate::open(&path).unwrap();
+        let format = match img.format.has_alpha() {
+            true => PixelFormat::Rgba8,
+            false => PixelFormat::Rgb8,
+        };
+        let encoded = encode(&img, ColourSpace::Srgb).unwrap();
+        assert_eq!(decode(&encoded).unwrap(), img.convert(format), "{}", name);
+        assert!(encoded.ends_with(&END_MARKER));
+    }
+}
+
+#[test]
+fn test_qoi_ops() {
+    /* A run of the initial pixel, a small difference, a luma difference, an
+     * index hit, a literal and a change of alpha */
+    let pixels = [
+        [0, 0, 0, 255],
+        [0, 0, 0, 255],
+        [1, 255, 0, 255],
+        [11, 5, 3, 255],
+        [1, 255, 0, 255],
+        [200, 100, 50, 255],
+        [200, 100, 50, 128],
+    ];
+    let header = Header {
+        width: pixels.len() as u32,
+        height: 1,
+        channels: 4,
+        colour_space: ColourSpace::Linear,
+    };
+    let mut encoder = Encoder::new(Vec::new(), header).unwrap();
+    for px in pixels.iter() {
+        encoder.write_pixels(px).unwrap();
+    }
+    let encoded = encoder.finish().unwrap();
+    assert_eq!(&encoded[..HEADER_SIZE], &header.to_bytes());
+    let expected = [
+        OP_RUN | 1,
+        OP_DIFF | 3 << 4 | 1 << 2 | 2,
+        OP_LUMA | 38,
+        12 << 4 | 5,
+        OP_INDEX | hash([1, 255, 0, 255]) as u8,
+        OP_RGB,
+        200,
+        100,
+        50,
+        OP_RGBA,
+        200,
+        100,
+        50,
+        128,
+    ];
+    assert_eq!(&encoded[HEADER_SIZE..encoded.len() - 8], &expected);
+
+    let mut decoder = Decoder::new(encoded.as_slice()).unwrap();
+    assert_eq!(decoder.header(), header);
+    let mut row = vec![0; pixels.len() * 4];
+    decoder.read_row(&mut row).unwrap();
+    assert_eq!(row, pixels.concat());
+    assert!(decoder.read_row(&mut row).is_err());
+    assert!(decode(&encoded[..encoded.len() - 12]).is_err());
+}
+
+#[test]
+fn test_qoi_size_limits() {
+    /* Over the 400 million pixels of the specification */
+    let mut data = b"qoif\xff\xff\xff\xff\xff\xff\xff\xff\x04\x00".to_vec();
+    assert!(Decoder::new(data.as_slice()).is_err());
+    assert!(decode(&data).is_err());
+
+    /* Allowed, but the single run that follows cannot fill it */
+    data[4..12].copy_from_slice(&[0, 0, 0x27, 0x10, 0, 0, 0x27, 0x10]);
+    assert!(Decoder::new(data.as_slice()).is_ok());
+    data.push(OP_RUN | 61);
+    data.extend_from_slice(&END_MARKER);
+    assert!(decode(&data).is_err());
+}
@@ -0,0 +1,701 @@
+/* Truevision TGA. Colour-mapped, truecolour and gray images are read raw or
+ * run length encoded, along with the TGA 2.0 footer and extension area.
+ * Files are written run length encoded with a top-left origin. */
+use std::io::Read;
+
+use crate::codec::{ImageDecoder, ImageEncoder, ImageInfo};
+use crate::image::{Image, PixelData, PixelFormat};
+
+const HEADER_SIZE: usize = 18;
+const FOOTER_SIZE: usize = 26;
+const SIGNATURE: &[u8; 18] = b"TRUEVISION-XFILE.\0";
+const EXTENSION_SIZE: usize = 495;
+
+/* Image types, RLE adds 8 */
+const COLOUR_MAPPED: u8 = 1;
+const TRUECOLOUR: u8 = 2;
+const GRAY: u8 = 3;
+const RLE: u8 = 8;
+
+/* Image descriptor bits */
+const ALPHA_BITS: u8 = 0x0f;
+const RIGHT_TO_LEFT: u8 = 0x10;
+const TOP_TO_BOTTOM: u8 = 0x20;
+
+/* Attributes types of the extension area */
+const ATTRIBUTES_ALPHA: u8 = 3;
+const ATTRIBUTES_PREMULTIPLIED: u8 = 4;
+
+/* Packets hold 1 to 128 pixels */
+const MAX_PACKET: usize = 128;
+
+struct Header {
+    id_length: u8,
+    colour_map_type: u8,
+    image_type: u8,
+    map_first: u16,
+    map_length: u16,
+    map_entry_bits: u8,
+    width: u16,
+    height: u16,
+    depth: u8,
+    descriptor: u8,
+}
+
+impl Header {
+    fn from_bytes(data: &[u8]) -> Result<Header, String> {
+        if data.len() < HEADER_SIZE {
+            return Err("Truncated TGA header".to_string());
+        }
+        let u16_at = |pos: usize| u16::from_le_bytes([data[pos], data[pos + 1]]);
+        let header = Header {
+            id_length: data[0],
+            colour_map_type: data[1],
+            image_type: data[2],
+            map_first: u16_at(3),
+            map_length: u16_at(5),
+            map_entry_bits: data[7],
+            width: u16_at(12),
+            height: u16_at(14),
+            depth: data[16],
+            descriptor: data[17],
+        };
+
+        if header.width == 0 || header.height == 0 {
+            return Err(format!(
+                "Invalid image size {}x{}",
+                header.width, header.height
+            ));
+        }
+        if header.colour_map_type > 1 {
+            return Err(format!(
+                "Invalid colour map type {}",
+                header.colour_map_type
+            ));
+        }
+        if header.colour_map_type == 1 && ![15, 16, 24, 32].contains(&header.map_entry_bits) {
+            return Err(format!(
+                "Unsupported colour map entry size {}",
+                header.map_entry_bits
+            ));
+        }
+        let valid = match header.image_type & !RLE {
+            COLOUR_MAPPED => header.colour_map_type == 1 && [8, 16].contains(&header.depth),
+            TRUECOLOUR => [15, 16, 24, 32].contains(&header.depth),
+            GRAY => [8, 16].contains(&header.depth),
+            _ => false,
+        };
+        if !valid {
+            return Err(format!(
+                "Unsupported image type {} at {} bits per pixel",
+                header.image_type, header.depth
+            ));
+        }
+        Ok(header)
+    }
+
+    fn map_entry_size(&self) -> usize {
+        match self.colour_map_type {
+            1 => (self.map_entry_bits as usize).div_ceil(8),
+            _ => 0,
+        }
+    }
+
+    /* Bytes from the start of the file to the pixel data */
+    fn data_offset(&self) -> usize {
+        HEADER_SIZE + self.id_length as usize + self.map_length as usize * self.map_entry_size()
+    }
+
+    /* Bits of the stored colours, which are the map entries for colour-mapped images */
+    fn colour_bits(&self) -> u8 {
+        match self.image_type & !RLE {
+            COLOUR_MAPPED => self.map_entry_bits,
+            _ => self.depth,
+        }
+    }
+
+    /* The descriptor's alpha bits, unless the extension area says otherwise */
+    fn format(&self, extension: Option<&Extension>) -> PixelFormat {
+        let alpha = match extension.map(|e| e.attributes_type) {
+            Some(attributes) if attributes <= 4 => attributes >= ATTRIBUTES_ALPHA,
+            _ => self.descriptor & ALPHA_BITS > 0,
+        };
+        let premultiplied = extension.map(|e| e.attributes_type) == Some(ATTRIBUTES_PREMULTIPLIED);
+        match (self.image_type & !RLE, self.colour_bits()) {
+            (GRAY, 16) if alpha => PixelFormat::La8,
+            (GRAY, _) => PixelFormat::L8,
+            (_, 16 | 32) if alpha && premultiplied => PixelFormat::Rgba8Premultiplied,
+            (_, 16 | 32) if alpha => PixelFormat::Rgba8,
+            _ => PixelFormat::Rgb8,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self.image_type & !RLE {
+            COLOUR_MAPPED => "colour-mapped",
+            TRUECOLOUR => "truecolour",
+            _ => "gray",
+        }
+    }
+}
+
+/* The TGA 2.0 extension area, located through the footer */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Extension {
+    pub author: String,
+    /* Up to four lines */
+    pub comments: String,
+    /* Month, day, year, hour, minute and second */
+    pub timestamp: Option<[u16; 6]>,
+    pub job: String,
+    pub software: String,
+    /* Version times 100 and a letter, as in 1.10b */
+    pub software_version: Option<(u16, char)>,
+    /* Background colour as ARGB */
+    pub key_colour: u32,
+    /* Pixel width to height */
+    pub aspect_ratio: Option<(u16, u16)>,
+    /* Display gamma as numerator and denominator */
+    pub gamma: Option<(u16, u16)>,
+    /* 0 no alpha, 1 and 2 undefined, 3 alpha, 4 premultiplied alpha */
+    pub attributes_type: u8,
+}
+
+/* A NUL terminated ASCII field */
+fn read_string(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    data[..end].iter().map(|b| *b as char).collect()
+}
+
+/* Reads the extension area of a TGA 2.0 file, None for files without one */
+pub fn read_extension(data: &[u8]) -> Result<Option<Extension>, String> {
+    if !data.ends_with(SIGNATURE) || data.len() < FOOTER_SIZE {
+        return Ok(None);
+    }
+    let footer = &data[data.len() - FOOTER_SIZE..];
+    let offset = u32::from_le_bytes([footer[0], footer[1], footer[2], footer[3]]) as usize;
+    if offset == 0 {
+        return Ok(None);
+    }
+    let ext = data
+        .get(offset..offset + EXTENSION_SIZE)
+        .ok_or("Truncated TGA extension area")?;
+    let u16_at = |pos: usize| u16::from_le_bytes([ext[pos], ext[pos + 1]]);
+    let pair_at = |pos: usize| match (u16_at(pos), u16_at(pos + 2)) {
+        (_, 0) => None,
+        pair => Some(pair),
+    };
+    if (u16_at(0) as usize) < EXTENSION_SIZE {
+        return Err(format!("Invalid extension area size {}", u16_at(0)));
+    }
+
+    let comments: Vec<String> = ext[43..367]
+        .chunks(81)
+        .map(read_string)
+        .filter(|line| !line.is_empty())
+        .collect();
+    let timestamp = [0, 1, 2, 3, 4, 5].map(|i| u16_at(367 + 2 * i));
+    let software_version = match (u16_at(467), ext[469]) {
+        (0, b' ' | 0) => None,
+        (version, letter) => Some((version, letter as char)),
+    };
+    Ok(Some(Extension {
+        author: read_string(&ext[2..43]),
+        comments: comments.join("\n"),
+        timestamp: Some(timestamp).filter(|t| t.iter().any(|v| *v != 0)),
+        job: read_string(&ext[379..420]),
+        software: read_string(&ext[426..467]),
+        software_version,
+        key_colour: u32::from_le_bytes([ext[470], ext[471], ext[472], ext[473]]),
+        aspect_ratio: pair_at(474),
+        gamma: pair_at(478),
+        attributes_type: ext[494],
+    }))
+}
+
+/* A stored colour as RGBA, 15 and 16-bit colours have 5 bits per channel
+ * and an attribute bit */
+fn read_colour(data: &[u8], bits: u8) -> [u8; 4] {
+    let scale = |v: u16| ((v as u32 * 255 + 15) / 31) as u8;
+    match bits {
+        15 | 16 => {
+            let v = u16::from_le_bytes([data[0], data[1]]);
+            let a = match v >> 15 {
+                1 => 255,
+                _ => 0,
+            };
+            [scale(v >> 10 & 31), scale(v >> 5 & 31), scale(v & 31), a]
+        }
+        24 => [data[2], data[1], data[0], 255],
+        _ => [data[2], data[1], data[0], data[3]],
+    }
+}
+
+fn read_colour_map(header: &Header, data: &[u8]) -> Result<Vec<[u8; 4]>, String> {
+    if header.colour_map_type == 0 {
+        return Ok(Vec::new());
+    }
+    let start = HEADER_SIZE + header.id_length as usize;
+    let entry_size = header.map_entry_size();
+    let map = data
+        .get(start..start + header.map_length as usize * entry_size)
+        .ok_or("Truncated TGA colour map")?;
+    Ok(map
+        .chunks(entry_size)
+        .map(|entry| read_colour(entry, header.map_entry_bits))
+        .collect())
+}
+
+/* Reads width times height pixels of `size` bytes, raw or run length encoded.
+ * Packets may cross rows. */
+fn read_pixels(header: &Header, data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let len = header.width as usize * header.height as usize * size;
+    if header.image_type & RLE == 0 {
+        return match data.get(..len) {
+            Some(pixels) => Ok(pixels.to_vec()),
+            None => Err("Truncated TGA pixel data".to_string()),
+        };
+    }
+
+    /* Every packet takes at least a byte and holds at most 128 pixels, so
+     * the header cannot ask for more than that before anything is allocated */
+    if len / size > data.len() * 128 {
+        return Err("Truncated TGA pixel data".to_string());
+    }
+    let mut pixels = Vec::new();
+    let mut pos = 0;
+    while pixels.len() < len {
+        let packet = *data.get(pos).ok_or("Truncated TGA pixel data")?;
+        let count = (packet & 0x7f) as usize + 1;
+        let stored = match packet & 0x80 {
+            0 => count * size,
+            _ => size,
+        };
+        let bytes = data
+            .get(pos + 1..pos + 1 + stored)
+            .ok_or("Truncated TGA pixel data")?;
+        if packet & 0x80 == 0 {
+            pixels.extend_from_slice(bytes);
+        } else {
+            for _ in 0..count {
+                pixels.extend_from_slice(bytes);
+            }
+        }
+        pos += 1 + stored;
+    }
+    pixels.truncate(len);
+    Ok(pixels)
+}
+
+/* Decodes to L8, LA8, RGB8, RGBA8 or premultiplied RGBA8 */
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    let header = Header::from_bytes(data)?;
+    let extension = read_extension(data)?;
+    let format = header.format(extension.as_ref());
+    let colour_map = read_colour_map(&header, data)?;
+
+    let size = (header.depth as usize).div_ceil(8);
+    let offset = header.data_offset();
+    let pixels = read_pixels(&header, data.get(offset..).unwrap_or(&[]), size)?;
+
+    let (width, height) = (header.width as usize, header.height as usize);
+    let mut img = Image::new(width as u32, height as u32, format);
+    let channels = format.channels();
+    let PixelData::U8(out) = &mut img.data else {
+        unreachable!()
+    };
+    for (i, px) in pixels.chunks(size).enumerate() {
+        let rgba = pixel_colour(&header, &colour_map, px)?;
+
+        /* The origin is in the bottom left corner unless the descriptor says otherwise */
+        let (mut x, mut y) = (i % width, i / width);
+        if header.descriptor & RIGHT_TO_LEFT != 0 {
+            x = width - 1 - x;
+        }
+        if header.descriptor & TOP_TO_BOTTOM == 0 {
+            y = height - 1 - y;
+        }
+        store(&mut out[(y * width + x) * channels..][..channels], rgba);
+    }
+    Ok(img)
+}
+
+/* The RGBA colour of one stored pixel of `size` bytes */
+fn pixel_colour(header: &Header, colour_map: &[[u8; 4]], px: &[u8]) -> Result<[u8; 4], String> {
+    Ok(match header.image_type & !RLE {
+        COLOUR_MAPPED => {
+            let index = match px.len() {
+                1 => px[0] as usize,
+                _ => u16::from_le_bytes([px[0], px[1]]) as usize,
+            };
+            *index
+                .checked_sub(header.map_first as usize)
+                .and_then(|i| colour_map.get(i))
+                .ok_or_else(|| format!("Colour map index {} out of range", index))?
+        }
+        GRAY => [px[0], px[0], px[0], px.get(1).copied().unwrap_or(255)],
+        _ => read_colour(px, header.depth),
+    })
+}
+
+/* Writes an RGBA colour to a pixel with 1 to 4 channels */
+fn store(dest: &mut [u8], rgba: [u8; 4]) {
+    match dest.len() {
+        1 => dest[0] = rgba[0],
+        2 => dest.copy_from_slice(&[rgba[0], rgba[3]]),
+        channels => dest.copy_from_slice(&rgba[..channels]),
+    }
+}
+
+/* Packs a row into run packets of repeated pixels and raw packets of the rest */
+fn write_rle_row(out: &mut Vec<u8>, row: &[u8], size: usize) {
+    let pixels: Vec<&[u8]> = row.chunks(size).collect();
+    let mut i = 0;
+    while i < pixels.len() {
+        let mut run = 1;
+        while i + run < pixels.len() && run < MAX_PACKET && pixels[i + run] == pixels[i] {
+            run += 1;
+        }
+        if run > 1 {
+            out.push(0x80 | (run - 1) as u8);
+            out.extend_from_slice(pixels[i]);
+            i += run;
+            continue;
+        }
+
+        /* Raw pixels up to the start of the next run */
+        let mut end = i + 1;
+        while end < pixels.len()
+            && end - i < MAX_PACKET
+            && (end + 1 == pixels.len() || pixels[end] != pixels[end + 1])
+        {
+            end += 1;
+        }
+        out.push((end - i - 1) as u8);
+        for px in pixels[i..end].iter() {
+            out.extend_from_slice(px);
+        }
+        i = end;
+    }
+}
+
+/* Writes gray, gray and alpha, BGR or BGRA with a top-left origin and the
+ * TGA 2.0 footer. Packets do not cross rows. */
+pub fn encode(img: &Image, rle: bool) -> Result<Vec<u8>, String> {
+    if img.width > u16::MAX as u32 || img.height > u16::MAX as u32 {
+        return Err(format!(
+            "Image size {}x{} is too large for TGA",
+            img.width, img.height
+        ));
+    }
+    let format = match (img.format.is_gray(), img.format.has_alpha()) {
+        (true, false) => PixelFormat::L8,
+        (true, true) => PixelFormat::La8,
+        (false, false) => PixelFormat::Rgb8,
+        (false, true) => PixelFormat::Rgba8,
+    };
+    let img = img.convert(format);
+    let PixelData::U8(data) = &img.data else {
+        unreachable!()
+    };
+
+    let image_type = match img.format.is_gray() {
+        true => GRAY,
+        false => TRUECOLOUR,
+    };
+    let alpha_bits = match format.has_alpha() {
+        true => 8,
+        false => 0,
+    };
+    let channels = format.channels();
+    let mut out = vec![0; HEADER_SIZE];
+    out[2] = image_type | if rle { RLE } else { 0 };
+    out[12..14].copy_from_slice(&(img.width as u16).to_le_bytes());
+    out[14..16].copy_from_slice(&(img.height as u16).to_le_bytes());
+    out[16] = (channels * 8) as u8;
+    out[17] = TOP_TO_BOTTOM | alpha_bits;
+
+    /* Colours are stored as BGR and BGRA */
+    let mut stored = data.clone();
+    if channels >= 3 {
+        for px in stored.chunks_mut(channels) {
+            px.swap(0, 2);
+        }
+    }
+    if rle {
+        for row in stored.chunks(img.width as usize * channels) {
+            write_rle_row(&mut out, row, channels);
+        }
+    } else {
+        out.extend_from_slice(&stored);
+    }
+
+    /* No extension area or developer directory */
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(SIGNATURE);
+    Ok(out)
+}
+
+/* Reads the header, image ID and colour map. Without the footer at the end
+ * of the file the pixel format only follows the descriptor's alpha bits. */
+pub fn probe<R: Read>(mut reader: R) -> Result<ImageInfo, String> {
+    let mut data = vec![0; HEADER_SIZE];
+    reader
+        .read_exact(&mut data)
+        .map_err(|e| format!("Reading TGA header: {}", e))?;
+    let header = Header::from_bytes(&data)?;
+    data.resize(header.data_offset(), 0);
+    reader
+        .read_exact(&mut data[HEADER_SIZE..])
+        .map_err(|e| format!("Reading TGA header: {}", e))?;
+
+    let mut info = ImageInfo::new(
+        header.width as u32,
+        header.height as u32,
+        header.format(None),
+    );
+    info.bit_depth = match header.colour_bits() {
+        15 | 16 if header.image_type & !RLE != GRAY => 5,
+        _ => 8,
+    };
+    if header.image_type & !RLE == COLOUR_MAPPED {
+        info.bit_depth = header.depth;
+        let map = read_colour_map(&header, &data)?;
+        info.palette = Some(map.iter().map(|c| (c[0], c[1], c[2], c[3])).collect());
+    }
+    let id = read_string(&data[HEADER_SIZE..HEADER_SIZE + header.id_length as usize]);
+    if !id.is_empty() {
+        info.text.push(("Image ID".to_string(), id));
+    }
+    Ok(info)
+}
+
+pub struct TgaDecoder {
+    data: Vec<u8>,
+}
+
+impl TgaDecoder {
+    pub fn new(data: Vec<u8>) -> TgaDecoder {
+        TgaDecoder { data }
+    }
+}
+
+impl ImageDecoder for TgaDecoder {
+    fn info(&mut self) -> Result<ImageInfo, String> {
+        let mut info = probe(self.data.as_slice())?;
+        let extension = match read_extension(&self.data)? {
+            Some(extension) => extension,
+            None => return Ok(info),
+        };
+        info.pixel_format = Header::from_bytes(&self.data)?.format(Some(&extension));
+        info.gamma = extension
+            .gamma
+            .filter(|(num, _)| *num > 0)
+            .map(|(num, den)| (100000 * den as u32 + num as u32 / 2) / num as u32);
+        let fields = [
+            ("Author", &extension.author),
+            ("Comment", &extension.comments),
+            ("Job", &extension.job),
+            ("Software", &extension.software),
+        ];
+        for (keyword, text) in fields.iter() {
+            if !text.is_empty() {
+                info.text.push((keyword.to_string(), text.to_string()));
+            }
+        }
+        Ok(info)
+    }
+
+    fn decode(&mut self) -> Result<Image, String> {
+        decode(&self.data)
+    }
+
+    /* Uncompressed rows are converted one at a time, RLE is expanded whole */
+    fn decode_rows(&mut self, f: &mut dyn FnMut(u32, &Image)) -> Result<(), String> {
+        let header = Header::from_bytes(&self.data)?;
+        let size = (header.depth as usize).div_ceil(8);
+        let (width, height) = (header.width as usize, header.height as usize);
+        let pixels = self
+            .data
+            .get(header.data_offset()..)
+            .and_then(|data| data.get(..width * height * size));
+        let (Some(pixels), 0) = (pixels, header.image_type & RLE) else {
+            let img = decode(&self.data)?;
+            for y in 0..img.height {
+                f(y, &crate::codec::row(&img, y));
+            }
+            return Ok(());
+        };
+
+        let extension = read_extension(&self.data)?;
+        let format = header.format(extension.as_ref());
+        let colour_map = read_colour_map(&header, &self.data)?;
+        let channels = format.channels();
+        let mut row = Image::new(width as u32, 1, format);
+        for y in 0..height {
+            let stored = match header.descriptor & TOP_TO_BOTTOM {
+                0 => height - 1 - y,
+                _ => y,
+            };
+            let PixelData::U8(dest) = &mut row.data else {
+                unreachable!()
+            };
+            let src = &pixels[stored * width * size..][..width * size];
+            for (i, px) in src.chunks(size).enumerate() {
+                let x = match header.descriptor & RIGHT_TO_LEFT {
+                    0 => i,
+                    _ => width - 1 - i,
+                };
+                let rgba = pixel_colour(&header, &colour_map, px)?;
+                store(&mut dest[x * channels..][..channels], rgba);
+            }
+            f(y as u32, &row);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> Vec<(String, String)> {
+        let header = match Header::from_bytes(&self.data) {
+            Ok(header) => header,
+            Err(_) => return Vec::new(),
+        };
+        let origin = match (
+            header.descriptor & TOP_TO_BOTTOM,
+            header.descriptor & RIGHT_TO_LEFT,
+        ) {
+            (0, 0) => "bottom left",
+            (0, _) => "bottom right",
+            (_, 0) => "top left",
+            _ => "top right",
+        };
+        let mut metadata = vec![
+            ("image type".to_string(), header.type_name().to_string()),
+            ("bits per pixel".to_string(), header.depth.to_string()),
+            (
+                "rle".to_string(),
+                (header.image_type & RLE != 0).to_string(),
+            ),
+            ("origin".to_string(), origin.to_string()),
+            (
+                "alpha bits".to_string(),
+                (header.descriptor & ALPHA_BITS).to_string(),
+            ),
+        ];
+        if let Ok(Some(extension)) = read_extension(&self.data) {
+            if let Some([month, day, year, hour, minute, second]) = extension.timestamp {
+                metadata.push((
+                    "date".to_string(),
+                    format!(
+                        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                        year, month, day, hour, minute, second
+                    ),
+                ));
+            }
+            metadata.push((
+                "attributes type".to_string(),
+                extension.attributes_type.to_string(),
+            ));
+        }
+        metadata
+    }
+}
+
+/* Writes RLE TGA unless `rle` is turned off */
+pub struct TgaEncoder {
+    pub rle: bool,
+}
+
+impl Default for TgaEncoder {
+    fn default() -> Self {
+        TgaEncoder { rle: true }
+    }
+}
+
+impl ImageEncoder for TgaEncoder {
+    fn encode(&self, img: &Image) -> Result<Vec<u8>, String> {
+        encode(img, self.rle)
+    }
+}
+
+#[test]
+fn test_decode_tga() {
+    let files = [
+        ("basn0g08", "basn0g08"),
+        ("basn2c08_16", "basn2c08"),
+        ("basn2c08_rle", "basn2c08"),
+        ("basn3p08_cmap", "basn3p08"),
+        ("basn3p08_cmap_rle", "basn3p08"),
+        ("basn4a08_rle", "basn4a08"),
+        ("basn6a08_rle", "basn6a08"),
+    ];
+    for (name, reference) in files.iter() {
+        let data = std::fs::read(format!("tests/tga/{}.tga", name)).unwrap();
+        let img = decode(&data).unwrap();
+        let expected = crate::open(format!("tests/png_testsuite/{}.png", reference))
+            .unwrap()
+            .convert(img.format);
+        assert_eq!(
+            TgaDecoder::new(data).info().unwrap().pixel_format,
+            img.format
+        );
+
+        /* 5 bits per channel */
+        let tolerance = match name.contains("16") || name.contains("cmap_rle") {
+            true => 8,
+            false => 0,
+        };
+        let (PixelData::U8(a), PixelData::U8(b)) = (&img.data, &expected.data) else {
+            panic!("{} is not 8-bit", name)
+        };
+        assert!(
+            a.iter().zip(b).all(|(a, b)| a.abs_diff(*b) <= tolerance),
+            "{}",
+            name
+        );
+    }
+
+    let data = std::fs::read("tests/tga/basn6a08_rle.tga").unwrap();
+    let extension = read_extension(&data).unwrap().unwrap();
+    assert_eq!(extension.author, "Test author");
+    assert_eq!(extension.software_version, Some((110, 'b')));
+    assert_eq!(extension.gamma, Some((22, 10)));
+    assert_eq!(extension.attributes_type, ATTRIBUTES_ALPHA);
+    let info = TgaDecoder::new(data).info().unwrap();
+    assert_eq!(info.gamma, Some(45455));
+
+    let data = std::fs::read("tests/tga/basn3p08_cmap.tga").unwrap();
+    let info = probe(data.as_slice()).unwrap();
+    assert_eq!(
+        info.text,
+        vec![("Image ID".to_string(), "sparrow".to_string())]
+    );
+    assert_eq!(info.bit_depth, 16);
+    assert_eq!(info.palette.unwrap().len(), 256);
+
+    /* One RLE packet cannot fill 65535x65535 pixels */
+    let mut data = vec![
+        0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 32, 8,
+    ];
+    data.extend_from_slice(&[0xff, 1, 2, 3, 4]);
+    assert!(decode(&data).is_err());
+}
+
+#[test]
+fn test_encode_tga() {
+    for name in ["basn0g08", "basn4a08", "basn2c08", "basn6a08", "basn3p04"].iter() {
+        let img = crate::open(format!("tests/png_testsuite/{}.png", name)).unwrap();
+        for rle in [true, false].iter() {
+            let encoded = encode(&img, *rle).unwrap();
+            let decoded = decode(&encoded).unwrap();
+            assert_eq!(decoded, img.convert(decoded.format), "{}", name);
+            assert_eq!(decoded.format.has_alpha(), img.format.has_alpha());
+        }
+    }
+
+    /* A run of 130 pixels needs two packets, followed by a raw packet */
+    let mut img = Image::new(132, 1, PixelFormat::L8);
+    img.data = PixelData::U8([vec![7; 130], vec![1, 2]].concat());
+    let encoded = encode(&img, true).unwrap();
+    let packets = &encoded[HEADER_SIZE..encoded.len() - FOOTER_SIZE];
+    assert_eq!(packets, &[0xff, 7, 0x81, 7, 0x01, 1, 2]);
+}
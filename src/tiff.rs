@@ -0,0 +1,856 @@
+/* Baseline TIFF: a chain of IFDs, one per page, each stored in strips or
+ * tiles, uncompressed or with PackBits, LZW or Deflate */
+use std::collections::VecDeque;
+use std::io::Read;
+
+use crate::codec::{ImageDecoder, ImageInfo};
+use crate::image::{Image, PixelData, PixelFormat};
+use crate::zlib;
+
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 12;
+const MAGIC: u16 = 42;
+const MAX_PAGES: usize = 4096;
+const MAX_CODES: usize = 4096;
+
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC: u16 = 262;
+const IMAGE_DESCRIPTION: u16 = 270;
+const MAKE: u16 = 271;
+const MODEL: u16 = 272;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const PLANAR_CONFIGURATION: u16 = 284;
+const SOFTWARE: u16 = 305;
+const DATE_TIME: u16 = 306;
+const ARTIST: u16 = 315;
+const PREDICTOR: u16 = 317;
+const COLOUR_MAP: u16 = 320;
+const TILE_WIDTH: u16 = 322;
+const TILE_LENGTH: u16 = 323;
+const TILE_OFFSETS: u16 = 324;
+const TILE_BYTE_COUNTS: u16 = 325;
+const EXTRA_SAMPLES: u16 = 338;
+const SAMPLE_FORMAT: u16 = 339;
+
+const NONE: u16 = 1;
+const LZW: u16 = 5;
+const DEFLATE: u16 = 8;
+const PACKBITS: u16 = 32773;
+/* The code Adobe used before Deflate was registered */
+const OLD_DEFLATE: u16 = 32946;
+
+const WHITE_IS_ZERO: u16 = 0;
+const BLACK_IS_ZERO: u16 = 1;
+const RGB: u16 = 2;
+const PALETTE: u16 = 3;
+
+const HORIZONTAL: u16 = 2;
+const ASSOCIATED_ALPHA: u16 = 1;
+
+/* Text tags listed by the metadata */
+const TEXT_TAGS: [(u16, &str); 6] = [
+    (IMAGE_DESCRIPTION, "description"),
+    (MAKE, "make"),
+    (MODEL, "model"),
+    (SOFTWARE, "software"),
+    (DATE_TIME, "date"),
+    (ARTIST, "artist"),
+];
+
+/* One IFD. Strips are treated as tiles as wide as the image. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_sample: u16,
+    pub samples_per_pixel: u16,
+    /* 1 for unsigned integers, the only kind decoded */
+    pub sample_format: u16,
+    pub compression: u16,
+    pub photometric: u16,
+    /* Each sample in its own strips or tiles (PlanarConfiguration 2) */
+    pub planar: bool,
+    pub predictor: u16,
+    pub extra_samples: Vec<u16>,
+    /* All red values, then green, then blue */
+    pub colour_map: Vec<u16>,
+    pub tiled: bool,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub offsets: Vec<u32>,
+    pub byte_counts: Vec<u32>,
+    pub text: Vec<(String, String)>,
+}
+
+fn read_u16(data: &[u8], pos: usize, little: bool) -> u16 {
+    let bytes = [data[pos], data[pos + 1]];
+    match little {
+        true => u16::from_le_bytes(bytes),
+        false => u16::from_be_bytes(bytes),
+    }
+}
+
+fn read_u32(data: &[u8], pos: usize, little: bool) -> u32 {
+    let bytes = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+    match little {
+        true => u32::from_le_bytes(bytes),
+        false => u32::from_be_bytes(bytes),
+    }
+}
+
+/* Whether the file is little endian (II) and the offset of the first IFD */
+fn read_header(data: &[u8]) -> Result<(bool, u32), String> {
+    let little = match data.get(..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return Err("Not a TIFF file".to_string()),
+    };
+    if data.len() < HEADER_SIZE || read_u16(data, 2, little) != MAGIC {
+        return Err("Not a TIFF file".to_string());
+    }
+    Ok((little, read_u32(data, 4, little)))
+}
+
+/* A tag's type, count and values, which are inline when they fit in 4
+ * bytes */
+struct Field<'a> {
+    kind: u16,
+    count: usize,
+    data: &'a [u8],
+}
+
+impl Field<'_> {
+    /* Values of the integer types */
+    fn values(&self, little: bool) -> Result<Vec<u32>, String> {
+        let values = match self.kind {
+            1 | 6 | 7 => self.data.iter().map(|b| *b as u32).collect(),
+            3 | 8 => (0..self.count)
+                .map(|i| read_u16(self.data, i * 2, little) as u32)
+                .collect(),
+            4 | 9 | 13 => (0..self.count)
+                .map(|i| read_u32(self.data, i * 4, little))
+                .collect(),
+            k => return Err(format!("TIFF field of type {} is not an integer", k)),
+        };
+        Ok(values)
+    }
+
+    fn text(&self) -> String {
+        let text = String::from_utf8_lossy(self.data);
+        text.trim_end_matches('\0').to_string()
+    }
+}
+
+fn type_size(kind: u16) -> usize {
+    match kind {
+        3 | 8 => 2,
+        4 | 9 | 11 | 13 => 4,
+        5 | 10 | 12 => 8,
+        _ => 1,
+    }
+}
+
+/* Reads the IFD at `offset` and returns its fields and the offset of the
+ * next one */
+fn read_ifd(
+    data: &[u8],
+    offset: usize,
+    little: bool,
+) -> Result<(Vec<(u16, Field<'_>)>, u32), String> {
+    if data.len() < offset.saturating_add(2) {
+        return Err(format!("TIFF IFD at {} is outside the file", offset));
+    }
+    let count = read_u16(data, offset, little) as usize;
+    let end = offset + 2 + count * ENTRY_SIZE;
+    if data.len() < end + 4 {
+        return Err(format!("Truncated TIFF IFD at {}", offset));
+    }
+
+    let mut fields = Vec::with_capacity(count);
+    for i in 0..count {
+        let pos = offset + 2 + i * ENTRY_SIZE;
+        let tag = read_u16(data, pos, little);
+        let kind = read_u16(data, pos + 2, little);
+        let count = read_u32(data, pos + 4, little) as usize;
+        let size = count.saturating_mul(type_size(kind));
+        let start = match size <= 4 {
+            true => pos + 8,
+            false => read_u32(data, pos + 8, little) as usize,
+        };
+        let field = data
+            .get(start..start.saturating_add(size))
+            .ok_or(format!("TIFF tag {} is outside the file", tag))?;
+        fields.push((
+            tag,
+            Field {
+                kind,
+                count,
+                data: field,
+            },
+        ));
+    }
+    Ok((fields, read_u32(data, end, little)))
+}
+
+fn read_page(fields: &[(u16, Field)], little: bool) -> Result<Page, String> {
+    let get = |tag: u16| -> Result<Option<Vec<u32>>, String> {
+        match fields.iter().find(|(t, _)| *t == tag) {
+            Some((_, field)) => field.values(little).map(Some),
+            None => Ok(None),
+        }
+    };
+    let single = |tag: u16, default: Option<u32>| -> Result<u32, String> {
+        match get(tag)?.and_then(|v| v.first().copied()).or(default) {
+            Some(value) => Ok(value),
+            None => Err(format!("TIFF page has no tag {}", tag)),
+        }
+    };
+
+    let width = single(IMAGE_WIDTH, None)?;
+    let height = single(IMAGE_LENGTH, None)?;
+    let samples_per_pixel = single(SAMPLES_PER_PIXEL, Some(1))? as u16;
+    let bits = get(BITS_PER_SAMPLE)?.unwrap_or(vec![1]);
+    let bits_per_sample = *bits.first().unwrap_or(&1) as u16;
+    if bits.iter().any(|b| *b != bits_per_sample as u32) {
+        return Err("TIFF samples of different sizes are not supported".to_string());
+    }
+
+    let tiled = get(TILE_OFFSETS)?.is_some();
+    let (tile_width, tile_height, offsets, byte_counts) = match tiled {
+        true => (
+            single(TILE_WIDTH, None)?,
+            single(TILE_LENGTH, None)?,
+            get(TILE_OFFSETS)?.unwrap(),
+            get(TILE_BYTE_COUNTS)?.ok_or("TIFF page has no tile byte counts")?,
+        ),
+        false => (
+            width,
+            single(ROWS_PER_STRIP, Some(u32::MAX))?.clamp(1, height.max(1)),
+            get(STRIP_OFFSETS)?.ok_or("TIFF page has no strip offsets")?,
+            get(STRIP_BYTE_COUNTS)?.ok_or("TIFF page has no strip byte counts")?,
+        ),
+    };
+    let colour_map = get(COLOUR_MAP)?
+        .unwrap_or_default()
+        .iter()
+        .map(|v| *v as u16)
+        .collect();
+    let text = TEXT_TAGS
+        .iter()
+        .filter_map(|(tag, name)| {
+            let (_, field) = fields.iter().find(|(t, _)| t == tag)?;
+            Some((name.to_string(), field.text()))
+        })
+        .collect();
+
+    Ok(Page {
+        width,
+        height,
+        bits_per_sample,
+        samples_per_pixel,
+        sample_format: single(SAMPLE_FORMAT, Some(1))? as u16,
+        compression: single(COMPRESSION, Some(NONE as u32))? as u16,
+        photometric: single(PHOTOMETRIC, None)? as u16,
+        planar: single(PLANAR_CONFIGURATION, Some(1))? == 2,
+        predictor: single(PREDICTOR, Some(1))? as u16,
+        extra_samples: get(EXTRA_SAMPLES)?
+            .unwrap_or_default()
+            .iter()
+            .map(|v| *v as u16)
+            .collect(),
+        colour_map,
+        tiled,
+        tile_width,
+        tile_height,
+        offsets,
+        byte_counts,
+        text,
+    })
+}
+
+impl Page {
+    fn colour_channels(&self) -> Result<usize, String> {
+        match self.photometric {
+            WHITE_IS_ZERO | BLACK_IS_ZERO | PALETTE => Ok(1),
+            RGB => Ok(3),
+            p => Err(format!("Unsupported TIFF photometric interpretation {}", p)),
+        }
+    }
+
+    fn has_alpha(&self) -> Result<bool, String> {
+        Ok(self.samples_per_pixel as usize > self.colour_channels()?)
+    }
+
+    /* The format decode_page() produces: palettes are expanded to 8 bits
+     * and gray below 8 bits is scaled up */
+    pub fn pixel_format(&self) -> Result<PixelFormat, String> {
+        let colours = self.colour_channels()?;
+        if self.sample_format != 1 {
+            return Err("Only unsigned integer TIFF samples are supported".to_string());
+        }
+        if (self.samples_per_pixel as usize) < colours {
+            return Err(format!(
+                "TIFF page has {} samples per pixel",
+                self.samples_per_pixel
+            ));
+        }
+        let bits = self.bits_per_sample;
+        let valid = match self.photometric {
+            WHITE_IS_ZERO | BLACK_IS_ZERO => [1, 2, 4, 8, 16].contains(&bits),
+            PALETTE => [1, 2, 4, 8].contains(&bits),
+            _ => [8, 16].contains(&bits),
+        };
+        if !valid {
+            return Err(format!("Unsupported TIFF bit depth {}", bits));
+        }
+
+        let format = match (self.photometric, self.has_alpha()?, bits == 16) {
+            (PALETTE, false, _) => PixelFormat::Rgb8,
+            (PALETTE, true, _) => PixelFormat::Rgba8,
+            (RGB, false, false) => PixelFormat::Rgb8,
+            (RGB, true, false) => PixelFormat::Rgba8,
+            (RGB, false, true) => PixelFormat::Rgb16,
+            (RGB, true, true) => PixelFormat::Rgba16,
+            (_, false, false) => PixelFormat::L8,
+            (_, true, false) => PixelFormat::La8,
+            (_, false, true) => PixelFormat::L16,
+            (_, true, true) => PixelFormat::La16,
+        };
+        Ok(format)
+    }
+
+    /* Colour map entries scaled to 8 bits */
+    fn palette(&self) -> Vec<(u8, u8, u8, u8)> {
+        let size = self.colour_map.len() / 3;
+        let scale = |v: u16| ((v as u32 * 255 + 32767) / 65535) as u8;
+        (0..size)
+            .map(|i| {
+                (
+                    scale(self.colour_map[i]),
+                    scale(self.colour_map[size + i]),
+                    scale(self.colour_map[2 * size + i]),
+                    255,
+                )
+            })
+            .collect()
+    }
+}
+
+/* PackBits: a count n below 128 copies the next n + 1 bytes, -1 to -127
+ * repeat the next byte 1 - n times */
+fn unpack_bits(data: &[u8], limit: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while out.len() < limit && pos < data.len() {
+        let n = data[pos] as i8;
+        pos += 1;
+        match n {
+            0.. => {
+                let end = (pos + n as usize + 1).min(data.len());
+                out.extend_from_slice(&data[pos..end]);
+                pos = end;
+            }
+            -127..=-1 => {
+                if let Some(b) = data.get(pos) {
+                    out.resize(out.len() + (1 - n as isize) as usize, *b);
+                    pos += 1;
+                }
+            }
+            -128 => {}
+        }
+    }
+    out
+}
+
+/* LZW as used by TIFF: codes packed MSB first, 256 clears the table, 257
+ * ends the data and the code width grows one code earlier than in GIF */
+fn lzw_decode(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    const CLEAR: usize = 256;
+    const END: usize = 257;
+    let mut prefix = [0u16; MAX_CODES];
+    let mut suffix = [0u8; MAX_CODES];
+    let mut first = [0u8; MAX_CODES];
+    let mut length = [0u16; MAX_CODES];
+    for i in 0..CLEAR {
+        suffix[i] = i as u8;
+        first[i] = i as u8;
+        length[i] = 1;
+    }
+
+    let mut out = Vec::new();
+    let mut width = 9;
+    let mut next = END + 1;
+    let mut previous: Option<usize> = None;
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    let mut bytes = data.iter();
+
+    while out.len() < limit {
+        while bits < width {
+            match bytes.next() {
+                Some(b) => {
+                    buffer = buffer << 8 | *b as u32;
+                    bits += 8;
+                }
+                None => return Ok(out),
+            }
+        }
+        let code = ((buffer >> (bits - width)) & ((1 << width) - 1)) as usize;
+        bits -= width;
+
+        if code == CLEAR {
+            width = 9;
+            next = END + 1;
+            previous = None;
+            continue;
+        }
+        if code == END {
+            break;
+        }
+
+        match previous {
+            None if code < CLEAR => {}
+            None => return Err(format!("Invalid first LZW code {}", code)),
+            Some(p) => {
+                let k = if code < next {
+                    first[code]
+                } else if code == next {
+                    first[p]
+                } else {
+                    return Err(format!("Invalid LZW code {}", code));
+                };
+                if next < MAX_CODES {
+                    prefix[next] = p as u16;
+                    suffix[next] = k;
+                    first[next] = first[p];
+                    length[next] = length[p] + 1;
+                    next += 1;
+                    if next + 1 == 1 << width && width < 12 {
+                        width += 1;
+                    }
+                }
+            }
+        }
+
+        let start = out.len();
+        out.resize(start + length[code] as usize, 0);
+        let mut c = code;
+        for i in (start..out.len()).rev() {
+            out[i] = suffix[c];
+            c = prefix[c] as usize;
+        }
+        previous = Some(code);
+    }
+
+    out.truncate(limit);
+    Ok(out)
+}
+
+/* Decompresses one strip or tile, which must hold at least `size` bytes */
+fn decompress(compression: u16, data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let mut out = match compression {
+        NONE => data.to_vec(),
+        LZW => lzw_decode(data, size)?,
+        DEFLATE | OLD_DEFLATE => zlib::parse(&mut VecDeque::from(data.to_vec()))?,
+        PACKBITS => unpack_bits(data, size),
+        c => return Err(format!("Unsupported TIFF compression {}", c)),
+    };
+    if out.len() < size {
+        return Err(format!(
+            "TIFF data is {} bytes, expected {}",
+            out.len(),
+            size
+        ));
+    }
+    out.truncate(size);
+    Ok(out)
+}
+
+/* Splits a row into samples, 16-bit ones in the file's byte order and
+ * smaller ones MSB first */
+fn unpack_row(row: &[u8], bits: u16, count: usize, little: bool) -> Vec<u16> {
+    match bits {
+        16 => (0..count).map(|i| read_u16(row, i * 2, little)).collect(),
+        8 => row[..count].iter().map(|b| *b as u16).collect(),
+        _ => {
+            let per_byte = 8 / bits as usize;
+            let mask = (1 << bits) - 1;
+            (0..count)
+                .map(|i| {
+                    let shift = 8 - bits as usize * (i % per_byte + 1);
+                    (row[i / per_byte] as u16 >> shift) & mask
+                })
+                .collect()
+        }
+    }
+}
+
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    little: bool,
+    pages: Vec<Page>,
+}
+
+impl<'a> Decoder<'a> {
+    /* Reads every IFD in the chain */
+    pub fn new(data: &'a [u8]) -> Result<Decoder<'a>, String> {
+        let (little, mut offset) = read_header(data)?;
+        let mut visited = Vec::new();
+        let mut pages = Vec::new();
+        while offset != 0 {
+            if visited.contains(&offset) || visited.len() == MAX_PAGES {
+                return Err("TIFF IFDs form a loop".to_string());
+            }
+            visited.push(offset);
+            let (fields, next) = read_ifd(data, offset as usize, little)?;
+            pages.push(read_page(&fields, little)?);
+            offset = next;
+        }
+        if pages.is_empty() {
+            return Err("TIFF file has no pages".to_string());
+        }
+        Ok(Decoder {
+            data,
+            little,
+            pages,
+        })
+    }
+
+    pub fn is_little_endian(&self) -> bool {
+        self.little
+    }
+
+    pub fn pages(&self) -> &[Page] {
+        &self.pages
+    }
+
+    pub fn decode_page(&self, index: usize) -> Result<Image, String> {
+        let page = self
+            .pages
+            .get(index)
+            .ok_or(format!("TIFF file has no page {}", index))?;
+        let format = page.pixel_format()?;
+        let (width, height) = (page.width as usize, page.height as usize);
+        let spp = page.samples_per_pixel as usize;
+        let bits = page.bits_per_sample;
+        if page.predictor == HORIZONTAL && bits < 8 {
+            return Err("The TIFF predictor needs 8 or 16-bit samples".to_string());
+        }
+        if page.predictor != 1 && page.predictor != HORIZONTAL {
+            return Err(format!("Unsupported TIFF predictor {}", page.predictor));
+        }
+        if page.photometric == PALETTE && page.colour_map.len() != 3 << bits {
+            return Err("TIFF colour map has the wrong size".to_string());
+        }
+
+        /* Every sample of every pixel, whatever the bit depth */
+        let mut samples = Vec::new();
+        let len = width
+            .checked_mul(height)
+            .and_then(|n| n.checked_mul(spp))
+            .ok_or("TIFF image is too large")?;
+        samples
+            .try_reserve_exact(len)
+            .map_err(|_| "TIFF image is too large".to_string())?;
+        samples.resize(len, 0u16);
+
+        let (planes, chunk_spp) = match page.planar {
+            true => (spp, 1),
+            false => (1, spp),
+        };
+        let (tile_width, tile_height) = (page.tile_width as usize, page.tile_height as usize);
+        /* Tiles are multiples of 16, none needs to be larger than that */
+        if tile_width == 0
+            || tile_height == 0
+            || tile_width > width.next_multiple_of(16)
+            || tile_height > height.next_multiple_of(16)
+        {
+            return Err(format!(
+                "Invalid TIFF tile size {}x{}",
+                tile_width, tile_height
+            ));
+        }
+        let across = width.div_ceil(tile_width);
+        let down = height.div_ceil(tile_height);
+        let per_plane = across * down;
+        if page.offsets.len() < per_plane * planes || page.byte_counts.len() < page.offsets.len() {
+            return Err("TIFF page is missing strips or tiles".to_string());
+        }
+
+        let row_samples = tile_width * chunk_spp;
+        let row_bytes = (row_samples * bits as usize).div_ceil(8);
+        let chunk_len = |rows: usize| {
+            rows.checked_mul(row_bytes)
+                .ok_or("TIFF tiles are too large")
+        };
+        let mask = ((1u32 << bits) - 1) as u16;
+        for i in 0..per_plane * planes {
+            let (plane, tile) = (i / per_plane, i % per_plane);
+            let (x0, y0) = ((tile % across) * tile_width, (tile / across) * tile_height);
+            /* The last strip stops at the bottom, tiles are always whole */
+            let rows = match page.tiled {
+                true => tile_height,
+                false => tile_height.min(height - y0),
+            };
+            let start = page.offsets[i] as usize;
+            let stored = self
+                .data
+                .get(start..start.saturating_add(page.byte_counts[i] as usize))
+                .ok_or(format!("TIFF strip or tile {} is outside the file", i))?;
+            let chunk = decompress(page.compression, stored, chunk_len(rows)?)?;
+
+            for (y, row) in chunk.chunks(row_bytes).enumerate().take(height - y0) {
+                let mut row = unpack_row(row, bits, row_samples, self.little);
+                if page.predictor == HORIZONTAL {
+                    for j in chunk_spp..row.len() {
+                        row[j] = row[j].wrapping_add(row[j - chunk_spp]) & mask;
+                    }
+                }
+                let columns = tile_width.min(width - x0);
+                let dst = ((y0 + y) * width + x0) * spp + plane;
+                for x in 0..columns {
+                    for s in 0..chunk_spp {
+                        samples[dst + x * spp + s] = row[x * chunk_spp + s];
+                    }
+                }
+            }
+        }
+
+        Ok(to_image(page, format, &samples))
+    }
+}
+
+/* Turns the samples into pixels of `format`: palette lookup, inverted
+ * WhiteIsZero gray, 8-bit scaling and unassociated alpha */
+fn to_image(page: &Page, format: PixelFormat, samples: &[u16]) -> Image {
+    let spp = page.samples_per_pixel as usize;
+    let colours = page.colour_channels().unwrap();
+    let alpha = format.has_alpha();
+    let max = ((1u32 << page.bits_per_sample) - 1) as u16;
+    let associated = page.extra_samples.first() == Some(&ASSOCIATED_ALPHA);
+    let palette = page.palette();
+
+    let mut out = Vec::with_capacity(samples.len() / spp * format.channels());
+    for px in samples.chunks(spp) {
+        let a = px.get(colours).copied().unwrap_or(max);
+        if page.photometric == PALETTE {
+            let (r, g, b, _) = palette[px[0] as usize];
+            out.extend_from_slice(&[r as u16, g as u16, b as u16]);
+        } else {
+            for c in &px[..colours] {
+                let mut c = match page.photometric {
+                    WHITE_IS_ZERO => max - *c,
+                    _ => *c,
+                };
+                /* Associated alpha is already multiplied into the colour */
+                if alpha && associated && a != 0 {
+                    let unscaled = (c as u32 * max as u32 + a as u32 / 2) / a as u32;
+                    c = unscaled.min(max as u32) as u16;
+                }
+                out.push(c);
+            }
+        }
+        /* Palette colours are already 8-bit, so the alpha is scaled here */
+        match (alpha, page.photometric) {
+            (false, _) => {}
+            (true, PALETTE) => out.push((a as u32 * 255 / max as u32) as u16),
+            (true, _) => out.push(a),
+        }
+    }
+
+    let data = match format.bytes_per_sample() {
+        2 => PixelData::U16(out),
+        _ if page.photometric == PALETTE => PixelData::U8(out.iter().map(|v| *v as u8).collect()),
+        _ => PixelData::U8(
+            out.iter()
+                .map(|v| (*v as u32 * 255 / max as u32) as u8)
+                .collect(),
+        ),
+    };
+    Image {
+        width: page.width,
+        height: page.height,
+        format,
+        data,
+    }
+}
+
+/* Decodes the first page */
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    Decoder::new(data)?.decode_page(0)
+}
+
+/* IFDs may be anywhere, so this reads the whole file */
+pub fn probe<R: Read>(mut reader: R) -> Result<ImageInfo, String> {
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Reading TIFF: {}", e))?;
+    let decoder = Decoder::new(&data)?;
+    let page = &decoder.pages()[0];
+
+    let mut info = ImageInfo::new(page.width, page.height, page.pixel_format()?);
+    info.bit_depth = page.bits_per_sample as u8;
+    if page.photometric == PALETTE {
+        info.palette = Some(page.palette());
+    }
+    info.text = page.text.clone();
+    Ok(info)
+}
+
+fn compression_name(compression: u16) -> String {
+    match compression {
+        NONE => "none".to_string(),
+        LZW => "LZW".to_string(),
+        DEFLATE | OLD_DEFLATE => "Deflate".to_string(),
+        PACKBITS => "PackBits".to_string(),
+        c => format!("unsupported ({})", c),
+    }
+}
+
+pub struct TiffDecoder {
+    data: Vec<u8>,
+}
+
+impl TiffDecoder {
+    pub fn new(data: Vec<u8>) -> TiffDecoder {
+        TiffDecoder { data }
+    }
+}
+
+impl ImageDecoder for TiffDecoder {
+    fn info(&mut self) -> Result<ImageInfo, String> {
+        probe(self.data.as_slice())
+    }
+
+    fn decode(&mut self) -> Result<Image, String> {
+        decode(&self.data)
+    }
+
+    fn metadata(&self) -> Vec<(String, String)> {
+        let decoder = match Decoder::new(&self.data) {
+            Ok(decoder) => decoder,
+            Err(_) => return Vec::new(),
+        };
+        let order = match decoder.is_little_endian() {
+            true => "little endian",
+            false => "big endian",
+        };
+        let mut entries = vec![("byte order".to_string(), order.to_string())];
+        for (i, page) in decoder.pages().iter().enumerate() {
+            let mut value = format!(
+                "{}x{}, {}x{} bits, {}",
+                page.width,
+                page.height,
+                page.samples_per_pixel,
+                page.bits_per_sample,
+                compression_name(page.compression)
+            );
+            if page.predictor == HORIZONTAL {
+                value += ", predictor";
+            }
+            if page.tiled {
+                value += &format!(", {}x{} tiles", page.tile_width, page.tile_height);
+            }
+            if page.planar {
+                value += ", planar";
+            }
+            entries.push((format!("page {}", i), value));
+        }
+        entries.extend(decoder.pages()[0].text.iter().cloned());
+        entries
+    }
+}
+
+#[test]
+fn test_decode_tiff() {
+    /* Each one uses another compression, byte order or layout */
+    for (file, name) in [
+        ("basn2c08_lzw", "basn2c08"),
+        ("basn0g16_deflate", "basn0g16"),
+        ("basn3p04_palette", "basn3p04"),
+        ("basn0g01_white", "basn0g01"),
+    ] {
+        let data = std::fs::read(format!("tests/tiff/{}.tiff", file)).unwrap();
+        let img = decode(&data).unwrap();
+        let expected = crate::open(format!("tests/png_testsuite/{}.png", name)).unwrap();
+        assert_eq!(img, expected.convert(img.format), "{}", file);
+        let info = probe(data.as_slice()).unwrap();
+        assert_eq!(info.pixel_format, img.format);
+    }
+
+    /* Tiles past the right and bottom edges are cut off */
+    let data = std::fs::read("tests/tiff/basn6a08_packbits.tiff").unwrap();
+    let img = decode(&data).unwrap();
+    let expected = crate::open("tests/png_testsuite/basn6a08.png").unwrap();
+    assert_eq!(
+        (img.width, img.height, img.format),
+        (30, 27, PixelFormat::Rgba8)
+    );
+    for i in 0..img.num_pixels() {
+        let (x, y) = (i % 30, i / 30);
+        assert_eq!(img.get_rgba16(i), expected.get_rgba16(y * 32 + x));
+    }
+
+    /* The example from the TIFF specification */
+    let packed = [
+        0xfe, 0xaa, 0x02, 0x80, 0x00, 0x2a, 0xfd, 0xaa, 0x03, 0x80, 0x00, 0x2a, 0x22, 0xf7, 0xaa,
+    ];
+    let mut expected = vec![0xaa; 3];
+    expected.extend_from_slice(&[0x80, 0x00, 0x2a, 0xaa, 0xaa, 0xaa, 0xaa]);
+    expected.extend_from_slice(&[0x80, 0x00, 0x2a, 0x22]);
+    expected.extend_from_slice(&[0xaa; 10]);
+    assert_eq!(unpack_bits(&packed, 100), expected);
+}
+
+#[test]
+fn test_tiff_pages() {
+    let data = std::fs::read("tests/tiff/multi.tiff").unwrap();
+    let decoder = Decoder::new(&data).unwrap();
+    assert!(decoder.is_little_endian());
+    let pages: Vec<_> = decoder
+        .pages()
+        .iter()
+        .map(|p| (p.compression, p.photometric, p.planar))
+        .collect();
+    assert_eq!(
+        pages,
+        vec![
+            (DEFLATE, RGB, true),
+            (LZW, PALETTE, false),
+            (NONE, BLACK_IS_ZERO, false)
+        ]
+    );
+    for (i, name) in ["basn2c08", "basn3p08", "basn0g08"].iter().enumerate() {
+        let img = decoder.decode_page(i).unwrap();
+        let expected = crate::open(format!("tests/png_testsuite/{}.png", name)).unwrap();
+        assert_eq!(img, expected.convert(img.format), "page {}", i);
+    }
+    assert!(decoder.decode_page(3).is_err());
+
+    /* Tiles may not be much larger than the image */
+    let mut tiled = std::fs::read("tests/tiff/basn0g16_deflate.tiff").unwrap();
+    let entry = tiled
+        .windows(8)
+        .position(|e| e == [0x01, 0x42, 0, 3, 0, 0, 0, 1])
+        .unwrap();
+    tiled[entry + 8..entry + 10].copy_from_slice(&[0x40, 0]);
+    assert!(decode(&tiled).is_err());
+
+    /* A truncated strip is an error, not a short image */
+    assert!(decode(&data[..data.len() / 2]).is_err());
+
+    let mut decoder = TiffDecoder::new(std::fs::read("tests/tiff/python.tiff").unwrap());
+    let img = decoder.decode().unwrap();
+    assert_eq!(
+        (img.width, img.height, img.format),
+        (16, 16, PixelFormat::Rgba8)
+    );
+    let metadata = decoder.metadata();
+    assert_eq!(metadata[1].1, "16x16, 4x8 bits, none");
+}
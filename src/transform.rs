@@ -0,0 +1,274 @@
+/* Sample transformations modelled on libpng's png_set_* functions. They are
+ * applied in the order they are given, to every pixel while decoding. */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    /* Scale 1, 2 and 4-bit grayscale up to 8 bits */
+    ExpandGray,
+    /* Drop the low byte of 16-bit samples */
+    Strip16,
+    StripAlpha,
+    /* Replicate gray into red, green and blue, expanding sub-byte gray first */
+    GrayToRgb,
+    /* Weighted sum of red, green and blue, the weights should add up to 1 */
+    RgbToGray { red: f32, green: f32, blue: f32 },
+    /* Store colour as blue, green, red */
+    Bgr,
+    /* Move the alpha channel in front of the colour channels */
+    SwapAlpha,
+    /* Turn gray samples into max - value, so 0 becomes white */
+    InvertMono,
+    /* Add a constant channel to images without alpha, expanding sub-byte gray first */
+    Filler { value: u16, first: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Gray,
+    Red,
+    Green,
+    Blue,
+    Alpha,
+    Filler,
+}
+
+/* Bit depth and channel order of a pixel */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub depth: u8,
+    pub channels: Vec<Channel>,
+}
+
+/* Decoded scanlines in the layout produced by the transforms. Samples below
+ * 8 bits are packed starting at the most significant bit, 16-bit samples are
+ * stored big endian, just like in a PNG file. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransformedImage {
+    pub width: u32,
+    pub height: u32,
+    pub layout: Layout,
+    /* Bytes per row */
+    pub stride: usize,
+    pub data: Vec<u8>,
+}
+
+impl Layout {
+    fn position(&self, channel: Channel) -> Option<usize> {
+        self.channels.iter().position(|c| *c == channel)
+    }
+
+    fn max(&self) -> u32 {
+        (1 << self.depth) - 1
+    }
+
+    pub fn bits_per_pixel(&self) -> usize {
+        self.depth as usize * self.channels.len()
+    }
+}
+
+fn expand_gray(layout: &mut Layout, px: &mut [u16]) {
+    if layout.depth < 8 && layout.position(Channel::Gray).is_some() {
+        let max = layout.max();
+        for v in px.iter_mut() {
+            *v = (*v as u32 * 255 / max) as u16;
+        }
+        layout.depth = 8;
+    }
+}
+
+impl Transform {
+    /* Applies the transform to a single pixel whose samples follow `layout`,
+     * updating the layout to describe the result */
+    fn apply(&self, layout: &mut Layout, px: &mut Vec<u16>) {
+        match *self {
+            Transform::ExpandGray => expand_gray(layout, px),
+            Transform::Strip16 => {
+                if layout.depth == 16 {
+                    for v in px.iter_mut() {
+                        *v >>= 8;
+                    }
+                    layout.depth = 8;
+                }
+            }
+            Transform::StripAlpha => {
+                if let Some(i) = layout.position(Channel::Alpha) {
+                    px.remove(i);
+                    layout.channels.remove(i);
+                }
+            }
+            Transform::GrayToRgb => {
+                expand_gray(layout, px);
+                if let Some(i) = layout.position(Channel::Gray) {
+                    let v = px[i];
+                    px.splice(i..=i, [v, v, v].iter().cloned());
+                    layout.channels.splice(
+                        i..=i,
+                        [Channel::Red, Channel::Green, Channel::Blue]
+                            .iter()
+                            .cloned(),
+                    );
+                }
+            }
+            Transform::RgbToGray { red, green, blue } => {
+                if let Some(i) = layout.position(Channel::Red) {
+                    let g = layout.position(Channel::Green).unwrap();
+                    let b = layout.position(Channel::Blue).unwrap();
+                    let v = px[i] as f32 * red + px[g] as f32 * green + px[b] as f32 * blue;
+                    let v = (v + 0.5).clamp(0.0, layout.max() as f32) as u16;
+                    /* The colour channels are always adjacent */
+                    let start = i.min(b);
+                    px.splice(start..start + 3, [v].iter().cloned());
+                    layout
+                        .channels
+                        .splice(start..start + 3, [Channel::Gray].iter().cloned());
+                }
+            }
+            Transform::Bgr => {
+                if let (Some(r), Some(b)) = (
+                    layout.position(Channel::Red),
+                    layout.position(Channel::Blue),
+                ) {
+                    px.swap(r, b);
+                    layout.channels.swap(r, b);
+                }
+            }
+            Transform::SwapAlpha => {
+                if let Some(i) = layout.position(Channel::Alpha) {
+                    let a = px.remove(i);
+                    px.insert(0, a);
+                    layout.channels.remove(i);
+                    layout.channels.insert(0, Channel::Alpha);
+                }
+            }
+            Transform::InvertMono => {
+                if let Some(i) = layout.position(Channel::Gray) {
+                    px[i] = (layout.max() - px[i] as u32) as u16;
+                }
+            }
+            Transform::Filler { value, first } => {
+                if layout.position(Channel::Alpha).is_none()
+                    && layout.position(Channel::Filler).is_none()
+                {
+                    expand_gray(layout, px);
+                    let value = (value as u32 & layout.max()) as u16;
+                    let i = if first { 0 } else { px.len() };
+                    px.insert(i, value);
+                    layout.channels.insert(i, Channel::Filler);
+                }
+            }
+        }
+    }
+}
+
+/* Writes transformed pixels into packed scanlines */
+pub struct TransformWriter {
+    transforms: Vec<Transform>,
+    input: Layout,
+    output: TransformedImage,
+    px: Vec<u16>,
+}
+
+impl TransformWriter {
+    pub fn new(
+        width: u32,
+        height: u32,
+        input: Layout,
+        transforms: &[Transform],
+    ) -> TransformWriter {
+        /* Run the chain once on a dummy pixel to find the output layout */
+        let mut layout = input.clone();
+        let mut px = vec![0; layout.channels.len()];
+        for t in transforms.iter() {
+            t.apply(&mut layout, &mut px);
+        }
+
+        let stride = (width as usize * layout.bits_per_pixel()).div_ceil(8);
+        TransformWriter {
+            transforms: transforms.to_vec(),
+            input,
+            output: TransformedImage {
+                width,
+                height,
+                layout,
+                stride,
+                data: vec![0; stride * height as usize],
+            },
+            px: Vec::with_capacity(4),
+        }
+    }
+
+    /* Transforms the samples of the pixel at `index` and stores the result */
+    pub fn write(&mut self, index: usize, samples: &[u16]) {
+        let mut layout = self.input.clone();
+        self.px.clear();
+        self.px.extend_from_slice(samples);
+        for t in self.transforms.iter() {
+            t.apply(&mut layout, &mut self.px);
+        }
+
+        let width = self.output.width as usize;
+        let (y, x) = (index / width, index % width);
+        let depth = layout.depth as usize;
+        let row = &mut self.output.data[y * self.output.stride..(y + 1) * self.output.stride];
+        for (c, v) in self.px.iter().enumerate() {
+            let bit = (x * self.px.len() + c) * depth;
+            match depth {
+                16 => {
+                    row[bit / 8] = (v >> 8) as u8;
+                    row[bit / 8 + 1] = *v as u8;
+                }
+                8 => row[bit / 8] = *v as u8,
+                _ => row[bit / 8] |= (*v as u8) << (8 - depth - bit % 8),
+            }
+        }
+    }
+
+    pub fn finish(self) -> TransformedImage {
+        self.output
+    }
+}
+
+#[test]
+fn test_transform_chain() {
+    let input = Layout {
+        depth: 16,
+        channels: vec![Channel::Red, Channel::Green, Channel::Blue, Channel::Alpha],
+    };
+    let transforms = [Transform::Strip16, Transform::Bgr, Transform::SwapAlpha];
+    let mut writer = TransformWriter::new(1, 1, input, &transforms);
+    writer.write(0, &[0x1234, 0x5678, 0x9abc, 0xdef0]);
+    let img = writer.finish();
+
+    assert_eq!(img.layout.depth, 8);
+    assert_eq!(
+        img.layout.channels,
+        vec![Channel::Alpha, Channel::Blue, Channel::Green, Channel::Red]
+    );
+    assert_eq!(img.data, vec![0xde, 0x9a, 0x56, 0x12]);
+}
+
+#[test]
+fn test_transform_gray() {
+    let input = Layout {
+        depth: 1,
+        channels: vec![Channel::Gray],
+    };
+    let mut writer = TransformWriter::new(3, 1, input.clone(), &[Transform::InvertMono]);
+    for (i, v) in [1, 0, 1].iter().enumerate() {
+        writer.write(i, &[*v]);
+    }
+    assert_eq!(writer.finish().data, vec![0b0100_0000]);
+
+    let transforms = [
+        Transform::GrayToRgb,
+        Transform::Filler {
+            value: 0xff,
+            first: false,
+        },
+    ];
+    let mut writer = TransformWriter::new(1, 1, input, &transforms);
+    writer.write(0, &[1]);
+    let img = writer.finish();
+    assert_eq!(img.layout.channels.len(), 4);
+    assert_eq!(img.data, vec![255, 255, 255, 255]);
+}
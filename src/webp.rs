@@ -0,0 +1,1137 @@
+/* WebP: the RIFF container with its extended (VP8X) chunks, and decoding of
+ * the lossless VP8L bitstream. Lossy VP8 frames are listed but not decoded. */
+use std::io::Read;
+
+use crate::codec::{ImageDecoder, ImageInfo};
+use crate::image::{Image, PixelData, PixelFormat};
+use crate::zlib;
+
+const VP8L_SIGNATURE: u8 = 0x2f;
+const MAX_CODE_LENGTH: u32 = 15;
+const PRIMARY_BITS: u32 = 8;
+const NUM_LENGTH_CODES: usize = 24;
+const NUM_DISTANCE_CODES: usize = 40;
+const CODE_LENGTH_CODES: usize = 19;
+const CODE_LENGTH_ORDER: [usize; CODE_LENGTH_CODES] = [
+    17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/* (x, y) offsets of the 120 short distance codes, nearest pixels first */
+#[rustfmt::skip]
+const DISTANCE_MAP: [(i8, i8); 120] = [
+    (0, 1), (1, 0), (1, 1), (-1, 1), (0, 2), (2, 0), (1, 2), (-1, 2),
+    (2, 1), (-2, 1), (2, 2), (-2, 2), (0, 3), (3, 0), (1, 3), (-1, 3),
+    (3, 1), (-3, 1), (2, 3), (-2, 3), (3, 2), (-3, 2), (0, 4), (4, 0),
+    (1, 4), (-1, 4), (4, 1), (-4, 1), (3, 3), (-3, 3), (2, 4), (-2, 4),
+    (4, 2), (-4, 2), (0, 5), (3, 4), (-3, 4), (4, 3), (-4, 3), (5, 0),
+    (1, 5), (-1, 5), (5, 1), (-5, 1), (2, 5), (-2, 5), (5, 2), (-5, 2),
+    (4, 4), (-4, 4), (3, 5), (-3, 5), (5, 3), (-5, 3), (0, 6), (6, 0),
+    (1, 6), (-1, 6), (6, 1), (-6, 1), (2, 6), (-2, 6), (6, 2), (-6, 2),
+    (4, 5), (-4, 5), (5, 4), (-5, 4), (3, 6), (-3, 6), (6, 3), (-6, 3),
+    (0, 7), (7, 0), (1, 7), (-1, 7), (5, 5), (-5, 5), (7, 1), (-7, 1),
+    (4, 6), (-4, 6), (6, 4), (-6, 4), (2, 7), (-2, 7), (7, 2), (-7, 2),
+    (3, 7), (-3, 7), (7, 3), (-7, 3), (5, 6), (-5, 6), (6, 5), (-6, 5),
+    (8, 0), (4, 7), (-4, 7), (7, 4), (-7, 4), (8, 1), (8, 2), (6, 6),
+    (-6, 6), (8, 3), (5, 7), (-5, 7), (7, 5), (-7, 5), (8, 4), (6, 7),
+    (-6, 7), (7, 6), (-7, 6), (8, 5), (7, 7), (-7, 7), (8, 6), (8, 7),
+];
+
+/* Bits are read starting at the least significant bit of each byte. Reading
+ * past the end gives zeros, which is reported by check(). */
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u64,
+    num_bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            buffer: 0,
+            num_bits: 0,
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.num_bits <= 56 {
+            let byte = self.data.get(self.pos).copied().unwrap_or(0);
+            self.buffer |= (byte as u64) << self.num_bits;
+            self.num_bits += 8;
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self, n: u32) -> u32 {
+        if self.num_bits < n {
+            self.fill();
+        }
+        (self.buffer & ((1 << n) - 1)) as u32
+    }
+
+    fn consume(&mut self, n: u32) {
+        self.buffer >>= n;
+        self.num_bits -= n;
+    }
+
+    fn read(&mut self, n: u32) -> u32 {
+        let value = self.peek(n);
+        self.consume(n);
+        value
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match self.pos * 8 - self.num_bits as usize > self.data.len() * 8 {
+            true => Err("Truncated VP8L data".to_string()),
+            false => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct TableEntry {
+    /* The symbol, or the offset of the second level table */
+    value: u16,
+    bits: u8,
+    /* Nonzero when the entry points to a second level table */
+    sub_bits: u8,
+}
+
+/* Table driven decoding of a canonical Huffman code: codes up to
+ * PRIMARY_BITS long are looked up directly, longer ones through a second
+ * table for their first PRIMARY_BITS bits */
+struct HuffmanCode {
+    table: Vec<TableEntry>,
+}
+
+impl HuffmanCode {
+    fn new(lengths: &[u32]) -> Result<HuffmanCode, String> {
+        let used: Vec<usize> = (0..lengths.len()).filter(|i| lengths[*i] > 0).collect();
+        match used.len() {
+            0 => return Err("Huffman code without symbols".to_string()),
+            /* A single symbol takes no bits at all */
+            1 => {
+                let entry = TableEntry {
+                    value: used[0] as u16,
+                    ..Default::default()
+                };
+                return Ok(HuffmanCode {
+                    table: vec![entry; 1 << PRIMARY_BITS],
+                });
+            }
+            _ => {}
+        }
+        let space: u32 = used
+            .iter()
+            .map(|i| 1 << (MAX_CODE_LENGTH - lengths[*i]))
+            .sum();
+        if space != 1 << MAX_CODE_LENGTH {
+            return Err("Incomplete or oversubscribed Huffman code".to_string());
+        }
+
+        let codes = zlib::canonical_codes(lengths);
+        let reversed = |i: usize| (codes[i].reverse_bits() >> (16 - lengths[i])) as usize;
+        let mut table = vec![TableEntry::default(); 1 << PRIMARY_BITS];
+        let mut sub_bits = vec![0; 1 << PRIMARY_BITS];
+        for &i in used.iter().filter(|i| lengths[**i] > PRIMARY_BITS) {
+            let prefix = reversed(i) & ((1 << PRIMARY_BITS) - 1);
+            sub_bits[prefix] = sub_bits[prefix].max(lengths[i] - PRIMARY_BITS);
+        }
+        for (prefix, bits) in sub_bits.iter().enumerate().filter(|(_, b)| **b > 0) {
+            table[prefix] = TableEntry {
+                value: table.len() as u16,
+                bits: 0,
+                sub_bits: *bits as u8,
+            };
+            table.resize(table.len() + (1 << bits), TableEntry::default());
+        }
+
+        for &i in used.iter() {
+            let (code, len) = (reversed(i), lengths[i]);
+            let entry = TableEntry {
+                value: i as u16,
+                bits: len as u8,
+                sub_bits: 0,
+            };
+            if len <= PRIMARY_BITS {
+                for index in (code..1 << PRIMARY_BITS).step_by(1 << len) {
+                    table[index] = entry;
+                }
+            } else {
+                let sub = table[code & ((1 << PRIMARY_BITS) - 1)];
+                let start = sub.value as usize;
+                let step = 1 << (len - PRIMARY_BITS);
+                for index in ((code >> PRIMARY_BITS)..1 << sub.sub_bits).step_by(step) {
+                    table[start + index] = entry;
+                }
+            }
+        }
+        Ok(HuffmanCode { table })
+    }
+
+    fn read(&self, reader: &mut BitReader) -> u16 {
+        let bits = reader.peek(MAX_CODE_LENGTH) as usize;
+        let mut entry = self.table[bits & ((1 << PRIMARY_BITS) - 1)];
+        if entry.sub_bits > 0 {
+            let index = (bits >> PRIMARY_BITS) & ((1 << entry.sub_bits) - 1);
+            entry = self.table[entry.value as usize + index];
+        }
+        reader.consume(entry.bits as u32);
+        entry.value
+    }
+}
+
+fn read_huffman_code(reader: &mut BitReader, alphabet_size: usize) -> Result<HuffmanCode, String> {
+    let mut lengths = vec![0; alphabet_size];
+
+    /* One or two symbols of length 1 */
+    if reader.read(1) == 1 {
+        let num_symbols = reader.read(1) + 1;
+        let first_bits = match reader.read(1) {
+            1 => 8,
+            _ => 1,
+        };
+        let mut symbols = vec![reader.read(first_bits) as usize];
+        if num_symbols == 2 {
+            symbols.push(reader.read(8) as usize);
+        }
+        for symbol in symbols {
+            *lengths
+                .get_mut(symbol)
+                .ok_or(format!("Huffman symbol {} out of range", symbol))? = 1;
+        }
+        return HuffmanCode::new(&lengths);
+    }
+
+    let mut code_length_lengths = [0; CODE_LENGTH_CODES];
+    let num_code_lengths = 4 + reader.read(4) as usize;
+    for i in CODE_LENGTH_ORDER.iter().take(num_code_lengths) {
+        code_length_lengths[*i] = reader.read(3);
+    }
+    let code_length_code = HuffmanCode::new(&code_length_lengths)?;
+
+    let mut max_symbol = match reader.read(1) {
+        1 => {
+            let length_bits = 2 + 2 * reader.read(3);
+            let max_symbol = 2 + reader.read(length_bits) as usize;
+            if max_symbol > alphabet_size {
+                return Err(format!("Invalid Huffman code length count {}", max_symbol));
+            }
+            max_symbol
+        }
+        _ => alphabet_size,
+    };
+
+    /* Repeats of 16 copy the last nonzero length, 8 before there is one */
+    let (mut symbol, mut previous) = (0, 8);
+    while symbol < alphabet_size && max_symbol > 0 {
+        max_symbol -= 1;
+        let code = code_length_code.read(reader) as u32;
+        if code < 16 {
+            lengths[symbol] = code;
+            symbol += 1;
+            if code != 0 {
+                previous = code;
+            }
+            continue;
+        }
+        let (length, repeat) = match code {
+            16 => (previous, 3 + reader.read(2)),
+            17 => (0, 3 + reader.read(3)),
+            _ => (0, 11 + reader.read(7)),
+        };
+        let end = symbol + repeat as usize;
+        if end > alphabet_size {
+            return Err("Huffman code lengths overflow the alphabet".to_string());
+        }
+        lengths[symbol..end].fill(length);
+        symbol = end;
+    }
+    reader.check()?;
+    HuffmanCode::new(&lengths)
+}
+
+/* Lengths and distances: the first four prefix codes are the values 1 to 4,
+ * the others add extra bits to a base */
+fn read_prefixed(reader: &mut BitReader, prefix: u16) -> usize {
+    if prefix < 4 {
+        return prefix as usize + 1;
+    }
+    let extra_bits = (prefix as u32 - 2) >> 1;
+    let offset = (2 + (prefix as usize & 1)) << extra_bits;
+    offset + reader.read(extra_bits) as usize + 1
+}
+
+fn div_round_up(value: usize, bits: u32) -> usize {
+    (value + (1 << bits) - 1) >> bits
+}
+
+/* The five codes for green (with lengths and cache indices), red, blue,
+ * alpha and distances */
+type HuffmanGroup = Vec<HuffmanCode>;
+
+fn read_huffman_group(reader: &mut BitReader, cache_bits: u32) -> Result<HuffmanGroup, String> {
+    let cache_size = match cache_bits {
+        0 => 0,
+        bits => 1 << bits,
+    };
+    [
+        256 + NUM_LENGTH_CODES + cache_size,
+        256,
+        256,
+        256,
+        NUM_DISTANCE_CODES,
+    ]
+    .iter()
+    .map(|size| read_huffman_code(reader, *size))
+    .collect()
+}
+
+/* Decodes an image stream: the main image of a VP8L bitstream (level 0,
+ * which may use meta Huffman codes) or one of the images holding transform
+ * data and entropy codes. Pixels are ARGB. */
+fn decode_entropy_image(
+    reader: &mut BitReader,
+    width: usize,
+    height: usize,
+    level0: bool,
+) -> Result<Vec<u32>, String> {
+    let cache_bits = match reader.read(1) {
+        1 => match reader.read(4) {
+            bits @ 1..=11 => bits,
+            bits => return Err(format!("Invalid colour cache size {}", bits)),
+        },
+        _ => 0,
+    };
+
+    /* Meta Huffman codes: an image saying which group codes each block */
+    let mut meta = None;
+    if level0 && reader.read(1) == 1 {
+        let bits = reader.read(3) + 2;
+        let (meta_width, meta_height) = (div_round_up(width, bits), div_round_up(height, bits));
+        let entropy = decode_entropy_image(reader, meta_width, meta_height, false)?;
+        let groups: Vec<usize> = entropy.iter().map(|p| (p >> 8) as usize & 0xffff).collect();
+        meta = Some((bits, meta_width, groups));
+    }
+    let num_groups = match &meta {
+        Some((_, _, groups)) => groups.iter().max().unwrap() + 1,
+        None => 1,
+    };
+    let mut groups = Vec::with_capacity(num_groups);
+    for _ in 0..num_groups {
+        groups.push(read_huffman_group(reader, cache_bits)?);
+        reader.check()?;
+    }
+
+    let mut cache = vec![0u32; 1 << cache_bits];
+    let mut cached = 0;
+    let total = width * height;
+    let mut pixels = vec![0u32; total];
+    let mut pos = 0;
+    while pos < total {
+        let (x, y) = (pos % width, pos / width);
+        if x == 0 {
+            reader.check()?;
+        }
+        let group = match &meta {
+            Some((bits, meta_width, indices)) => {
+                &groups[indices[(y >> bits) * meta_width + (x >> bits)]]
+            }
+            None => &groups[0],
+        };
+
+        let green = group[0].read(reader) as usize;
+        if green < 256 {
+            let red = group[1].read(reader) as u32;
+            let blue = group[2].read(reader) as u32;
+            let alpha = group[3].read(reader) as u32;
+            pixels[pos] = alpha << 24 | red << 16 | (green as u32) << 8 | blue;
+            pos += 1;
+        } else if green < 256 + NUM_LENGTH_CODES {
+            let length = read_prefixed(reader, (green - 256) as u16);
+            let prefix = group[4].read(reader);
+            let code = read_prefixed(reader, prefix);
+            let distance = match code {
+                1..=120 => {
+                    let (dx, dy) = DISTANCE_MAP[code - 1];
+                    (dx as isize + dy as isize * width as isize).max(1) as usize
+                }
+                _ => code - 120,
+            };
+            if distance > pos || length > total - pos {
+                return Err("Invalid VP8L back-reference".to_string());
+            }
+            for i in pos..pos + length {
+                pixels[i] = pixels[i - distance];
+            }
+            pos += length;
+        } else {
+            let index = green - 256 - NUM_LENGTH_CODES;
+            pixels[pos] = *cache.get(index).ok_or("Colour cache index out of range")?;
+            pos += 1;
+        }
+
+        if cache_bits > 0 {
+            for p in &pixels[cached..pos] {
+                cache[(0x1e35a7bd_u32.wrapping_mul(*p) >> (32 - cache_bits)) as usize] = *p;
+            }
+            cached = pos;
+        }
+    }
+    reader.check()?;
+    Ok(pixels)
+}
+
+enum Transform {
+    Predictor { bits: u32, data: Vec<u32> },
+    Colour { bits: u32, data: Vec<u32> },
+    SubtractGreen,
+    ColourIndexing { bits: u32, table: Vec<u32> },
+}
+
+/* Adds two ARGB pixels, or subtracts them, per channel */
+fn add_pixels(a: u32, b: u32) -> u32 {
+    let alpha_green = (a & 0xff00ff00).wrapping_add(b & 0xff00ff00) & 0xff00ff00;
+    let red_blue = (a & 0x00ff00ff).wrapping_add(b & 0x00ff00ff) & 0x00ff00ff;
+    alpha_green | red_blue
+}
+
+fn channels(p: u32) -> [i32; 4] {
+    [
+        (p >> 24) as i32,
+        (p >> 16 & 0xff) as i32,
+        (p >> 8 & 0xff) as i32,
+        (p & 0xff) as i32,
+    ]
+}
+
+fn from_channels(c: [i32; 4]) -> u32 {
+    c.iter().fold(0, |p, v| p << 8 | (*v).clamp(0, 255) as u32)
+}
+
+fn average2(a: u32, b: u32) -> u32 {
+    let (a, b) = (channels(a), channels(b));
+    from_channels([0, 1, 2, 3].map(|i| (a[i] + b[i]) / 2))
+}
+
+/* The prediction for a pixel from its left, top, top-left and top-right
+ * neighbours, modes 14 and 15 act like 0 */
+fn predict(mode: u32, l: u32, t: u32, tl: u32, tr: u32) -> u32 {
+    match mode {
+        1 => l,
+        2 => t,
+        3 => tr,
+        4 => tl,
+        5 => average2(average2(l, tr), t),
+        6 => average2(l, tl),
+        7 => average2(l, t),
+        8 => average2(tl, t),
+        9 => average2(t, tr),
+        10 => average2(average2(l, tl), average2(t, tr)),
+        11 => {
+            let (lc, tc, tlc) = (channels(l), channels(t), channels(tl));
+            let distance = |c: [i32; 4]| -> i32 {
+                (0..4).map(|i| (lc[i] + tc[i] - tlc[i] - c[i]).abs()).sum()
+            };
+            match distance(lc) < distance(tc) {
+                true => l,
+                false => t,
+            }
+        }
+        12 => {
+            let (lc, tc, tlc) = (channels(l), channels(t), channels(tl));
+            from_channels([0, 1, 2, 3].map(|i| lc[i] + tc[i] - tlc[i]))
+        }
+        13 => {
+            let (ac, tlc) = (channels(average2(l, t)), channels(tl));
+            from_channels([0, 1, 2, 3].map(|i| ac[i] + (ac[i] - tlc[i]) / 2))
+        }
+        _ => 0xff000000,
+    }
+}
+
+fn colour_delta(t: u32, c: u32) -> u32 {
+    ((t as u8 as i8 as i32 * c as u8 as i8 as i32) >> 5) as u32
+}
+
+impl Transform {
+    /* Undoes the transform on an image `width` pixels wide, the width after
+     * the transform is applied for colour indexing */
+    fn apply(&self, mut pixels: Vec<u32>, width: usize, out_width: usize) -> Vec<u32> {
+        match self {
+            Transform::Predictor { bits, data } => {
+                let blocks_wide = div_round_up(width, *bits);
+                for pos in 0..pixels.len() {
+                    let (x, y) = (pos % width, pos / width);
+                    let prediction = match (x, y) {
+                        (0, 0) => 0xff000000,
+                        (_, 0) => pixels[pos - 1],
+                        (0, _) => pixels[pos - width],
+                        _ => {
+                            let block = (y >> bits) * blocks_wide + (x >> bits);
+                            let mode = data[block] >> 8 & 0xf;
+                            let (l, t) = (pixels[pos - 1], pixels[pos - width]);
+                            /* The rightmost pixel takes the first one of its own row */
+                            let (tl, tr) = (pixels[pos - width - 1], pixels[pos - width + 1]);
+                            predict(mode, l, t, tl, tr)
+                        }
+                    };
+                    pixels[pos] = add_pixels(pixels[pos], prediction);
+                }
+                pixels
+            }
+            Transform::Colour { bits, data } => {
+                let blocks_wide = div_round_up(width, *bits);
+                for (pos, p) in pixels.iter_mut().enumerate() {
+                    let (x, y) = (pos % width, pos / width);
+                    let element = data[(y >> bits) * blocks_wide + (x >> bits)];
+                    let (green_to_red, green_to_blue, red_to_blue) =
+                        (element, element >> 8, element >> 16);
+                    let green = *p >> 8 & 0xff;
+                    let red = (*p >> 16).wrapping_add(colour_delta(green_to_red, green)) & 0xff;
+                    let blue = p
+                        .wrapping_add(colour_delta(green_to_blue, green))
+                        .wrapping_add(colour_delta(red_to_blue, red))
+                        & 0xff;
+                    *p = *p & 0xff00ff00 | red << 16 | blue;
+                }
+                pixels
+            }
+            Transform::SubtractGreen => {
+                for p in pixels.iter_mut() {
+                    let green = *p >> 8 & 0xff;
+                    *p = add_pixels(*p, green << 16 | green);
+                }
+                pixels
+            }
+            Transform::ColourIndexing { bits, table } => {
+                /* Several indices may be packed into the green of each pixel */
+                let per_pixel = 1 << bits;
+                let index_bits = 8 >> bits;
+                let height = pixels.len() / width;
+                let mut out = Vec::with_capacity(out_width * height);
+                for row in pixels.chunks(width) {
+                    for x in 0..out_width {
+                        let green = row[x / per_pixel] >> 8 & 0xff;
+                        let shift = index_bits * (x % per_pixel) as u32;
+                        let index = (green >> shift) & ((1 << index_bits) - 1);
+                        out.push(table.get(index as usize).copied().unwrap_or(0));
+                    }
+                }
+                out
+            }
+        }
+    }
+}
+
+/* Reads a whole image stream, transforms included, for an image of the
+ * given size */
+fn decode_image_stream(
+    reader: &mut BitReader,
+    width: usize,
+    height: usize,
+) -> Result<Vec<u32>, String> {
+    /* Each transform comes with the width it works on */
+    let mut transforms: Vec<(Transform, usize, usize)> = Vec::new();
+    let mut coded_width = width;
+    while reader.read(1) == 1 {
+        let kind = reader.read(2);
+        let seen = transforms.iter().any(|(t, _, _)| {
+            matches!(
+                (t, kind),
+                (Transform::Predictor { .. }, 0)
+                    | (Transform::Colour { .. }, 1)
+                    | (Transform::SubtractGreen, 2)
+                    | (Transform::ColourIndexing { .. }, 3)
+            )
+        });
+        if seen {
+            return Err(format!("VP8L transform {} is used twice", kind));
+        }
+        let transform = match kind {
+            0 | 1 => {
+                let bits = reader.read(3) + 2;
+                let data = decode_entropy_image(
+                    reader,
+                    div_round_up(coded_width, bits),
+                    div_round_up(height, bits),
+                    false,
+                )?;
+                match kind {
+                    0 => Transform::Predictor { bits, data },
+                    _ => Transform::Colour { bits, data },
+                }
+            }
+            2 => Transform::SubtractGreen,
+            _ => {
+                let size = reader.read(8) as usize + 1;
+                let mut table = decode_entropy_image(reader, size, 1, false)?;
+                for i in 1..size {
+                    table[i] = add_pixels(table[i], table[i - 1]);
+                }
+                let bits = match size {
+                    0..=2 => 3,
+                    3..=4 => 2,
+                    5..=16 => 1,
+                    _ => 0,
+                };
+                Transform::ColourIndexing { bits, table }
+            }
+        };
+        let out_width = coded_width;
+        if let Transform::ColourIndexing { bits, .. } = transform {
+            coded_width = div_round_up(coded_width, bits);
+        }
+        transforms.push((transform, coded_width, out_width));
+    }
+
+    let mut pixels = decode_entropy_image(reader, coded_width, height, true)?;
+    for (transform, width, out_width) in transforms.iter().rev() {
+        pixels = transform.apply(pixels, *width, *out_width);
+    }
+    Ok(pixels)
+}
+
+/* Width, height and whether alpha is used, from the 5 byte VP8L header */
+fn read_vp8l_header(data: &[u8]) -> Result<(u32, u32, bool), String> {
+    if data.len() < 5 || data[0] != VP8L_SIGNATURE {
+        return Err("Invalid VP8L signature".to_string());
+    }
+    let bits = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+    if bits >> 29 != 0 {
+        return Err(format!("Unknown VP8L version {}", bits >> 29));
+    }
+    Ok((
+        (bits & 0x3fff) + 1,
+        (bits >> 14 & 0x3fff) + 1,
+        bits >> 28 & 1 == 1,
+    ))
+}
+
+/* Width and height from the frame header of a lossy VP8 key frame */
+fn read_vp8_header(data: &[u8]) -> Result<(u32, u32), String> {
+    if data.len() < 10 || data[0] & 1 != 0 || data[3..6] != [0x9d, 0x01, 0x2a] {
+        return Err("Invalid VP8 frame header".to_string());
+    }
+    Ok((
+        (u16::from_le_bytes([data[6], data[7]]) & 0x3fff) as u32,
+        (u16::from_le_bytes([data[8], data[9]]) & 0x3fff) as u32,
+    ))
+}
+
+/* Decodes a VP8L bitstream, header included, to ARGB pixels */
+fn decode_vp8l(data: &[u8]) -> Result<(u32, u32, Vec<u32>), String> {
+    let (width, height, _) = read_vp8l_header(data)?;
+    let mut reader = BitReader::new(&data[5..]);
+    let pixels = decode_image_stream(&mut reader, width as usize, height as usize)?;
+    Ok((width, height, pixels))
+}
+
+/* Decodes the contents of an ALPH chunk to one alpha value per pixel. It
+ * holds the alpha of a lossy frame, raw or as the green of a headerless
+ * VP8L image stream, with an optional spatial filter on top. */
+pub fn decode_alpha(data: &[u8], width: u32, height: u32) -> Result<Vec<u8>, String> {
+    let header = *data.first().ok_or("Empty ALPH chunk")?;
+    let (width, height) = (width as usize, height as usize);
+    let mut alpha = match header & 3 {
+        0 => data
+            .get(1..1 + width * height)
+            .ok_or("Truncated ALPH chunk")?
+            .to_vec(),
+        1 => {
+            let mut reader = BitReader::new(&data[1..]);
+            let pixels = decode_image_stream(&mut reader, width, height)?;
+            pixels.iter().map(|p| (p >> 8) as u8).collect()
+        }
+        c => return Err(format!("Unknown alpha compression {}", c)),
+    };
+
+    /* Horizontal, vertical or gradient prediction from the decoded values */
+    let filter = header >> 2 & 3;
+    for pos in 0..alpha.len() {
+        let (x, y) = (pos % width, pos / width);
+        let prediction = match (filter, x, y) {
+            (0, _, _) | (_, 0, 0) => 0,
+            (_, _, 0) => alpha[pos - 1],
+            (_, 0, _) => alpha[pos - width],
+            (1, _, _) => alpha[pos - 1],
+            (2, _, _) => alpha[pos - width],
+            _ => {
+                let (l, t, tl) = (alpha[pos - 1], alpha[pos - width], alpha[pos - width - 1]);
+                (l as i32 + t as i32 - tl as i32).clamp(0, 255) as u8
+            }
+        };
+        alpha[pos] = alpha[pos].wrapping_add(prediction);
+    }
+    Ok(alpha)
+}
+
+/* The flags and canvas size of a VP8X chunk */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Features {
+    pub icc_profile: bool,
+    pub alpha: bool,
+    pub exif: bool,
+    pub xmp: bool,
+    pub animation: bool,
+    pub canvas_width: u32,
+    pub canvas_height: u32,
+}
+
+/* The ANIM chunk */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Animation {
+    /* RGBA, a hint that players are free to ignore */
+    pub background: (u8, u8, u8, u8),
+    /* 0 loops forever */
+    pub loop_count: u16,
+}
+
+/* A frame with its bitstream. Still images have a single frame covering
+ * the canvas. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame<'a> {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /* In milliseconds */
+    pub duration: u32,
+    /* Alpha blending onto the canvas, otherwise the frame replaces it */
+    pub blend: bool,
+    /* Clearing the frame's area to the background after it is shown */
+    pub dispose: bool,
+    pub lossless: bool,
+    /* The ALPH chunk of a lossy frame */
+    pub alpha: Option<&'a [u8]>,
+    pub bitstream: &'a [u8],
+}
+
+fn read_u24(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], 0])
+}
+
+/* A FourCC and the chunk's contents */
+type RiffChunk<'a> = ([u8; 4], &'a [u8]);
+
+/* Splits a RIFF WEBP file into its chunks, skipping the padding bytes
+ * after odd sized ones */
+fn read_chunks(data: &[u8]) -> Result<Vec<RiffChunk<'_>>, String> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err("Not a WebP file".to_string());
+    }
+    let riff_size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let end = riff_size.saturating_add(8).min(data.len());
+    read_sub_chunks(&data[12..end])
+}
+
+fn read_sub_chunks(data: &[u8]) -> Result<Vec<RiffChunk<'_>>, String> {
+    let mut chunks = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let fourcc = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+            as usize;
+        let body = data
+            .get(pos + 8..(pos + 8).saturating_add(size))
+            .ok_or(format!(
+                "Truncated {} chunk",
+                String::from_utf8_lossy(&fourcc)
+            ))?;
+        chunks.push((fourcc, body));
+        pos += 8 + size + size % 2;
+    }
+    Ok(chunks)
+}
+
+/* The image of a frame from its chunks: an optional ALPH and a VP8 or VP8L
+ * bitstream */
+fn read_frame<'a>(chunks: &[RiffChunk<'a>]) -> Result<Frame<'a>, String> {
+    let mut alpha = None;
+    for (fourcc, body) in chunks {
+        match fourcc {
+            b"ALPH" => alpha = Some(*body),
+            b"VP8L" => {
+                let (width, height, _) = read_vp8l_header(body)?;
+                return Ok(Frame {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                    duration: 0,
+                    blend: false,
+                    dispose: false,
+                    lossless: true,
+                    alpha: None,
+                    bitstream: body,
+                });
+            }
+            b"VP8 " => {
+                let (width, height) = read_vp8_header(body)?;
+                return Ok(Frame {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                    duration: 0,
+                    blend: false,
+                    dispose: false,
+                    lossless: false,
+                    alpha,
+                    bitstream: body,
+                });
+            }
+            _ => {}
+        }
+    }
+    Err("WebP frame without a bitstream".to_string())
+}
+
+pub struct Decoder<'a> {
+    features: Option<Features>,
+    animation: Option<Animation>,
+    frames: Vec<Frame<'a>>,
+    chunks: Vec<RiffChunk<'a>>,
+}
+
+impl<'a> Decoder<'a> {
+    /* Reads the chunks and the headers of every frame */
+    pub fn new(data: &'a [u8]) -> Result<Decoder<'a>, String> {
+        let chunks = read_chunks(data)?;
+        let first = chunks.first().ok_or("WebP file without chunks")?;
+        if &first.0 != b"VP8X" {
+            let frame = read_frame(&chunks)?;
+            return Ok(Decoder {
+                features: None,
+                animation: None,
+                frames: vec![frame],
+                chunks,
+            });
+        }
+
+        let body = first.1;
+        if body.len() < 10 {
+            return Err("Truncated VP8X chunk".to_string());
+        }
+        let flags = body[0];
+        let features = Features {
+            icc_profile: flags & 0x20 != 0,
+            alpha: flags & 0x10 != 0,
+            exif: flags & 0x08 != 0,
+            xmp: flags & 0x04 != 0,
+            animation: flags & 0x02 != 0,
+            canvas_width: read_u24(body, 4) + 1,
+            canvas_height: read_u24(body, 7) + 1,
+        };
+        if features.canvas_width as u64 * features.canvas_height as u64 > u32::MAX as u64 {
+            return Err("WebP canvas is too large".to_string());
+        }
+
+        let mut animation = None;
+        let mut frames = Vec::new();
+        if !features.animation {
+            let frame = read_frame(&chunks)?;
+            if frame.width > features.canvas_width || frame.height > features.canvas_height {
+                return Err("WebP frame lies outside the canvas".to_string());
+            }
+            frames.push(frame);
+        }
+        for (fourcc, body) in chunks.iter().filter(|_| features.animation) {
+            match fourcc {
+                b"ANIM" if body.len() >= 6 => {
+                    animation = Some(Animation {
+                        background: (body[2], body[1], body[0], body[3]),
+                        loop_count: u16::from_le_bytes([body[4], body[5]]),
+                    });
+                }
+                b"ANMF" if body.len() >= 16 => {
+                    let frame = read_frame(&read_sub_chunks(&body[16..])?)?;
+                    let (x, y) = (read_u24(body, 0) * 2, read_u24(body, 3) * 2);
+                    let (width, height) = (read_u24(body, 6) + 1, read_u24(body, 9) + 1);
+                    if (width, height) != (frame.width, frame.height) {
+                        return Err("WebP frame size does not match its bitstream".to_string());
+                    }
+                    if x + width > features.canvas_width || y + height > features.canvas_height {
+                        return Err("WebP frame lies outside the canvas".to_string());
+                    }
+                    frames.push(Frame {
+                        x,
+                        y,
+                        duration: read_u24(body, 12),
+                        blend: body[15] & 2 == 0,
+                        dispose: body[15] & 1 != 0,
+                        ..frame
+                    });
+                }
+                _ => {}
+            }
+        }
+        if frames.is_empty() {
+            return Err("Animated WebP without frames".to_string());
+        }
+        Ok(Decoder {
+            features: Some(features),
+            animation,
+            frames,
+            chunks,
+        })
+    }
+
+    /* None for simple files without a VP8X chunk */
+    pub fn features(&self) -> Option<Features> {
+        self.features
+    }
+
+    pub fn animation(&self) -> Option<Animation> {
+        self.animation
+    }
+
+    pub fn frames(&self) -> &[Frame<'a>] {
+        &self.frames
+    }
+
+    pub fn width(&self) -> u32 {
+        self.features
+            .map_or(self.frames[0].width, |f| f.canvas_width)
+    }
+
+    pub fn height(&self) -> u32 {
+        self.features
+            .map_or(self.frames[0].height, |f| f.canvas_height)
+    }
+
+    /* Whether the image has alpha, from VP8X or else the VP8L header */
+    pub fn has_alpha(&self) -> bool {
+        match self.features {
+            Some(features) => features.alpha,
+            None => match self.frames[0].lossless {
+                true => read_vp8l_header(self.frames[0].bitstream).is_ok_and(|(_, _, a)| a),
+                false => false,
+            },
+        }
+    }
+
+    /* The contents of a metadata chunk such as ICCP, EXIF or XMP */
+    pub fn chunk(&self, fourcc: &[u8; 4]) -> Option<&'a [u8]> {
+        self.chunks
+            .iter()
+            .find(|(f, _)| f == fourcc)
+            .map(|(_, body)| *body)
+    }
+
+    /* Decodes a frame on its own, without compositing it onto the canvas */
+    pub fn decode_frame(&self, index: usize) -> Result<Image, String> {
+        let frame = self
+            .frames
+            .get(index)
+            .ok_or(format!("WebP file has no frame {}", index))?;
+        if !frame.lossless {
+            return Err("Lossy (VP8) WebP is not supported".to_string());
+        }
+        let (width, height, pixels) = decode_vp8l(frame.bitstream)?;
+        let format = match self.has_alpha() {
+            true => PixelFormat::Rgba8,
+            false => PixelFormat::Rgb8,
+        };
+        let channels = format.channels();
+        let mut img = Image::new(width, height, format);
+        let PixelData::U8(data) = &mut img.data else {
+            unreachable!()
+        };
+        for (px, argb) in data.chunks_mut(channels).zip(pixels) {
+            let [a, r, g, b] = argb.to_be_bytes();
+            px.copy_from_slice(&[r, g, b, a][..channels]);
+        }
+        Ok(img)
+    }
+}
+
+/* Decodes a still image, or the first frame of an animation placed on a
+ * transparent canvas */
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    let decoder = Decoder::new(data)?;
+    let first = &decoder.frames()[0];
+    let frame = decoder.decode_frame(0)?;
+    if (first.x, first.y, first.width, first.height) == (0, 0, decoder.width(), decoder.height()) {
+        return Ok(frame);
+    }
+
+    let frame = frame.convert(PixelFormat::Rgba8);
+    let PixelData::U8(src) = &frame.data else {
+        unreachable!()
+    };
+    let (x, y) = (first.x as usize, first.y as usize);
+    /* The canvas size comes from VP8X alone, so it may not be allocatable */
+    let canvas_width = decoder.width() as usize;
+    let mut out = Vec::new();
+    out.try_reserve_exact(canvas_width * decoder.height() as usize * 4)
+        .map_err(|_| "WebP canvas is too large".to_string())?;
+    out.resize(canvas_width * decoder.height() as usize * 4, 0);
+    let row = frame.width as usize * 4;
+    for (i, src_row) in src.chunks(row).enumerate() {
+        out[((y + i) * canvas_width + x) * 4..][..row].copy_from_slice(src_row);
+    }
+    Ok(Image {
+        width: decoder.width(),
+        height: decoder.height(),
+        format: PixelFormat::Rgba8,
+        data: PixelData::U8(out),
+    })
+}
+
+/* Reads the RIFF header and the first chunk, 30 bytes at most */
+pub fn probe<R: Read>(reader: R) -> Result<ImageInfo, String> {
+    let mut data = Vec::new();
+    reader
+        .take(30)
+        .read_to_end(&mut data)
+        .map_err(|e| format!("Reading WebP: {}", e))?;
+    if data.len() < 20 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err("Not a WebP file".to_string());
+    }
+    let body = &data[20..];
+    let (width, height, alpha) = match &data[12..16] {
+        b"VP8L" => read_vp8l_header(body)?,
+        b"VP8 " => {
+            let (width, height) = read_vp8_header(body)?;
+            (width, height, false)
+        }
+        b"VP8X" if body.len() >= 10 => (
+            read_u24(body, 4) + 1,
+            read_u24(body, 7) + 1,
+            body[0] & 0x10 != 0,
+        ),
+        _ => return Err("Invalid WebP header".to_string()),
+    };
+    let format = match alpha {
+        true => PixelFormat::Rgba8,
+        false => PixelFormat::Rgb8,
+    };
+    Ok(ImageInfo::new(width, height, format))
+}
+
+pub struct WebpDecoder {
+    data: Vec<u8>,
+}
+
+impl WebpDecoder {
+    pub fn new(data: Vec<u8>) -> WebpDecoder {
+        WebpDecoder { data }
+    }
+}
+
+impl ImageDecoder for WebpDecoder {
+    fn info(&mut self) -> Result<ImageInfo, String> {
+        probe(self.data.as_slice())
+    }
+
+    fn decode(&mut self) -> Result<Image, String> {
+        decode(&self.data)
+    }
+
+    fn metadata(&self) -> Vec<(String, String)> {
+        let decoder = match Decoder::new(&self.data) {
+            Ok(decoder) => decoder,
+            Err(_) => return Vec::new(),
+        };
+        let kind = match decoder.frames()[0].lossless {
+            true => "lossless",
+            false => "lossy",
+        };
+        let mut entries = vec![("compression".to_string(), kind.to_string())];
+        if let Some(animation) = decoder.animation() {
+            entries.push(("frames".to_string(), decoder.frames().len().to_string()));
+            entries.push(("loop count".to_string(), animation.loop_count.to_string()));
+        }
+        if decoder.frames().iter().any(|f| f.alpha.is_some()) {
+            entries.push(("alpha chunk".to_string(), "true".to_string()));
+        }
+        for (fourcc, name) in [
+            (b"ICCP", "ICC profile"),
+            (b"EXIF", "Exif"),
+            (b"XMP ", "XMP"),
+        ] {
+            if let Some(body) = decoder.chunk(fourcc) {
+                entries.push((name.to_string(), format!("{} bytes", body.len())));
+            }
+        }
+        entries
+    }
+}
+
+#[test]
+fn test_decode_webp() {
+    /* Between them these use every transform, colour caches and meta codes */
+    for name in ["basn2c08", "basn3p01", "basn3p04", "basn3p08", "basn6a08"] {
+        let data = std::fs::read(format!("tests/webp/{}.webp", name)).unwrap();
+        let img = decode(&data).unwrap();
+        let expected = crate::open(format!("tests/png_testsuite/{}.png", name))
+            .unwrap()
+            .convert(img.format);
+        assert_eq!(img, expected, "{}", name);
+        let info = probe(data.as_slice()).unwrap();
+        assert_eq!((info.width, info.pixel_format), (32, img.format));
+    }
+
+    let data = std::fs::read("tests/webp/animated.webp").unwrap();
+    let decoder = Decoder::new(&data).unwrap();
+    assert!(decoder.features().unwrap().animation);
+    assert_eq!((decoder.width(), decoder.height()), (40, 36));
+    let animation = decoder.animation().unwrap();
+    assert_eq!(animation.background, (0x10, 0x20, 0x30, 0xff));
+    assert_eq!(animation.loop_count, 3);
+    let frames: Vec<_> = decoder
+        .frames()
+        .iter()
+        .map(|f| (f.x, f.y, f.duration, f.blend, f.dispose))
+        .collect();
+    assert_eq!(
+        frames,
+        vec![(4, 2, 100, true, false), (8, 4, 250, false, true)]
+    );
+    let expected = crate::open("tests/png_testsuite/basn6a08.png").unwrap();
+    assert_eq!(decoder.decode_frame(1).unwrap(), expected);
+
+    /* The first frame lands on a transparent canvas */
+    let canvas = decode(&data).unwrap();
+    let frame = crate::open("tests/png_testsuite/basn3p04.png")
+        .unwrap()
+        .convert(PixelFormat::Rgba8);
+    assert_eq!(canvas.get_rgba16(0), [0; 4]);
+    assert_eq!(
+        canvas.get_rgba16(33 * 40 + 35),
+        frame.get_rgba16(31 * 32 + 31)
+    );
+
+    /* A still frame larger than its VP8X canvas */
+    let vp8l = std::fs::read("tests/webp/basn6a08.webp").unwrap();
+    let mut data = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0".to_vec();
+    data.extend_from_slice(&[0; 10]);
+    data.extend_from_slice(&vp8l[12..]);
+    let size = (data.len() - 8) as u32;
+    data[4..8].copy_from_slice(&size.to_le_bytes());
+    assert!(Decoder::new(&data).is_err());
+    assert!(decode(&data).is_err());
+}
+
+#[test]
+fn test_decode_alpha() {
+    /* A lossy image with its alpha in a VP8L compressed ALPH chunk */
+    let data = std::fs::read("tests/webp/python.webp").unwrap();
+    let decoder = Decoder::new(&data).unwrap();
+    assert!(decoder.has_alpha());
+    let frame = &decoder.frames()[0];
+    assert!(!frame.lossless);
+    assert_eq!((frame.width, frame.height), (16, 16));
+    assert!(decode(&data).unwrap_err().contains("Lossy"));
+    let alpha = decode_alpha(frame.alpha.unwrap(), 16, 16).unwrap();
+    assert_eq!(alpha[..8], [0, 0, 0, 0, 0xaf, 0xed, 0xff, 0xff]);
+    assert_eq!(alpha.iter().filter(|a| **a == 255).count(), 109);
+    assert_eq!(alpha.iter().map(|a| *a as u32).sum::<u32>(), 38971);
+
+    /* Raw values with the gradient filter */
+    let alpha = decode_alpha(&[3 << 2, 10, 5, 5, 1, 2, 3], 3, 2).unwrap();
+    assert_eq!(alpha, vec![10, 15, 20, 11, 18, 26]);
+}
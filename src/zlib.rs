@@ -29,17 +29,17 @@ struct BitBuffer<'a> {
 }
 
 impl<'a> BitBuffer<'a> {
-    fn new(data: &mut VecDeque<u8>) -> BitBuffer {
+    fn new(data: &mut VecDeque<u8>) -> BitBuffer<'_> {
         BitBuffer {
             buffer: 0,
             num_bits: 0,
-            data: data,
+            data,
         }
     }
 
     fn fill(&mut self) {
         while self.num_bits < 24 {
-            if self.data.len() == 0 {
+            if self.data.is_empty() {
                 break;
             }
             self.buffer |= (self.data.pop_front().unwrap() as u32) << self.num_bits;
@@ -47,12 +47,15 @@ impl<'a> BitBuffer<'a> {
         }
     }
 
+    /* Returns the whole bytes that are still buffered to the data, the last
+     * byte read has to go back first */
     fn reset(&mut self) {
         while self.num_bits > 0 {
-            self.data.push_front((self.buffer & 0xff) as u8);
-            self.buffer >>= 8;
             self.num_bits -= 8;
+            self.data
+                .push_front(((self.buffer >> self.num_bits) & 0xff) as u8);
         }
+        self.buffer = 0;
     }
 
     fn get_n_bits(&mut self, n: u32) -> u16 {
@@ -61,9 +64,10 @@ impl<'a> BitBuffer<'a> {
         }
         let result = self.buffer & ((1 << n) - 1);
         self.buffer >>= n;
-        self.num_bits -= n;
+        /* Past the end of the data only zeros are read, and find() fails */
+        self.num_bits = self.num_bits.saturating_sub(n);
 
-        return result as u16;
+        result as u16
     }
 }
 
@@ -75,33 +79,39 @@ pub fn parse(data: &mut VecDeque<u8>) -> Result<Vec<u8>, String> {
     let cmf = data.pop_front().unwrap();
     let flg = data.pop_front().unwrap();
 
-    if (cmf as u16 * 256 + flg as u16) % 31 != 0 {
+    if !(cmf as u16 * 256 + flg as u16).is_multiple_of(31) {
         return Err("Error in bitstream header".to_string());
     }
 
     let mut buffer = BitBuffer::new(data);
 
     let c_method = cmf & 0b1111;
-    let _c_info = cmf >> 4;
+    let c_info = cmf >> 4;
 
     let _f_check = flg & 0b1111;
     let f_dict = (flg >> 5) & 0b1;
     let _f_level = flg >> 6;
 
     if f_dict > 0 {
-        if data.len() < 4 {
-            return Err("Not enough data".to_string());
-        }
-        panic!("Not implemented");
+        return Err("Preset dictionaries are not supported".to_string());
     }
 
     if c_method != 8 {
         return Err("Not a DEFLATE stream".to_string());
     }
 
+    if c_info > 7 {
+        return Err(format!("Invalid window size: {}", c_info));
+    }
+
     let mut output = Vec::with_capacity(buffer.data.len());
     let mut is_final = false;
     while !is_final {
+        /* Past the end only zeros are read, which look like empty stored blocks */
+        buffer.fill();
+        if buffer.num_bits < 3 {
+            return Err("Unexpected end of zlib stream".to_string());
+        }
         let b_final = buffer.get_n_bits(1);
         let b_type = buffer.get_n_bits(2);
         is_final = b_final != 0;
@@ -120,13 +130,7 @@ pub fn parse(data: &mut VecDeque<u8>) -> Result<Vec<u8>, String> {
                 output.push(buffer.data.pop_front().unwrap());
             }
         } else if b_type == 0b01 {
-            let mut lits = vec![8; 144];
-            lits.append(&mut vec![9; 256 - 144]);
-            lits.append(&mut vec![7; 280 - 256]);
-            lits.append(&mut vec![8; 288 - 280]);
-
-            let dists = vec![5; 32];
-
+            let (lits, dists) = fixed_lengths();
             let hf_lit = build_huffman_codes(&lits, true);
             let hf_dist = build_huffman_codes(&dists, true);
             parse_block(&hf_lit, &hf_dist, &mut buffer, &mut output)?;
@@ -154,9 +158,184 @@ pub fn parse(data: &mut VecDeque<u8>) -> Result<Vec<u8>, String> {
         }
     }
 
+    /* The Adler-32 checksum follows the final block, starting on a byte boundary */
+    let partial = buffer.num_bits % 8;
+    buffer.get_n_bits(partial);
+    buffer.reset();
+    if buffer.data.len() < 4 {
+        return Err("Missing Adler-32 checksum".to_string());
+    }
+    let mut expected: u32 = 0;
+    for _ in 0..4 {
+        expected = expected << 8 | buffer.data.pop_front().unwrap() as u32;
+    }
+    let actual = adler32(&output);
+    if actual != expected {
+        return Err(format!(
+            "Adler-32 mismatch: expected {:08x}, computed {:08x}",
+            expected, actual
+        ));
+    }
+
     Ok(output)
 }
 
+pub fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let (mut a, mut b) = (1, 0);
+
+    /* 5552 is the largest n for which the sums cannot overflow before the modulo */
+    for block in data.chunks(5552) {
+        for byte in block {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+
+    b << 16 | a
+}
+
+/* Code lengths of the fixed Huffman codes for literals and lengths, and
+ * for distances */
+fn fixed_lengths() -> (Vec<u32>, Vec<u32>) {
+    let mut lits = vec![8; 144];
+    lits.append(&mut vec![9; 256 - 144]);
+    lits.append(&mut vec![7; 280 - 256]);
+    lits.append(&mut vec![8; 288 - 280]);
+    (lits, vec![5; 32])
+}
+
+/* Writes bits starting at the least significant bit of every byte */
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u64,
+    num_bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, n: u32) {
+        self.buffer |= (value as u64) << self.num_bits;
+        self.num_bits += n;
+        while self.num_bits >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.num_bits -= 8;
+        }
+    }
+
+    /* Huffman codes are packed starting with their most significant bit */
+    fn write_code(&mut self, code: u16, n: u32) {
+        let reversed = code.reverse_bits() >> (16 - n);
+        self.write(reversed as u32, n);
+    }
+}
+
+/* Longest match and how many earlier positions to try for it */
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const WINDOW_SIZE: usize = 32768;
+const HASH_BITS: u32 = 15;
+
+fn hash3(data: &[u8]) -> usize {
+    ((data[0] as usize) << 10 ^ (data[1] as usize) << 5 ^ data[2] as usize) & ((1 << HASH_BITS) - 1)
+}
+
+/* Compresses to a zlib stream of a single block with the fixed Huffman
+ * codes, finding matches through hash chains of three byte prefixes */
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let (lit_lengths, dist_lengths) = fixed_lengths();
+    let (lit_codes, dist_codes) = (
+        canonical_codes(&lit_lengths),
+        canonical_codes(&dist_lengths),
+    );
+    let mut writer = BitWriter {
+        out: vec![0x78, 0x01],
+        buffer: 0,
+        num_bits: 0,
+    };
+    let literal = |writer: &mut BitWriter, symbol: usize| {
+        writer.write_code(lit_codes[symbol], lit_lengths[symbol]);
+    };
+
+    /* Final block with fixed codes */
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let insert = |pos: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>| {
+        if pos + 3 <= data.len() {
+            let h = hash3(&data[pos..]);
+            prev[pos] = head[h];
+            head[h] = pos;
+        }
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if pos + 3 <= data.len() {
+            let max_len = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash3(&data[pos..])];
+            for _ in 0..MAX_CHAIN {
+                if candidate == usize::MAX || pos - candidate > WINDOW_SIZE {
+                    break;
+                }
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    (best_len, best_dist) = (len, pos - candidate);
+                    if len == max_len {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+            }
+        }
+
+        if best_len < 3 {
+            literal(&mut writer, data[pos] as usize);
+            insert(pos, &mut head, &mut prev);
+            pos += 1;
+            continue;
+        }
+
+        let i = LENGTHS_BASE
+            .iter()
+            .rposition(|b| *b as usize <= best_len)
+            .unwrap();
+        literal(&mut writer, 257 + i);
+        writer.write(
+            (best_len - LENGTHS_BASE[i] as usize) as u32,
+            LENGTH_EXTRA_BITS[i],
+        );
+        let d = DIST_BASE
+            .iter()
+            .rposition(|b| *b as usize <= best_dist)
+            .unwrap();
+        writer.write_code(dist_codes[d], dist_lengths[d]);
+        writer.write(
+            (best_dist - DIST_BASE[d] as usize) as u32,
+            DIST_EXTRA_BITS[d],
+        );
+        for p in pos..pos + best_len {
+            insert(p, &mut head, &mut prev);
+        }
+        pos += best_len;
+    }
+
+    literal(&mut writer, 256);
+    writer.write(0, 7);
+    let mut out = writer.out;
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
 fn parse_block(
     hf_lit: &HuffmanTree,
     hf_dist: &HuffmanTree,
@@ -191,9 +370,12 @@ fn parse_block(
                 let idx = dist_val as usize;
                 let extra = DIST_EXTRA_BITS[idx];
                 let dist = DIST_BASE[idx] + buffer.get_n_bits(extra);
+                if dist as usize > output.len() {
+                    return Err("Distance is too far back".to_string());
+                }
 
                 for _ in 0..len {
-                    let v = output[(output.len() - dist as usize)];
+                    let v = output[output.len() - dist as usize];
                     output.push(v);
                 }
             }
@@ -229,15 +411,11 @@ fn fill_with_huffman(
             }
             17 => {
                 let c = buffer.get_n_bits(3);
-                for _ in 0..(c + 3) {
-                    lengths.push(0);
-                }
+                lengths.resize(lengths.len() + c as usize + 3, 0);
             }
             18 => {
                 let c = buffer.get_n_bits(7);
-                for _ in 0..(c + 11) {
-                    lengths.push(0);
-                }
+                lengths.resize(lengths.len() + c as usize + 11, 0);
             }
             _ => {
                 return Err("Corrupted bitstream".to_string());
@@ -248,17 +426,15 @@ fn fill_with_huffman(
     Ok(lengths)
 }
 
-fn build_huffman_codes(bit_lengths: &Vec<u32>, reverse_bits: bool) -> HuffmanTree {
-    let mut counts = vec![0; bit_lengths.len()];
-    let mut next_code = vec![0; bit_lengths.len()];
+/* Assigns the canonical Huffman code to every symbol, as described in the DEFLATE spec */
+pub(crate) fn canonical_codes(bit_lengths: &[u32]) -> Vec<u16> {
+    let max = bit_lengths.iter().cloned().max().unwrap_or(0);
+    let mut counts = vec![0; max as usize + 1];
+    let mut next_code = vec![0; max as usize + 1];
     let mut codes = vec![0; bit_lengths.len()];
 
-    let mut max = 0;
     for i in bit_lengths.iter() {
         counts[*i as usize] += 1;
-        if *i as u32 > max {
-            max = *i as u32;
-        }
     }
     counts[0] = 0;
 
@@ -270,20 +446,24 @@ fn build_huffman_codes(bit_lengths: &Vec<u32>, reverse_bits: bool) -> HuffmanTre
 
     for i in 0..bit_lengths.len() {
         if bit_lengths[i] != 0 {
-            codes[i] = next_code[bit_lengths[i] as usize];
+            codes[i] = next_code[bit_lengths[i] as usize] as u16;
             next_code[bit_lengths[i] as usize] += 1;
         }
     }
 
+    codes
+}
+
+fn build_huffman_codes(bit_lengths: &[u32], reverse_bits: bool) -> HuffmanTree {
+    let codes = canonical_codes(bit_lengths);
+
     let mut result = HuffmanTree::new();
     for i in 0..codes.len() {
         if bit_lengths[i] > 0 {
-            let mut code;
-            if reverse_bits {
-                code = codes[i] as u16;
-                code = code.reverse_bits() >> (16 - bit_lengths[i]);
+            let code = if reverse_bits {
+                codes[i].reverse_bits() >> (16 - bit_lengths[i])
             } else {
-                code = codes[i] as u16;
+                codes[i]
             };
             if !result.insert(code, bit_lengths[i] as u16, i as u32) {
                 panic!();
@@ -318,9 +498,9 @@ impl HuffmanNode {
 
 impl HuffmanTree {
     fn new() -> HuffmanTree {
-        let mut nodes = Vec::new();
-        nodes.push(HuffmanNode::new());
-        HuffmanTree { nodes: nodes }
+        HuffmanTree {
+            nodes: vec![HuffmanNode::new()],
+        }
     }
 
     fn new_node(&mut self) -> usize {
@@ -345,6 +525,9 @@ impl HuffmanTree {
                 if self.nodes[current_node].right.is_some() {
                     let idx = self.nodes[current_node].right.unwrap();
                     if self.nodes[idx].val.is_some() {
+                        if i > buffer.num_bits {
+                            return None;
+                        }
                         buffer.buffer >>= i;
                         buffer.num_bits -= i;
                         return self.nodes[idx].val;
@@ -357,6 +540,9 @@ impl HuffmanTree {
                 if self.nodes[current_node].left.is_some() {
                     let idx = self.nodes[current_node].left.unwrap();
                     if self.nodes[idx].val.is_some() {
+                        if i > buffer.num_bits {
+                            return None;
+                        }
                         buffer.buffer >>= i;
                         buffer.num_bits -= i;
                         return self.nodes[idx].val;
@@ -403,7 +589,6 @@ fn test_huffman_tree() {
     tree.insert(6, 8, 100);
     tree.insert(5, 8, 3);
     tree.insert(16, 8, 5);
-    println!("{:?}", tree.nodes);
     let mut data = vec![6, 5, 16].into_iter().collect();
     let mut buffer = BitBuffer::new(&mut data);
 
@@ -416,13 +601,71 @@ fn test_huffman_tree() {
 fn test_huffman() {
     /* Note, values defined by the DEFLATE spec */
     let bit_lengths = vec![3, 3, 3, 3, 3, 2, 4, 4];
-    let mut hf_codes = build_huffman_codes(&bit_lengths, false);
+    let codes = canonical_codes(&bit_lengths);
     let target = vec![2, 3, 4, 5, 6, 0, 14, 15];
 
-    hf_codes.sort_unstable_by(|a, b| a.2.cmp(&b.2));
-    for i in 0..hf_codes.len() {
-        assert_eq!(hf_codes[i].1, target[i]);
+    assert_eq!(codes, target);
+}
+
+#[test]
+fn test_adler32() {
+    assert_eq!(adler32(b""), 1);
+    assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+}
+
+#[test]
+fn test_compress() {
+    let text = b"Compressed with fixed codes, compressed with fixed codes again".repeat(100);
+    let mut noise = vec![0u8; 70000];
+    let mut x: u32 = 1;
+    for v in noise.iter_mut() {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        *v = (x >> 24) as u8;
+    }
+    for data in [&text[..], &noise[..], b"", b"ab"].iter() {
+        let compressed = compress(data);
+        assert_eq!(
+            parse(&mut compressed.iter().copied().collect()).unwrap(),
+            *data
+        );
+    }
+    assert!(compress(&text).len() < text.len() / 20);
+}
+
+#[test]
+fn test_corrupt() {
+    /* Running out of data is an error, never zeros filled in */
+    let text = b"Compressed with fixed codes, compressed with fixed codes again".repeat(10);
+    let compressed = compress(&text);
+    for len in 0..compressed.len() {
+        let mut data = compressed[..len].iter().copied().collect();
+        assert!(parse(&mut data).is_err(), "{} bytes", len);
+    }
+
+    /* A literal followed by a match of distance 2 */
+    let (lit_lengths, dist_lengths) = fixed_lengths();
+    let (lit_codes, dist_codes) = (
+        canonical_codes(&lit_lengths),
+        canonical_codes(&dist_lengths),
+    );
+    let mut writer = BitWriter {
+        out: vec![0x78, 0x01],
+        buffer: 0,
+        num_bits: 0,
+    };
+    writer.write(1, 1);
+    writer.write(1, 2);
+    for symbol in [b'a' as usize, 257] {
+        writer.write_code(lit_codes[symbol], lit_lengths[symbol]);
     }
+    writer.write_code(dist_codes[1], dist_lengths[1]);
+    writer.write_code(lit_codes[256], lit_lengths[256]);
+    writer.write(0, 7);
+    writer
+        .out
+        .extend_from_slice(&adler32(b"aaaa").to_be_bytes());
+    let error = parse(&mut writer.out.into_iter().collect()).unwrap_err();
+    assert_eq!(error, "Distance is too far back");
 }
 
 #[test]
@@ -29,7 +29,7 @@ def test_load_image(name):
     """
         Compare output from Sparrow to Pillow
     """
-    subprocess.run(["rm", "img.ppm"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+    subprocess.run(["rm", "img.pam"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
     should_fail = False
     try:
         img = Image.open(name)
@@ -40,19 +40,24 @@ def test_load_image(name):
 
     should_fail = should_fail or name[len(TEST_IMGS_DIR_NAME + "/")] == 'x'
 
-    result = subprocess.run([EXECUTABLE_PATH, name], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+    result = subprocess.run([EXECUTABLE_PATH, "convert", "-f", "rgba8", name, "-o", "img.pam"], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
     assert (result.returncode != 0) == should_fail, "Image loading failed: {}".format(result.stderr)
     if should_fail:
         return
 
     pixels = []
-    with open("img.ppm") as f:
-        assert f.readline().strip() == "P3"
-        w, h = f.readline().strip().split(" ")
-        max_val = f.readline().strip()
-        for i in range(int(w) * int(h)):
-            data = f.readline().strip().split(" ")
-            pixels.append(( int(data[0]), int(data[1]), int(data[2]), int(data[3]) ))
+    with open("img.pam", "rb") as f:
+        header = {}
+        assert f.readline().strip() == b"P7"
+        while True:
+            line = f.readline().strip().split(b" ")
+            if line[0] == b"ENDHDR":
+                break
+            header[line[0]] = line[1]
+        assert header[b"TUPLTYPE"] == b"RGB_ALPHA" and header[b"MAXVAL"] == b"255"
+        data = f.read()
+        for i in range(int(header[b"WIDTH"]) * int(header[b"HEIGHT"])):
+            pixels.append(tuple(data[i * 4:i * 4 + 4]))
 
     for x, y in zip(pixels, original):
         assert x == y